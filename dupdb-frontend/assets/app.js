@@ -0,0 +1,247 @@
+const main = document.getElementsByTagName("main")[0];
+const template = document.getElementById("duplicate-record");
+function newDup(file, parent) {
+	const div = template.content.cloneNode(true);
+	div.querySelector("p").textContent = file.path;
+	div.querySelector("input[name=path]").value = file.path;
+	for (const input of div.querySelectorAll("input[name=id]")) {
+		input.value = file.id;
+	}
+	const img = div.querySelector("img");
+	img.src = `${window.location.origin}/thumb/${file.id}?size=400`;
+	div.querySelector("figure a").href = `/file/${file.id}`;
+	div.querySelector("a.compare").href = `/compare/${file.id}`;
+	parent.appendChild(div);
+}
+
+function skipGroup(group) {
+	return group.files.some((file) => file.path.includes("Captivating"));
+}
+
+function groupSection(group) {
+	const section = document.createElement("section");
+	section.className = "group";
+	section.dataset.hash = group.hash;
+	for (const file of group.files) {
+		newDup(file, section);
+	}
+	return section;
+}
+
+function removeimage(img) {
+	img.closest("figure").remove();
+}
+
+function onloadimage(img) {
+	if (img.complete) {
+		img.closest("figure").previousElementSibling.remove();
+	}
+}
+
+const filters = document.getElementById("filters");
+const loadMore = document.getElementById("load-more");
+const PAGE_SIZE = 50;
+let nextCursor = "";
+
+function loadPage() {
+	const params = new URLSearchParams(new FormData(filters));
+	params.set("limit", PAGE_SIZE);
+	if (nextCursor) {
+		params.set("cursor", nextCursor);
+	}
+	return fetch(`/api/v1/groups?${params}`)
+		.then((response) => response.json())
+		.then((page) => {
+			if (page.error) {
+				throw new Error(page.error);
+			}
+			nextCursor = page.next_cursor || "";
+			loadMore.hidden = !nextCursor;
+			renderGroups(page.groups);
+		});
+}
+
+function renderGroups(groups) {
+	for (const group of groups) {
+		if (!skipGroup(group)) {
+			main.appendChild(groupSection(group));
+		}
+	}
+}
+
+filters.addEventListener("submit", (event) => {
+	event.preventDefault();
+	nextCursor = "";
+	main.replaceChildren();
+	loadPage();
+});
+
+loadMore.addEventListener("click", () => loadPage());
+
+const bulk = document.getElementById("bulk");
+const bulkPlan = document.getElementById("bulk-plan");
+const bulkSummary = document.getElementById("bulk-summary");
+const bulkApply = document.getElementById("bulk-apply");
+const bulkResults = document.getElementById("bulk-results");
+const csrfToken = document.querySelector("input[name=csrf]").value;
+let plan = null;
+
+function formatBytes(bytes) {
+	const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let unit = 0;
+	while (bytes >= 1024 && unit < units.length - 1) {
+		bytes /= 1024;
+		unit++;
+	}
+	return `${bytes.toFixed(unit ? 1 : 0)} ${units[unit]}`;
+}
+
+function bulkResult(text, failed) {
+	const li = document.createElement("li");
+	li.textContent = text;
+	if (failed) {
+		li.className = "failed";
+	}
+	bulkResults.appendChild(li);
+}
+
+bulk.addEventListener("submit", (event) => {
+	event.preventDefault();
+	const params = new URLSearchParams(new FormData(filters));
+	params.delete("sort");
+	for (const [name, value] of new FormData(bulk)) {
+		params.set(name, value);
+	}
+	fetch(`/api/v1/bulk/plan?${params}`)
+		.then((response) => response.json())
+		.then((body) => {
+			bulkPlan.hidden = false;
+			bulkResults.replaceChildren();
+			if (body.error) {
+				plan = null;
+				bulkSummary.textContent = body.error;
+				bulkApply.hidden = true;
+				return;
+			}
+			plan = body;
+			bulkSummary.textContent = `Remove ${body.files_to_remove} files from ${body.groups.length} groups`
+				+ ` to reclaim ${formatBytes(body.reclaimed_bytes)}.`
+				+ (body.skipped_groups ? ` ${body.skipped_groups} groups are left alone.` : "")
+				+ (body.truncated ? " There are more groups, preview again afterwards." : "");
+			bulkApply.hidden = body.groups.length === 0;
+			for (const group of body.groups) {
+				bulkResult(`keep ${group.keep.path}, remove ${group.remove.map((file) => file.path).join(", ")}`, false);
+			}
+		});
+});
+
+document.getElementById("bulk-cancel").addEventListener("click", () => {
+	plan = null;
+	bulkPlan.hidden = true;
+});
+
+// One group per request, so a failure part way only costs that group and
+// the list below shows exactly how far we got.
+bulkApply.addEventListener("click", async () => {
+	if (!plan) {
+		return;
+	}
+	const groups = plan.groups;
+	plan = null;
+	bulkApply.hidden = true;
+	bulkResults.replaceChildren();
+	let reclaimed = 0;
+	for (const group of groups) {
+		const response = await fetch("/api/v1/bulk/apply", {
+			method: "POST",
+			headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken },
+			body: JSON.stringify({ hash: group.hash, keep: group.keep.id, remove: group.remove.map((file) => file.id) }),
+		});
+		const body = await response.json();
+		if (body.error) {
+			bulkResult(`${group.keep.path}: ${body.error}`, true);
+			continue;
+		}
+		for (const result of body.results) {
+			if (result.removed) {
+				reclaimed += group.remove.find((file) => file.id === result.id)?.size || 0;
+				bulkResult(`removed ${result.path}`, false);
+			} else {
+				bulkResult(`could not remove ${result.path || result.id}: ${result.error}`, true);
+			}
+		}
+	}
+	bulkSummary.textContent = `Done, reclaimed ${formatBytes(reclaimed)}.`;
+});
+
+const search = document.getElementById("search");
+const searchResults = document.getElementById("search-results");
+// Same as the server's limit, bigger files are better checked with
+// `duplicate-file-monitor search` which only sends the hash.
+const MAX_UPLOAD_BYTES = 64 * 1024 * 1024;
+
+function searchResult(text) {
+	const li = document.createElement("li");
+	li.textContent = text;
+	searchResults.appendChild(li);
+	return li;
+}
+
+search.addEventListener("submit", async (event) => {
+	event.preventDefault();
+	const file = search.elements.file.files[0];
+	searchResults.replaceChildren();
+	if (file.size > MAX_UPLOAD_BYTES) {
+		searchResult(`${file.name} is too big to check from here, use duplicate-file-monitor search instead.`);
+		return;
+	}
+	const response = await fetch("/api/v1/search", { method: "POST", body: file });
+	const body = await response.json();
+	if (body.error) {
+		searchResult(body.error);
+	} else if (body.files.length === 0) {
+		searchResult(`${file.name} is not stored anywhere yet.`);
+	} else {
+		for (const stored of body.files) {
+			const link = document.createElement("a");
+			link.href = `/file/${stored.id}`;
+			link.target = "_blank";
+			link.textContent = stored.path;
+			searchResult(`${file.name} is already stored as `).appendChild(link);
+		}
+	}
+});
+
+loadPage().then(() => {
+	main.firstChild.remove();
+})
+
+// The server tells us whenever the monitor adds or removes a copy, so
+// tiles change in place instead of the page going stale.
+function shownGroup(hash) {
+	return [...main.querySelectorAll("section.group")].find((section) => section.dataset.hash === hash);
+}
+
+function matchesFilters(group) {
+	// Whole folder names only, like the server, so /photos leaves out /photos-old.
+	const folder = filters.elements.prefix.value.replace(/\/+$/, "");
+	const ext = filters.elements.ext.value.replace(/^\./, "").toLowerCase();
+	return group.files.some((file) => (!filters.elements.prefix.value || file.path === folder || file.path.startsWith(`${folder}/`))
+		&& (!ext || file.path.toLowerCase().endsWith(`.${ext}`)));
+}
+
+function showGroup(group, isNew) {
+	const shown = shownGroup(group.hash);
+	if (skipGroup(group)) {
+		shown?.remove();
+	} else if (shown) {
+		shown.replaceWith(groupSection(group));
+	} else if (isNew && matchesFilters(group)) {
+		main.prepend(groupSection(group));
+	}
+}
+
+const live = new EventSource("/events");
+live.addEventListener("group-created", (event) => showGroup(JSON.parse(event.data), true));
+live.addEventListener("group-changed", (event) => showGroup(JSON.parse(event.data), false));
+live.addEventListener("group-resolved", (event) => shownGroup(JSON.parse(event.data).hash)?.remove());
@@ -0,0 +1,174 @@
+const fileId = window.location.pathname.split("/").pop();
+const summary = document.getElementById("compare-summary");
+const previews = document.getElementById("compare-previews");
+const actions = document.getElementById("compare-actions");
+const diffs = document.getElementById("compare-diffs");
+const csrfToken = document.querySelector("input[name=csrf]").value;
+// Enough to tell two text files apart at a glance, the diff covers the rest.
+const TEXT_PREVIEW_CHARS = 4096;
+
+function formatBytes(bytes) {
+	const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let unit = 0;
+	while (bytes >= 1024 && unit < units.length - 1) {
+		bytes /= 1024;
+		unit++;
+	}
+	return `${bytes.toFixed(unit ? 1 : 0)} ${units[unit]}`;
+}
+
+function formatTime(seconds) {
+	return seconds == null ? "unknown" : new Date(seconds * 1000).toLocaleString();
+}
+
+function row(field) {
+	return document.querySelector(`tr[data-field=${field}]`);
+}
+
+function cell(tr, text) {
+	const td = document.createElement("td");
+	td.textContent = text;
+	tr.appendChild(td);
+	return td;
+}
+
+function preview(copy) {
+	const th = document.createElement("th");
+	if (!copy.available) {
+		th.textContent = "Missing from disk";
+		return th;
+	}
+	const src = `/file/${copy.id}`;
+	let media = null;
+	if (copy.preview === "image") {
+		media = document.createElement("img");
+		media.src = `/thumb/${copy.id}?size=400`;
+	} else if (copy.preview === "video" || copy.preview === "audio") {
+		media = document.createElement(copy.preview);
+		media.controls = true;
+		media.preload = "metadata";
+		media.src = src;
+	}
+	const link = document.createElement("a");
+	link.href = src;
+	link.target = "_blank";
+	if (media) {
+		link.appendChild(media);
+	} else {
+		link.textContent = "Open";
+	}
+	th.appendChild(link);
+	return th;
+}
+
+function textPreview(copy, td) {
+	if (!copy.available || copy.preview !== "text") {
+		td.textContent = "-";
+		return;
+	}
+	const pre = document.createElement("pre");
+	td.appendChild(pre);
+	fetch(`/file/${copy.id}`)
+		.then((response) => response.text())
+		.then((text) => {
+			pre.textContent = text.length > TEXT_PREVIEW_CHARS ? `${text.slice(0, TEXT_PREVIEW_CHARS)}\n...` : text;
+		});
+}
+
+function removeButton(copy, copies) {
+	const td = document.createElement("td");
+	const button = document.createElement("button");
+	button.textContent = "Remove this copy";
+	button.disabled = !copy.available;
+	button.addEventListener("click", async () => {
+		if (!window.confirm(`Remove ${copy.path}?`)) {
+			return;
+		}
+		const response = await fetch(`/api/v1/files/${copy.id}?${new URLSearchParams({ path: copy.path })}`, {
+			method: "DELETE",
+			headers: { "X-CSRF-Token": csrfToken },
+		});
+		const body = await response.json();
+		if (body.error) {
+			window.alert(body.error);
+			return;
+		}
+		// Removing the copy this page is named after leaves it pointing at
+		// nothing, so carry on from one that's still there.
+		const next = copies.find((other) => other.id !== copy.id && other.available);
+		if (copy.id === Number(fileId) && next) {
+			window.location.replace(`/compare/${next.id}`);
+		} else {
+			load();
+		}
+	});
+	td.appendChild(button);
+	return td;
+}
+
+function showDiffs(comparison) {
+	diffs.replaceChildren();
+	const first = comparison.copies[0];
+	for (const text of comparison.text) {
+		const other = comparison.copies.find((copy) => copy.id === text.id);
+		const heading = document.createElement("h2");
+		heading.textContent = `${first.path} against ${other.path}`;
+		diffs.appendChild(heading);
+		if (text.identical) {
+			const same = document.createElement("p");
+			same.textContent = "Byte for byte identical.";
+			diffs.appendChild(same);
+			continue;
+		}
+		const pre = document.createElement("pre");
+		pre.className = "diff";
+		for (const line of text.diff) {
+			const span = document.createElement("span");
+			span.className = line.startsWith("-") ? "removed" : "added";
+			span.textContent = `${line}\n`;
+			pre.appendChild(span);
+		}
+		if (text.truncated) {
+			pre.appendChild(document.createTextNode("... more differences not shown\n"));
+		}
+		diffs.appendChild(pre);
+	}
+}
+
+function show(comparison) {
+	for (const tr of [previews, actions, ...document.querySelectorAll("tr[data-field]")]) {
+		tr.replaceChildren(tr.firstElementChild);
+	}
+	const copies = comparison.copies;
+	summary.textContent = `${copies.length} copies with hash ${comparison.hash}`;
+	for (const copy of copies) {
+		previews.appendChild(preview(copy));
+		cell(row("name"), copy.name).title = copy.path;
+		cell(row("folder"), copy.folder);
+		const size = copy.size ?? copy.indexed_size;
+		cell(row("size"), size == null ? "unknown" : formatBytes(size));
+		cell(row("modified"), formatTime(copy.modified ?? copy.indexed_modified));
+		cell(row("owner"), copy.owner || "-");
+		cell(row("permissions"), copy.permissions || "-");
+		cell(row("content_type"), copy.content_type || "-");
+		textPreview(copy, cell(row("text"), ""));
+		actions.appendChild(removeButton(copy, copies));
+	}
+	showDiffs(comparison);
+}
+
+function load() {
+	fetch(`/api/v1/files/${fileId}/compare`)
+		.then((response) => response.json())
+		.then((body) => {
+			if (body.error) {
+				throw new Error(body.error);
+			}
+			show(body);
+		})
+		.catch((error) => {
+			summary.textContent = error.message;
+		});
+}
+
+load();
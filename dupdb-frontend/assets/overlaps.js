@@ -0,0 +1,79 @@
+const filters = document.getElementById("overlap-filters");
+const summary = document.getElementById("overlap-summary");
+const rows = document.querySelector("#overlaps tbody");
+
+function formatBytes(bytes) {
+	const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let unit = 0;
+	while (bytes >= 1024 && unit < units.length - 1) {
+		bytes /= 1024;
+		unit++;
+	}
+	return `${bytes.toFixed(unit ? 1 : 0)} ${units[unit]}`;
+}
+
+function cell(tr, text) {
+	const td = document.createElement("td");
+	td.textContent = text;
+	tr.appendChild(td);
+	return td;
+}
+
+function folderCell(tr, directory) {
+	const td = cell(tr, directory.path);
+	const detail = document.createElement("div");
+	detail.textContent = `${directory.overlap_percent}% of ${directory.files} files, ${formatBytes(directory.bytes)}`;
+	td.appendChild(detail);
+	td.classList.toggle("contained", directory.fully_contained);
+}
+
+function verdict(overlap) {
+	const { first, second } = overlap;
+	if (first.fully_contained && second.fully_contained) {
+		return "Same contents";
+	}
+	if (first.fully_contained) {
+		return `${first.path} is fully contained in ${second.path}`;
+	}
+	if (second.fully_contained) {
+		return `${second.path} is fully contained in ${first.path}`;
+	}
+	return "";
+}
+
+function render(overlaps) {
+	rows.replaceChildren();
+	for (const overlap of overlaps) {
+		const tr = document.createElement("tr");
+		folderCell(tr, overlap.first);
+		folderCell(tr, overlap.second);
+		cell(tr, overlap.shared_files);
+		cell(tr, formatBytes(overlap.shared_bytes));
+		cell(tr, verdict(overlap));
+		rows.appendChild(tr);
+	}
+	summary.textContent = overlaps.length ? `${overlaps.length} folder pairs, most shared first` : "No folders share any files";
+}
+
+function load() {
+	const params = new URLSearchParams(new FormData(filters));
+	summary.textContent = "loading...";
+	fetch(`/api/v1/overlaps?${params}`)
+		.then((response) => response.json())
+		.then((body) => {
+			if (body.error) {
+				throw new Error(body.error);
+			}
+			render(body.overlaps);
+		})
+		.catch((error) => {
+			summary.textContent = error.message;
+		});
+}
+
+filters.addEventListener("submit", (event) => {
+	event.preventDefault();
+	load();
+});
+
+load();
@@ -0,0 +1,156 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::http::Request;
+
+/// How each request gets written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// The Common Log Format, with the time taken on the end.
+    Common,
+    /// One JSON object per line.
+    Json,
+}
+
+/// One answered request. `request` is None when it couldn't be parsed.
+pub struct AccessLogEntry<'a> {
+    pub client: Option<SocketAddr>,
+    pub request: Option<&'a Request>,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    timestamp: f64,
+    client: Option<String>,
+    method: Option<&'a str>,
+    target: Option<&'a str>,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "common" => Ok(LogFormat::Common),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("log format must be common or json, not {format}")),
+        }
+    }
+
+    pub fn line(&self, entry: &AccessLogEntry, now: SystemTime) -> String {
+        let duration_ms = entry.latency.as_secs_f64() * 1000.0;
+        match self {
+            LogFormat::Common => {
+                let client = entry.client.map(|client| client.ip().to_string()).unwrap_or_else(|| "-".to_string());
+                let request_line = match entry.request {
+                    Some(request) => escape(&format!("{} {} {}", request.method, request.target, request.version)),
+                    None => "-".to_string(),
+                };
+                format!(
+                    "{client} - - [{}] \"{request_line}\" {} {} {duration_ms:.3}ms",
+                    common_log_time(now), entry.status, entry.bytes
+                )
+            },
+            LogFormat::Json => {
+                let json = JsonEntry {
+                    timestamp: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+                    client: entry.client.map(|client| client.ip().to_string()),
+                    method: entry.request.map(|request| request.method.as_str()),
+                    target: entry.request.map(|request| request.target.as_str()),
+                    status: entry.status,
+                    bytes: entry.bytes,
+                    duration_ms: (duration_ms * 1000.0).round() / 1000.0,
+                };
+                serde_json::to_string(&json).unwrap_or_else(|error| format!("{{\"error\":\"{error}\"}}"))
+            },
+        }
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn common_log_time(now: SystemTime) -> String {
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    let http_date = httpdate::fmt_http_date(now);
+    let parts: Vec<&str> = http_date.split(' ').collect();
+    match parts[..] {
+        [_, day, month, year, time, _] => format!("{day}/{month}/{year}:{time} +0000"),
+        _ => http_date,
+    }
+}
+
+/// Request lines are whatever the client sent, so nothing in them gets to
+/// end the quoted field or start a new log line.
+fn escape(request_line: &str) -> String {
+    request_line.chars().map(|c| match c {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if c.is_control() => format!("\\x{:02x}", c as u32),
+        c => c.to_string(),
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{self, Limits};
+    use std::io::Cursor;
+
+    fn request(raw: &str) -> Request {
+        http::read_request(&mut Cursor::new(raw.as_bytes().to_vec()), &Limits::default()).expect("Could not parse test request")
+    }
+
+    fn entry(request: Option<&Request>) -> AccessLogEntry<'_> {
+        AccessLogEntry {
+            client: Some("127.0.0.1:5000".parse().expect("valid address")),
+            request,
+            status: 303,
+            bytes: 12,
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn formats_parse() {
+        assert_eq!(LogFormat::parse("json"), Ok(LogFormat::Json));
+        assert!(LogFormat::parse("xml").is_err());
+    }
+
+    #[test]
+    fn common_log_lines() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        let remove = request("POST /remove HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(
+            LogFormat::Common.line(&entry(Some(&remove)), now),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"POST /remove HTTP/1.1\" 303 12 1.500ms"
+        );
+        assert_eq!(
+            LogFormat::Common.line(&entry(None), now),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"-\" 303 12 1.500ms"
+        );
+    }
+
+    #[test]
+    fn json_log_lines() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        let get = request("GET /api/v1/groups?limit=5 HTTP/1.1\r\n\r\n");
+        let line: serde_json::Value = serde_json::from_str(&LogFormat::Json.line(&entry(Some(&get)), now)).expect("should be json");
+        assert_eq!(line["client"], "127.0.0.1");
+        assert_eq!(line["method"], "GET");
+        assert_eq!(line["target"], "/api/v1/groups?limit=5");
+        assert_eq!(line["status"], 303);
+        assert_eq!(line["bytes"], 12);
+        assert_eq!(line["duration_ms"], 1.5);
+        assert_eq!(line["timestamp"], 784111777.0);
+    }
+
+    #[test]
+    fn request_lines_cannot_forge_log_entries() {
+        assert_eq!(escape("GET /\"x\n\\ HTTP/1.1"), "GET /\\\"x\\x0a\\\\ HTTP/1.1");
+    }
+}
@@ -0,0 +1,762 @@
+use std::fs;
+
+use duplicate_file_monitor::{DirectoryOverlap, DuplicateDatabase, GroupCursor, GroupFile, GroupQuery, GroupSort, GroupSummary, OverlapQuery};
+use duplicate_file_monitor::dupdb::dupdb_hash_bytes;
+use duplicate_file_monitor::query::MAX_PAGE_SIZE;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::bulk::{self, GroupPlan, KeepRule};
+use crate::compare;
+use crate::dbpool;
+use crate::files::{self, parse_file_id};
+use crate::http::Request;
+use crate::moves;
+use crate::response::Response;
+
+/// Everything under here is JSON in and out, errors included.
+pub const PREFIX: &str = "/api/v1/";
+/// Takes a whole file as its body, see `http::Limits::upload_path`.
+pub const SEARCH_PATH: &str = "/api/v1/search";
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+#[derive(Serialize)]
+struct GroupPageBody {
+    groups: Vec<GroupSummary>,
+    /// Pass back as `cursor` for the next page, null on the last one.
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FileBody {
+    hash: String,
+    #[serde(flatten)]
+    file: GroupFile,
+    /// Ids of the other files with the same hash.
+    duplicate_ids: Vec<i64>,
+}
+
+#[derive(Serialize)]
+struct SearchBody {
+    hash: String,
+    /// Every indexed file with that hash, empty if it isn't stored anywhere.
+    files: Vec<GroupFile>,
+}
+
+#[derive(Serialize)]
+struct OverlapsBody {
+    /// Folder pairs sharing the most bytes first.
+    overlaps: Vec<DirectoryOverlap>,
+}
+
+#[derive(Serialize)]
+struct RemovedBody {
+    removed: i64,
+    path: String,
+}
+
+/// Previews never look at more groups than this, apply in batches beyond it.
+const MAX_PLANNED_GROUPS: usize = 10_000;
+
+#[derive(Serialize)]
+struct PlanBody {
+    groups: Vec<GroupPlan>,
+    files_to_remove: usize,
+    reclaimed_bytes: u64,
+    /// Groups the rule had no opinion on, like ones without a copy in the preferred folder.
+    skipped_groups: usize,
+    /// True when there were more than MAX_PLANNED_GROUPS groups to look at.
+    truncated: bool,
+}
+
+/// One group of a previewed plan, sent back exactly as it was shown so
+/// nothing the user didn't see gets removed.
+#[derive(Deserialize)]
+struct ApplyRequest {
+    hash: String,
+    keep: i64,
+    remove: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct FileResult {
+    pub id: i64,
+    pub path: Option<String>,
+    pub removed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    target_dir: String,
+    /// Then remove every other copy, leaving only the moved one.
+    #[serde(default)]
+    remove_others: bool,
+}
+
+#[derive(Serialize)]
+struct MovedBody {
+    moved: i64,
+    from: String,
+    to: String,
+    /// Only when `remove_others` was asked for.
+    removed: Vec<FileResult>,
+}
+
+#[derive(Serialize)]
+struct ApplyBody {
+    hash: String,
+    kept: String,
+    results: Vec<FileResult>,
+}
+
+pub fn error(status: u16, message: &str) -> Response {
+    Response::json(status, &ErrorBody { error: message })
+}
+
+/// `method` has already had HEAD folded into GET.
+pub fn respond(method: &str, request: &Request, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let Some(route) = request.path.strip_prefix(PREFIX) else {
+        return error(404, "No such endpoint");
+    };
+    let segments: Vec<&str> = route.trim_end_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["groups"]) => groups(request, database),
+        ("GET", ["groups", hash]) => group(hash, database),
+        ("GET", ["files", id]) => file(id, database),
+        ("DELETE", ["files", id]) => delete_file(id, request, database, state),
+        ("GET", ["files", id, "metadata"]) => file_metadata(id, database, state),
+        ("GET", ["files", id, "compare"]) => compare_copies(id, database, state),
+        ("POST", ["files", id, "move"]) => move_file(id, request, database, state),
+        ("GET", ["stats"]) => match database.stats() {
+            Ok(stats) => Response::json(200, &stats),
+            Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not read stats: {database_error}")),
+        },
+        ("GET", ["search"]) => search_by_hash(request, database),
+        ("POST", ["search"]) => search(dupdb_hash_bytes(&request.body), database),
+        ("GET", ["overlaps"]) => overlaps(request, database),
+        ("GET", ["bulk", "plan"]) => bulk_plan(request, database),
+        ("POST", ["bulk", "apply"]) => bulk_apply(request, database, state),
+        (_, ["groups"] | ["groups", _] | ["stats"] | ["overlaps"] | ["bulk", "plan"] | ["files", _, "metadata" | "compare"]) => error(405, "Use GET").with_header("Allow", "GET, HEAD"),
+        (_, ["bulk", "apply"] | ["files", _, "move"]) => error(405, "Use POST").with_header("Allow", "POST"),
+        (_, ["search"]) => error(405, "Use GET or POST").with_header("Allow", "GET, HEAD, POST"),
+        (_, ["files", _]) => error(405, "Use GET or DELETE").with_header("Allow", "GET, HEAD, DELETE"),
+        _ => error(404, "No such endpoint"),
+    }
+}
+
+fn groups(request: &Request, database: &DuplicateDatabase) -> Response {
+    let group_query = match group_query_from(&request.query) {
+        Ok(group_query) => group_query,
+        Err(message) => return error(400, &message),
+    };
+    match database.query_groups(&group_query) {
+        Ok(page) => Response::json(200, &GroupPageBody {
+            groups: page.groups,
+            next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+        }),
+        Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not query duplicates: {database_error}")),
+    }
+}
+
+fn group(hash: &str, database: &DuplicateDatabase) -> Response {
+    match database.group_summary(hash) {
+        Ok(Some(group)) => Response::json(200, &group),
+        Ok(None) => error(404, "No duplicate group with that hash"),
+        Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not look up group: {database_error}")),
+    }
+}
+
+fn file(raw_id: &str, database: &DuplicateDatabase) -> Response {
+    let Some(id) = parse_file_id(raw_id) else {
+        return error(404, "No file with that id");
+    };
+    let (hash, file) = match database.file_for_id(id) {
+        Ok(Some(found)) => found,
+        Ok(None) => return error(404, "No file with that id"),
+        Err(database_error) => return error(dbpool::error_status(&database_error), &format!("Could not look up file: {database_error}")),
+    };
+    let duplicate_ids = match database.group_summary(&hash) {
+        Ok(group) => group.map(|group| group.files).unwrap_or_default()
+            .into_iter().map(|other| other.id).filter(|other_id| *other_id != file.id).collect(),
+        Err(database_error) => return error(dbpool::error_status(&database_error), &format!("Could not look up group: {database_error}")),
+    };
+    Response::json(200, &FileBody { hash, file, duplicate_ids })
+}
+
+/// Which folders share the most, see `duplicate_file_monitor::overlap`.
+fn overlaps(request: &Request, database: &DuplicateDatabase) -> Response {
+    let overlap_query = match overlap_query_from(&request.query) {
+        Ok(overlap_query) => overlap_query,
+        Err(message) => return error(400, &message),
+    };
+    match database.directory_overlaps(&overlap_query) {
+        Ok(overlaps) => Response::json(200, &OverlapsBody { overlaps }),
+        Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not work out overlaps: {database_error}")),
+    }
+}
+
+/// `?hash=...` as the monitor stores it, for when the file is too big to send.
+fn search_by_hash(request: &Request, database: &DuplicateDatabase) -> Response {
+    let Some((_, hash)) = request.query.iter().find(|(name, _)| name == "hash") else {
+        return error(400, "Give a ?hash= to look for, or POST the file itself");
+    };
+    match hash.parse() {
+        Ok(hash) => search(hash, database),
+        Err(_) => error(400, &format!("hash must be a decimal number, not {hash}")),
+    }
+}
+
+/// Whether anything indexed has this hash, asked before saving a file
+/// that might already be stored somewhere.
+fn search(hash: u64, database: &DuplicateDatabase) -> Response {
+    let hash = hash.to_string();
+    match database.files_for_hash(&hash) {
+        Ok(files) => Response::json(200, &SearchBody { hash, files }),
+        Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not search: {database_error}")),
+    }
+}
+
+/// What's on disk right now, as opposed to what was indexed.
+fn file_metadata(raw_id: &str, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let Some(id) = parse_file_id(raw_id) else {
+        return error(404, "No file with that id");
+    };
+    match compare::metadata(database, &state.file_access, id) {
+        Ok(Some(metadata)) => Response::json(200, &metadata),
+        Ok(None) => error(404, "No file with that id"),
+        Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not look up file: {database_error}")),
+    }
+}
+
+/// Every copy of a file side by side, with a diff when they're text.
+fn compare_copies(raw_id: &str, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let Some(id) = parse_file_id(raw_id) else {
+        return error(404, "No file with that id");
+    };
+    match compare::compare(database, &state.file_access, id) {
+        Ok(Some(comparison)) => Response::json(200, &comparison),
+        Ok(None) => error(404, "No file with that id"),
+        Err(database_error) => error(dbpool::error_status(&database_error), &format!("Could not compare copies: {database_error}")),
+    }
+}
+
+/// `?path=...` is where the caller thinks the file is. Same rules as the
+/// `/remove` form, see `remove_file_by_id`.
+fn delete_file(raw_id: &str, request: &Request, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let Some(id) = parse_file_id(raw_id) else {
+        return error(404, "No file with that id");
+    };
+    let Some((_, expected_path)) = request.query.iter().find(|(name, _)| name == "path") else {
+        return error(400, "Say which file is meant to go with ?path=...");
+    };
+    match remove_file_by_id(id, expected_path, database, state) {
+        Ok(path) => Response::json(200, &RemovedBody { removed: id, path }),
+        Err((status, message)) => error(status, &message),
+    }
+}
+
+/// `{"target_dir": ..., "remove_others": false}`, see `moves::move_file`.
+fn move_file(raw_id: &str, request: &Request, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let Some(id) = parse_file_id(raw_id) else {
+        return error(404, "No file with that id");
+    };
+    let move_request: MoveRequest = match serde_json::from_slice(&request.body) {
+        Ok(move_request) => move_request,
+        Err(json_error) => return error(400, &format!("Expected {{\"target_dir\", \"remove_others\"}}: {json_error}")),
+    };
+    let moved = match moves::move_file(id, &move_request.target_dir, database, state) {
+        Ok(moved) => moved,
+        Err((status, message)) => return error(status, &message),
+    };
+    let removed = if move_request.remove_others { remove_other_copies(id, database, state) } else { Vec::new() };
+    Response::json(200, &MovedBody { moved: id, from: moved.from, to: moved.to, removed })
+}
+
+/// Removes every copy but the one behind `id`, one at a time under the
+/// usual rules, reporting how each went.
+pub fn remove_other_copies(id: i64, database: &DuplicateDatabase, state: &AppState) -> Vec<FileResult> {
+    let group = match database.file_for_id(id) {
+        Ok(Some((hash, _))) => database.group_summary(&hash),
+        Ok(None) => Ok(None),
+        Err(database_error) => Err(database_error),
+    };
+    let others = match group {
+        Ok(group) => group.map(|group| group.files).unwrap_or_default(),
+        Err(database_error) => {
+            return vec![FileResult { id, path: None, removed: false, error: Some(format!("Could not look up the other copies: {database_error}")) }];
+        },
+    };
+    others.into_iter().filter(|other| other.id != id).map(|other| match remove_file_by_id(other.id, &other.path, database, state) {
+        Ok(path) => FileResult { id: other.id, path: Some(path), removed: true, error: None },
+        Err((_, message)) => FileResult { id: other.id, path: Some(other.path), removed: false, error: Some(message) },
+    }).collect()
+}
+
+/// Removes the file behind an id from disk, returning its path, or the
+/// status and reason it was refused. Nothing goes unless the id is still
+/// the file at `expected_path`, whatever the caller last saw may be stale.
+pub fn remove_file_by_id(id: i64, expected_path: &str, database: &DuplicateDatabase, state: &AppState) -> Result<String, (u16, String)> {
+    let path = match database.path_for_file_id(id) {
+        Ok(Some(path)) => path,
+        Ok(None) => return Err((404, "No file with that id".to_string())),
+        Err(database_error) => return Err((dbpool::error_status(&database_error), format!("Could not look up file: {database_error}"))),
+    };
+    if path != expected_path {
+        return Err((409, format!("File {id} is {path} now, not {expected_path}, reload and try again")));
+    }
+    // Resolving refuses anything outside what we'd serve, so we never
+    // delete anything we wouldn't also show.
+    if state.file_access.resolve(database, &id.to_string()).is_err() {
+        return Err((404, "No file with that id".to_string()));
+    }
+    files::check_removable(database, &path).map_err(|reason| (409, reason))?;
+    fs::remove_file(&path).map_err(|io_error| (500, format!("Could not remove {path}: {io_error}")))?;
+    state.metrics.record_file_removed();
+    Ok(path)
+}
+
+/// `?rule=...&folder=...` plus any of the `groups` filters. Looks at every
+/// matching group, not just one page, so the totals mean something.
+fn bulk_plan(request: &Request, database: &DuplicateDatabase) -> Response {
+    let param = |name: &str| request.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    let rule = match KeepRule::parse(param("rule").unwrap_or(""), param("folder")) {
+        Ok(rule) => rule,
+        Err(message) => return error(400, &message),
+    };
+    let filters: Vec<(String, String)> = request.query.iter()
+        .filter(|(name, _)| !matches!(name.as_str(), "rule" | "folder" | "cursor" | "limit"))
+        .cloned()
+        .collect();
+    let mut group_query = match group_query_from(&filters) {
+        Ok(group_query) => group_query,
+        Err(message) => return error(400, &message),
+    };
+    group_query.limit = MAX_PAGE_SIZE;
+
+    let mut plan = PlanBody { groups: Vec::new(), files_to_remove: 0, reclaimed_bytes: 0, skipped_groups: 0, truncated: false };
+    let mut groups_seen = 0;
+    loop {
+        let page = match database.query_groups(&group_query) {
+            Ok(page) => page,
+            Err(database_error) => return error(dbpool::error_status(&database_error), &format!("Could not query duplicates: {database_error}")),
+        };
+        for group in &page.groups {
+            match bulk::plan_for_group(group, &rule) {
+                Some(group_plan) => {
+                    plan.files_to_remove += group_plan.remove.len();
+                    plan.reclaimed_bytes += group_plan.reclaimed_bytes;
+                    plan.groups.push(group_plan);
+                },
+                None => plan.skipped_groups += 1,
+            }
+        }
+        groups_seen += page.groups.len();
+        match page.next_cursor {
+            Some(_) if groups_seen >= MAX_PLANNED_GROUPS => {
+                plan.truncated = true;
+                break;
+            },
+            Some(cursor) => group_query.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    Response::json(200, &plan)
+}
+
+/// Carries out one group of a plan. The kept file has to still be on disk
+/// and everything listed has to still be in the group, otherwise the plan
+/// is stale and nothing in the group is touched.
+fn bulk_apply(request: &Request, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let apply: ApplyRequest = match serde_json::from_slice(&request.body) {
+        Ok(apply) => apply,
+        Err(json_error) => return error(400, &format!("Expected {{\"hash\", \"keep\", \"remove\"}}: {json_error}")),
+    };
+    let group = match database.group_summary(&apply.hash) {
+        Ok(Some(group)) => group,
+        Ok(None) => return error(404, "No duplicate group with that hash"),
+        Err(database_error) => return error(dbpool::error_status(&database_error), &format!("Could not look up group: {database_error}")),
+    };
+    let Some(kept) = group.files.iter().find(|file| file.id == apply.keep) else {
+        return error(409, "The file to keep is no longer in this group, preview again");
+    };
+    if !std::path::Path::new(&kept.path).is_file() {
+        return error(409, &format!("{} is gone from disk, preview again", kept.path));
+    }
+    if let Some(stranger) = apply.remove.iter().find(|id| **id == apply.keep || !group.files.iter().any(|file| file.id == **id)) {
+        return error(409, &format!("File {stranger} is not a removable member of this group, preview again"));
+    }
+    // Keeping a symlink to a removed file, or the file itself through a
+    // symlinked folder, keeps nothing.
+    let mut removed_files = group.files.iter().filter(|file| apply.remove.contains(&file.id));
+    if let Some(same) = removed_files.find(|file| files::is_same_file(&file.path, &kept.path)) {
+        return error(409, &format!("{} is the same file on disk as {}, keep another copy", kept.path, same.path));
+    }
+
+    let to_remove = apply.remove.iter().filter_map(|id| group.files.iter().find(|file| file.id == *id));
+    let results = to_remove.map(|file| match remove_file_by_id(file.id, &file.path, database, state) {
+        Ok(path) => FileResult { id: file.id, path: Some(path), removed: true, error: None },
+        Err((_, message)) => FileResult { id: file.id, path: Some(file.path.clone()), removed: false, error: Some(message) },
+    }).collect();
+    Response::json(200, &ApplyBody { hash: group.hash, kept: kept.path.clone(), results })
+}
+
+/// Builds a group query out of `?...` parameters:
+/// prefix, ext, min_size, since (unix seconds), sort (wasted, count or newest), cursor and limit.
+pub fn group_query_from(query: &[(String, String)]) -> Result<GroupQuery, String> {
+    let mut group_query = GroupQuery::default();
+    for (name, value) in query {
+        if value.is_empty() {
+            continue;
+        }
+        match &name[..] {
+            "prefix" => group_query.path_prefix = Some(value.clone()),
+            "ext" => group_query.extension = Some(value.clone()),
+            "min_size" => group_query.min_size = Some(
+                value.parse().map_err(|_| format!("min_size must be a number of bytes, not {value}"))?
+            ),
+            "since" => group_query.modified_since = Some(
+                value.parse().map_err(|_| format!("since must be unix seconds, not {value}"))?
+            ),
+            "sort" => group_query.sort = GroupSort::parse(value)
+                .ok_or(format!("sort must be wasted, count or newest, not {value}"))?,
+            "cursor" => group_query.cursor = Some(
+                GroupCursor::parse(value).ok_or(format!("Invalid cursor {value}"))?
+            ),
+            "limit" => group_query.limit = value.parse()
+                .map_err(|_| format!("limit must be a number, not {value}"))?,
+            unknown => return Err(format!("Unknown parameter {unknown}")),
+        }
+    }
+    Ok(group_query)
+}
+
+fn overlap_query_from(query: &[(String, String)]) -> Result<OverlapQuery, String> {
+    let mut overlap_query = OverlapQuery::default();
+    for (name, value) in query {
+        if value.is_empty() {
+            continue;
+        }
+        match &name[..] {
+            "prefix" => overlap_query.path_prefix = Some(value.clone()),
+            "min_shared" => overlap_query.min_shared_files = value.parse()
+                .map_err(|_| format!("min_shared must be a number of files, not {value}"))?,
+            "limit" => overlap_query.limit = value.parse()
+                .map_err(|_| format!("limit must be a number, not {value}"))?,
+            unknown => return Err(format!("Unknown parameter {unknown}")),
+        }
+    }
+    Ok(overlap_query)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::accesslog::LogFormat;
+    use crate::dbpool::DatabasePool;
+    use crate::assets::Assets;
+    use crate::events::LiveClients;
+    use crate::metrics::Metrics;
+    use fixed_thread_pool::FixedThreadPool;
+    use crate::files::FileAccess;
+    use crate::http::{read_request, Limits};
+    use crate::response::Body;
+    use crate::shutdown::Shutdown;
+    use crate::test_support::ScratchDir;
+    use crate::thumbs::Thumbnails;
+    use std::io::Cursor;
+
+    /// Two copies of one file and a loner, indexed in a scratch database.
+    struct Scratch {
+        database: DuplicateDatabase,
+        state: AppState,
+        dir: ScratchDir,
+    }
+
+    impl Scratch {
+        fn new() -> Scratch {
+            let dir = ScratchDir::new("api");
+            let mut database = dir.database();
+            for (hash, name) in [(1, "a.txt"), (1, "b.txt"), (2, "solo.txt")] {
+                dir.write(name, name);
+                database.add(hash, dir.path_of(name));
+            }
+            let state = AppState {
+                file_access: FileAccess::default(),
+                auth: Auth::new(None),
+                thumbnails: Thumbnails::new(dir.join("thumbnails")),
+                live_clients: LiveClients::new(1),
+                metrics: Metrics::default(),
+                pool: FixedThreadPool::new(1, 0).observer(),
+                log_format: LogFormat::Common,
+                assets: Assets::new(None),
+                shutdown: Shutdown::new(),
+                database_writer: DatabasePool::new(dir.database_path(), 1).writer(),
+            };
+            Scratch { database, state, dir }
+        }
+
+        fn call(&self, method: &str, target: &str) -> (u16, serde_json::Value) {
+            self.call_with_body(method, target, "")
+        }
+
+        fn call_with_body(&self, method: &str, target: &str, body: &str) -> (u16, serde_json::Value) {
+            let raw = format!("{method} {target} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+            let request = read_request(&mut Cursor::new(raw.into_bytes()), &Limits::default()).expect("should parse");
+            let response = respond(method, &request, &self.database, &self.state);
+            let Body::Bytes(bytes) = response.body else {
+                panic!("API responses should be in memory");
+            };
+            (response.status, serde_json::from_slice(&bytes).expect("API responses should be JSON"))
+        }
+
+        /// Where to DELETE the file at `name`.
+        fn delete_target(&self, name: &str) -> String {
+            let query = form_urlencoded::Serializer::new(String::new()).append_pair("path", &self.dir.path_of(name)).finish();
+            format!("/api/v1/files/{}?{query}", self.id_of(name))
+        }
+
+        fn id_of(&self, name: &str) -> i64 {
+            self.database.file_id_for_path(&self.dir.path_of(name)).expect("lookup failed").expect("row missing")
+        }
+    }
+
+    #[test]
+    fn groups_files_and_stats_are_json() {
+        let scratch = Scratch::new();
+        let (status, body) = scratch.call("GET", "/api/v1/groups?limit=10");
+        assert_eq!(status, 200);
+        assert_eq!(body["groups"].as_array().map(Vec::len), Some(1));
+        assert_eq!(body["groups"][0]["hash"], "1");
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+
+        let (status, body) = scratch.call("GET", "/api/v1/groups/1");
+        assert_eq!(status, 200);
+        assert_eq!(body["copies"], 2);
+
+        let a = scratch.id_of("a.txt");
+        let (status, body) = scratch.call("GET", &format!("/api/v1/files/{a}"));
+        assert_eq!(status, 200);
+        assert_eq!(body["hash"], "1");
+        assert_eq!(body["duplicate_ids"], serde_json::json!([scratch.id_of("b.txt")]));
+
+        let (status, body) = scratch.call("GET", "/api/v1/stats");
+        assert_eq!(status, 200);
+        assert_eq!(body["files_indexed"], 3);
+        assert_eq!(body["duplicate_groups"], 1);
+    }
+
+    #[test]
+    fn errors_are_json_with_proper_statuses() {
+        let scratch = Scratch::new();
+        assert_eq!(scratch.call("GET", "/api/v1/groups/2").0, 404);
+        assert_eq!(scratch.call("GET", "/api/v1/files/9999").0, 404);
+        assert_eq!(scratch.call("GET", "/api/v1/files/abc").0, 404);
+        assert_eq!(scratch.call("GET", "/api/v1/nothing").0, 404);
+        assert_eq!(scratch.call("POST", "/api/v1/stats").0, 405);
+        let (status, body) = scratch.call("GET", "/api/v1/groups?sort=sideways");
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().is_some_and(|message| message.contains("sideways")));
+    }
+
+    #[test]
+    fn deleting_keeps_the_last_copy() {
+        let scratch = Scratch::new();
+        let a = scratch.id_of("a.txt");
+        assert_eq!(scratch.call("DELETE", &scratch.delete_target("solo.txt")).0, 409);
+
+        // Without saying which file, or when the id isn't that file any more, nothing goes.
+        assert_eq!(scratch.call("DELETE", &format!("/api/v1/files/{a}")).0, 400);
+        let stale = format!("/api/v1/files/{a}?path={}", scratch.dir.path_of("b.txt"));
+        assert_eq!(scratch.call("DELETE", &stale).0, 409);
+        assert!(scratch.dir.join("a.txt").exists());
+
+        let (status, body) = scratch.call("DELETE", &scratch.delete_target("a.txt"));
+        assert_eq!(status, 200);
+        assert_eq!(body["removed"], a);
+        assert!(!scratch.dir.join("a.txt").exists());
+
+        // The database still lists a.txt until the monitor notices, but it's
+        // gone from disk so b.txt is now the last copy.
+        assert_eq!(scratch.call("DELETE", &scratch.delete_target("b.txt")).0, 409);
+        assert!(scratch.dir.join("b.txt").exists());
+    }
+
+    #[test]
+    fn moving_updates_the_database_and_never_replaces() {
+        let mut scratch = Scratch::new();
+        let (a, b) = (scratch.id_of("a.txt"), scratch.id_of("b.txt"));
+        let target = scratch.dir.join("kept/photos");
+        let body = format!(r#"{{"target_dir":"{}","remove_others":true}}"#, target.display());
+        // Without --root there's nowhere it's allowed to go.
+        assert_eq!(scratch.call_with_body("POST", &format!("/api/v1/files/{a}/move"), &body).0, 403);
+
+        scratch.state.file_access = FileAccess::new(&[scratch.dir.path()]);
+        assert_eq!(scratch.call_with_body("POST", &format!("/api/v1/files/{a}/move"), "{}").0, 400);
+        let outside = r#"{"target_dir":"/"}"#;
+        assert_eq!(scratch.call_with_body("POST", &format!("/api/v1/files/{a}/move"), outside).0, 403);
+        fs::create_dir_all(&target).expect("Could not create target dir");
+        fs::write(target.join("a.txt"), "in the way").expect("Could not write test file");
+        assert_eq!(scratch.call_with_body("POST", &format!("/api/v1/files/{a}/move"), &body).0, 409);
+        assert!(scratch.dir.join("a.txt").exists());
+        fs::remove_file(target.join("a.txt")).expect("Could not remove test file");
+
+        let (status, response) = scratch.call_with_body("POST", &format!("/api/v1/files/{a}/move"), &body);
+        assert_eq!(status, 200);
+        let moved_to = target.join("a.txt").to_string_lossy().to_string();
+        assert_eq!(response["to"], moved_to.as_str());
+        assert_eq!(response["removed"][0]["id"], b);
+        assert_eq!(response["removed"][0]["removed"], true);
+        assert!(!scratch.dir.join("a.txt").exists());
+        assert!(!scratch.dir.join("b.txt").exists());
+        assert_eq!(fs::read_to_string(&moved_to).expect("should be moved"), "a.txt");
+        assert_eq!(scratch.database.file_id_for_path(&moved_to).expect("lookup failed"), Some(a));
+
+        assert_eq!(scratch.call_with_body("POST", &format!("/api/v1/files/{a}/move"), &body).0, 409);
+        assert_eq!(scratch.call("GET", &format!("/api/v1/files/{a}/move")).0, 405);
+    }
+
+    #[test]
+    fn overlapping_folders_are_reported() {
+        let mut scratch = Scratch::new();
+        assert_eq!(scratch.call("GET", "/api/v1/overlaps").1["overlaps"], serde_json::json!([]));
+
+        fs::create_dir_all(scratch.dir.join("copy")).expect("Could not create test dir");
+        for (hash, name) in [(1, "copy/a.txt"), (2, "copy/solo.txt"), (3, "copy/extra.txt")] {
+            assert!(scratch.database.add(hash, scratch.dir.path_of(name)));
+        }
+        let (status, body) = scratch.call("GET", "/api/v1/overlaps");
+        assert_eq!(status, 200);
+        let overlap = &body["overlaps"][0];
+        assert_eq!(overlap["first"]["path"], scratch.dir.path().to_string_lossy().as_ref());
+        assert_eq!(overlap["shared_files"], 2);
+        assert_eq!(overlap["first"]["fully_contained"], true);
+        assert_eq!(overlap["second"]["files"], 3);
+        assert_eq!(overlap["second"]["overlap_percent"], 66.7);
+        assert_eq!(overlap["second"]["fully_contained"], false);
+
+        assert_eq!(scratch.call("GET", "/api/v1/overlaps?min_shared=3").1["overlaps"], serde_json::json!([]));
+        assert_eq!(scratch.call("GET", "/api/v1/overlaps?min_shared=lots").0, 400);
+        assert_eq!(scratch.call("GET", "/api/v1/overlaps?sort=count").0, 400);
+        assert_eq!(scratch.call("POST", "/api/v1/overlaps").0, 405);
+    }
+
+    #[test]
+    fn copies_can_be_compared() {
+        let scratch = Scratch::new();
+        let (a, b) = (scratch.id_of("a.txt"), scratch.id_of("b.txt"));
+        let (status, body) = scratch.call("GET", &format!("/api/v1/files/{a}/metadata"));
+        assert_eq!(status, 200);
+        assert_eq!(body["name"], "a.txt");
+        assert_eq!(body["available"], true);
+        assert_eq!(body["preview"], "text");
+
+        let (status, body) = scratch.call("GET", &format!("/api/v1/files/{b}/compare"));
+        assert_eq!(status, 200);
+        assert_eq!(body["copies"][0]["id"], b);
+        assert_eq!(body["copies"][1]["id"], a);
+        // Test files share a hash without sharing contents, which is what the diff is for.
+        assert_eq!(body["text"][0]["identical"], false);
+        assert_eq!(body["text"][0]["diff"], serde_json::json!(["-b.txt", "+a.txt"]));
+
+        assert_eq!(scratch.call("GET", "/api/v1/files/9999/compare").0, 404);
+        assert_eq!(scratch.call("POST", &format!("/api/v1/files/{a}/metadata")).0, 405);
+    }
+
+    #[test]
+    fn files_can_be_searched_for_by_hash_or_contents() {
+        let mut scratch = Scratch::new();
+        let (status, body) = scratch.call("GET", "/api/v1/search?hash=2");
+        assert_eq!(status, 200);
+        assert_eq!(body["files"][0]["id"], scratch.id_of("solo.txt"));
+        assert_eq!(scratch.call("GET", "/api/v1/search?hash=3").1["files"], serde_json::json!([]));
+        assert_eq!(scratch.call("GET", "/api/v1/search?hash=abc").0, 400);
+        assert_eq!(scratch.call("GET", "/api/v1/search").0, 400);
+
+        let stored = scratch.dir.path_of("stored.txt");
+        scratch.database.add(dupdb_hash_bytes(b"already here"), stored.clone());
+        let (status, body) = scratch.call_with_body("POST", "/api/v1/search", "already here");
+        assert_eq!(status, 200);
+        assert_eq!(body["hash"], dupdb_hash_bytes(b"already here").to_string());
+        assert_eq!(body["files"][0]["path"], stored);
+        assert_eq!(scratch.call_with_body("POST", "/api/v1/search", "something new").1["files"], serde_json::json!([]));
+        assert_eq!(scratch.call("DELETE", "/api/v1/search").0, 405);
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn group_queries_are_built_from_parameters() {
+        let group_query = group_query_from(&query(&[("prefix", "/photos"), ("sort", "count"), ("limit", "5"), ("ext", "")]))
+            .expect("should parse");
+        assert_eq!(group_query.path_prefix.as_deref(), Some("/photos"));
+        assert_eq!(group_query.sort, GroupSort::Count);
+        assert_eq!(group_query.limit, 5);
+        assert_eq!(group_query.extension, None);
+    }
+
+    #[test]
+    fn bad_parameters_are_reported() {
+        assert!(group_query_from(&query(&[("sort", "sideways")])).is_err());
+        assert!(group_query_from(&query(&[("limit", "lots")])).is_err());
+        assert!(group_query_from(&query(&[("cursor", "nope")])).is_err());
+        assert!(group_query_from(&query(&[("colour", "blue")])).is_err());
+    }
+
+    #[test]
+    fn bulk_plans_preview_and_apply_group_by_group() {
+        let scratch = Scratch::new();
+        let (a, b) = (scratch.id_of("a.txt"), scratch.id_of("b.txt"));
+        assert_eq!(scratch.call("GET", "/api/v1/bulk/plan?rule=largest").0, 400);
+        assert_eq!(scratch.call("GET", "/api/v1/bulk/plan?rule=folder").0, 400);
+
+        let (status, plan) = scratch.call("GET", "/api/v1/bulk/plan?rule=shortest");
+        assert_eq!(status, 200);
+        assert_eq!(plan["files_to_remove"], 1);
+        assert_eq!(plan["skipped_groups"], 0);
+        assert_eq!(plan["groups"][0]["keep"]["id"], a);
+        assert_eq!(plan["groups"][0]["remove"][0]["id"], b);
+
+        // Asking to remove the file being kept, or one from elsewhere, touches nothing.
+        let solo = scratch.id_of("solo.txt");
+        for remove in [a, solo] {
+            let body = format!("{{\"hash\": \"1\", \"keep\": {a}, \"remove\": [{remove}]}}");
+            assert_eq!(scratch.call_with_body("POST", "/api/v1/bulk/apply", &body).0, 409);
+        }
+        assert_eq!(scratch.call_with_body("POST", "/api/v1/bulk/apply", "not json").0, 400);
+
+        let body = format!("{{\"hash\": \"1\", \"keep\": {a}, \"remove\": [{b}]}}");
+        let (status, applied) = scratch.call_with_body("POST", "/api/v1/bulk/apply", &body);
+        assert_eq!(status, 200);
+        assert_eq!(applied["results"][0]["removed"], true);
+        assert!(scratch.dir.join("a.txt").exists());
+        assert!(!scratch.dir.join("b.txt").exists());
+
+        // Running it again reports the failure per file instead of failing the group.
+        let (status, applied) = scratch.call_with_body("POST", "/api/v1/bulk/apply", &body);
+        assert_eq!(status, 200);
+        assert_eq!(applied["results"][0]["removed"], false);
+        assert!(applied["results"][0]["error"].is_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeping_a_symlink_to_a_removed_file_keeps_nothing() {
+        let mut scratch = Scratch::new();
+        let link = scratch.dir.join("link.txt");
+        std::os::unix::fs::symlink(scratch.dir.join("b.txt"), &link).expect("Could not make symlink");
+        assert!(scratch.database.add(1, scratch.dir.path_of("link.txt")));
+        let (b, link) = (scratch.id_of("b.txt"), scratch.id_of("link.txt"));
+
+        let body = format!("{{\"hash\": \"1\", \"keep\": {link}, \"remove\": [{b}]}}");
+        assert_eq!(scratch.call_with_body("POST", "/api/v1/bulk/apply", &body).0, 409);
+        assert!(scratch.dir.join("b.txt").exists());
+    }
+}
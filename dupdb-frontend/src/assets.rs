@@ -0,0 +1,222 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::http::Request;
+use crate::mime;
+use crate::response::{self, Response};
+
+pub const PREFIX: &str = "/assets/";
+pub const INDEX: &str = "index.html";
+pub const COMPARE: &str = "compare.html";
+pub const OVERLAPS: &str = "overlaps.html";
+
+/// The page and everything it loads, compiled in so the binary works from
+/// any directory. Only these names are ever served, `--assets-dir` included.
+const EMBEDDED: &[(&str, &[u8])] = &[
+    (INDEX, include_bytes!("../assets/index.html")),
+    ("app.js", include_bytes!("../assets/app.js")),
+    (COMPARE, include_bytes!("../assets/compare.html")),
+    ("compare.js", include_bytes!("../assets/compare.js")),
+    (OVERLAPS, include_bytes!("../assets/overlaps.html")),
+    ("overlaps.js", include_bytes!("../assets/overlaps.js")),
+    ("style.css", include_bytes!("../assets/style.css")),
+    ("favicon.svg", include_bytes!("../assets/favicon.svg")),
+];
+
+/// Changes whenever any embedded asset does, and goes in the page's asset
+/// URLs so a new build is never served an old build's script from cache.
+const EMBEDDED_VERSION: u64 = {
+    let mut hash = FNV_OFFSET;
+    let mut asset = 0;
+    while asset < EMBEDDED.len() {
+        hash = fnv1a(EMBEDDED[asset].1, hash);
+        asset += 1;
+    }
+    hash
+};
+
+/// A versioned URL's content never changes, so browsers may keep it for good.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// Anything else gets checked each time, which the ETag makes cheap.
+const REVALIDATE: &str = "no-cache";
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Not for anything adversarial, just to notice content changing.
+const fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    let mut index = 0;
+    while index < bytes.len() {
+        hash ^= bytes[index] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        index += 1;
+    }
+    hash
+}
+
+/// Serves the UI, from the binary or from `dir` while working on it.
+#[derive(Debug, Clone)]
+pub struct Assets {
+    /// Files here are read on every request, so edits show up on reload.
+    /// Anything missing falls back to the embedded copy.
+    dir: Option<PathBuf>,
+}
+
+impl Assets {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Assets { dir }
+    }
+
+    fn version(&self) -> String {
+        match self.dir {
+            // Files on disk change under us, so they're always revalidated instead.
+            Some(_) => "dev".to_string(),
+            None => format!("{EMBEDDED_VERSION:x}"),
+        }
+    }
+
+    /// None for anything that isn't one of our assets.
+    fn get(&self, name: &str) -> io::Result<Option<Cow<'static, [u8]>>> {
+        let Some((name, embedded)) = EMBEDDED.iter().find(|(embedded_name, _)| *embedded_name == name) else {
+            return Ok(None);
+        };
+        if let Some(dir) = &self.dir {
+            match fs::read(dir.join(name)) {
+                Ok(bytes) => return Ok(Some(Cow::Owned(bytes))),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(Some(Cow::Borrowed(embedded)))
+    }
+
+    /// One of the pages, with the CSRF token filled in. It's never cached
+    /// for long since the token is only good until the server restarts.
+    pub fn page(&self, name: &str, csrf_token: &str) -> Response {
+        match self.get(name) {
+            Ok(Some(page)) => {
+                let page = String::from_utf8_lossy(&page)
+                    .replace("{{csrf_token}}", csrf_token)
+                    .replace("{{assets_version}}", &self.version());
+                Response::html(&page).with_header("Cache-Control", "private, no-cache")
+            },
+            Ok(None) => Response::text(500, "The page is missing from this build"),
+            Err(error) => Response::text(500, &format!("Could not read {name}: {error}")),
+        }
+    }
+
+    /// `name` is whatever followed `/assets/` in the request.
+    pub fn response(&self, request: &Request, name: &str) -> Response {
+        // Pages only make sense with their token filled in, see `page`.
+        if name.ends_with(".html") {
+            return Response::text(404, "Not found");
+        }
+        let bytes = match self.get(name) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Response::text(404, "Not found"),
+            Err(error) => return Response::text(500, &format!("Could not read {name}: {error}")),
+        };
+
+        let version = self.version();
+        let versioned = self.dir.is_none()
+            && request.query.iter().any(|(parameter, value)| parameter == "v" && *value == version);
+        let etag = format!("\"{:x}\"", fnv1a(&bytes, FNV_OFFSET));
+        let validators = Response::new(200)
+            .with_header("ETag", &etag)
+            .with_header("Cache-Control", if versioned { IMMUTABLE } else { REVALIDATE });
+        if response::etag_matches(request, &etag) {
+            return Response { status: 304, ..validators };
+        }
+        let content_type = mime::from_extension(Path::new(name)).unwrap_or(mime::FALLBACK);
+        validators.with_header("Content-Type", content_type).with_body(bytes.into_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+    use crate::http::{self, Limits};
+    use std::io::Cursor;
+
+    fn get(target: &str, headers: &str) -> Request {
+        let raw = format!("GET {target} HTTP/1.1\r\n{headers}\r\n");
+        http::read_request(&mut Cursor::new(raw.into_bytes()), &Limits::default()).expect("Could not parse test request")
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response.headers.iter().find(|(header_name, _)| header_name == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn the_page_links_versioned_assets() {
+        let assets = Assets::new(None);
+        let page = assets.page(INDEX, "secret");
+        let body = match &page.body {
+            response::Body::Bytes(bytes) => String::from_utf8_lossy(bytes).to_string(),
+            _ => panic!("page should be in memory"),
+        };
+        assert!(body.contains("value=\"secret\""));
+        assert!(!body.contains("{{"));
+        assert!(body.contains(&format!("/assets/app.js?v={EMBEDDED_VERSION:x}")));
+
+        let compare = assets.page(COMPARE, "secret");
+        let response::Body::Bytes(bytes) = &compare.body else {
+            panic!("page should be in memory");
+        };
+        let body = String::from_utf8_lossy(bytes);
+        assert!(body.contains("value=\"secret\"") && !body.contains("{{"));
+        assert!(body.contains(&format!("/assets/compare.js?v={EMBEDDED_VERSION:x}")));
+
+        let overlaps = assets.page(OVERLAPS, "secret");
+        let response::Body::Bytes(bytes) = &overlaps.body else {
+            panic!("page should be in memory");
+        };
+        assert!(String::from_utf8_lossy(bytes).contains(&format!("/assets/overlaps.js?v={EMBEDDED_VERSION:x}")));
+    }
+
+    #[test]
+    fn embedded_assets_are_served_with_types_and_caching() {
+        let assets = Assets::new(None);
+        let versioned = assets.response(&get(&format!("/assets/app.js?v={EMBEDDED_VERSION:x}"), ""), "app.js");
+        assert_eq!(versioned.status, 200);
+        assert_eq!(header(&versioned, "Content-Type"), Some("text/javascript; charset=utf-8"));
+        assert_eq!(header(&versioned, "Cache-Control"), Some(IMMUTABLE));
+
+        let unversioned = assets.response(&get("/assets/style.css", ""), "style.css");
+        assert_eq!(header(&unversioned, "Content-Type"), Some("text/css; charset=utf-8"));
+        assert_eq!(header(&unversioned, "Cache-Control"), Some(REVALIDATE));
+
+        let etag = header(&unversioned, "ETag").expect("should have an etag").to_string();
+        let cached = assets.response(&get("/assets/style.css", &format!("If-None-Match: {etag}\r\n")), "style.css");
+        assert_eq!(cached.status, 304);
+        assert_eq!(cached.content_length(), 0);
+    }
+
+    #[test]
+    fn only_known_assets_are_served() {
+        let assets = Assets::new(None);
+        assert_eq!(assets.response(&get("/assets/nope.js", ""), "nope.js").status, 404);
+        assert_eq!(assets.response(&get("/assets/../Cargo.toml", ""), "../Cargo.toml").status, 404);
+        assert_eq!(assets.response(&get("/assets/index.html", ""), INDEX).status, 404);
+        assert_eq!(assets.response(&get("/assets/compare.html", ""), COMPARE).status, 404);
+    }
+
+    #[test]
+    fn an_assets_dir_overrides_what_it_has() {
+        let scratch = ScratchDir::new("assets");
+        fs::write(scratch.join("app.js"), "console.log('dev');").expect("Could not write test asset");
+        fs::write(scratch.join("secret.txt"), "no").expect("Could not write test file");
+        let assets = Assets::new(Some(scratch.path().to_path_buf()));
+
+        let overridden = assets.response(&get("/assets/app.js?v=dev", ""), "app.js");
+        assert_eq!(overridden.content_length(), "console.log('dev');".len() as u64);
+        assert_eq!(header(&overridden, "Cache-Control"), Some(REVALIDATE));
+
+        let fallback = assets.response(&get("/assets/style.css", ""), "style.css");
+        assert_eq!(fallback.content_length(), include_bytes!("../assets/style.css").len() as u64);
+        assert_eq!(assets.response(&get("/assets/secret.txt", ""), "secret.txt").status, 404);
+    }
+}
@@ -0,0 +1,128 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+pub const CSRF_FIELD: &str = "csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Who may talk to the server and proof that a form came from our own page.
+///
+/// The access token is optional, without one anybody who can reach the port
+/// is let in (fine on localhost, not so fine on a LAN). It can be sent as
+/// `Authorization: Bearer <token>` or as the password of basic auth, which
+/// gets browsers to prompt for it. The username is ignored.
+///
+/// The CSRF token is random per process. It's handed to our own page when
+/// it's served, and other sites can't read our page so they can't know it.
+#[derive(Debug, Clone)]
+pub struct Auth {
+    access_token: Option<String>,
+    csrf_token: String,
+}
+
+impl Auth {
+    pub fn new(access_token: Option<String>) -> Self {
+        Auth {
+            access_token: access_token.filter(|token| !token.is_empty()),
+            csrf_token: random_token(),
+        }
+    }
+
+    pub fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+
+    /// Checks the value of the Authorization header, if any was sent.
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(expected) = &self.access_token else {
+            return true;
+        };
+        let Some(authorization) = authorization else {
+            return false;
+        };
+        let offered = match authorization.trim().split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim().to_string(),
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                match basic_auth_password(credentials.trim()) {
+                    Some(password) => password,
+                    None => return false,
+                }
+            },
+            _ => return false,
+        };
+        constant_time_eq(offered.as_bytes(), expected.as_bytes())
+    }
+
+    pub fn is_valid_csrf(&self, offered: Option<&str>) -> bool {
+        match offered {
+            Some(offered) => constant_time_eq(offered.as_bytes(), self.csrf_token.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+fn basic_auth_password(credentials: &str) -> Option<String> {
+    let decoded = STANDARD.decode(credentials).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (_username, password) = decoded.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// Compares every byte no matter where the first difference is, so the time
+/// taken says nothing about how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+/// Panics if the OS can't give us randomness, in which case we've got
+/// bigger problems than a web page.
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).expect("Could not get random bytes for the CSRF token");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_token_lets_everyone_in() {
+        let auth = Auth::new(None);
+        assert!(auth.is_authorized(None));
+        assert!(auth.is_authorized(Some("Bearer whatever")));
+    }
+
+    #[test]
+    fn bearer_and_basic_auth_are_both_accepted() {
+        let auth = Auth::new(Some("s3cret".to_string()));
+        assert!(auth.is_authorized(Some("Bearer s3cret")));
+        let basic = STANDARD.encode("anyone:s3cret");
+        assert!(auth.is_authorized(Some(&format!("Basic {basic}"))));
+    }
+
+    #[test]
+    fn wrong_or_missing_credentials_are_refused() {
+        let auth = Auth::new(Some("s3cret".to_string()));
+        assert!(!auth.is_authorized(None));
+        assert!(!auth.is_authorized(Some("Bearer s3cre")));
+        assert!(!auth.is_authorized(Some("Bearer s3cret!")));
+        assert!(!auth.is_authorized(Some("s3cret")));
+        assert!(!auth.is_authorized(Some("Basic not-base64")));
+        let no_colon = STANDARD.encode("s3cret");
+        assert!(!auth.is_authorized(Some(&format!("Basic {no_colon}"))));
+    }
+
+    #[test]
+    fn csrf_tokens_must_match_exactly() {
+        let auth = Auth::new(None);
+        let token = auth.csrf_token().to_string();
+        assert_eq!(token.len(), 64);
+        assert!(auth.is_valid_csrf(Some(&token)));
+        assert!(!auth.is_valid_csrf(Some("")));
+        assert!(!auth.is_valid_csrf(None));
+        assert_ne!(Auth::new(None).csrf_token(), token);
+    }
+}
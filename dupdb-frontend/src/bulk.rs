@@ -0,0 +1,129 @@
+use std::path::Path;
+
+use duplicate_file_monitor::{GroupFile, GroupSummary};
+use serde::Serialize;
+
+/// Which copy in each group survives a bulk clean up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeepRule {
+    Oldest,
+    Newest,
+    ShortestPath,
+    /// The copy under this folder. Groups with no copy there are left alone.
+    UnderFolder(String),
+}
+
+impl KeepRule {
+    /// `rule` is oldest, newest, shortest or folder, the last one needing `folder`.
+    pub fn parse(rule: &str, folder: Option<&str>) -> Result<KeepRule, String> {
+        match rule {
+            "oldest" => Ok(KeepRule::Oldest),
+            "newest" => Ok(KeepRule::Newest),
+            "shortest" => Ok(KeepRule::ShortestPath),
+            "folder" => match folder.map(str::trim).filter(|folder| !folder.is_empty()) {
+                Some(folder) => Ok(KeepRule::UnderFolder(folder.to_string())),
+                None => Err("rule folder needs a folder to prefer".to_string()),
+            },
+            _ => Err(format!("rule must be oldest, newest, shortest or folder, not {rule}")),
+        }
+    }
+
+    /// The file to keep, None when the rule has no opinion on this group.
+    /// Ties go to the shortest and then alphabetically first path so the
+    /// same group always gets the same plan.
+    fn pick<'a>(&self, files: &'a [GroupFile]) -> Option<&'a GroupFile> {
+        let by_path = |file: &&GroupFile| (file.path.len(), file.path.clone());
+        match self {
+            KeepRule::Oldest => files.iter()
+                .filter(|file| file.modified.is_some())
+                .min_by_key(|file| (file.modified, by_path(file))),
+            KeepRule::Newest => files.iter()
+                .filter(|file| file.modified.is_some())
+                .min_by_key(|file| (std::cmp::Reverse(file.modified), by_path(file))),
+            KeepRule::ShortestPath => files.iter().min_by_key(by_path),
+            KeepRule::UnderFolder(folder) => files.iter()
+                .filter(|file| Path::new(&file.path).starts_with(folder))
+                .min_by_key(by_path),
+        }
+    }
+}
+
+/// What a rule would do to one group.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupPlan {
+    pub hash: String,
+    pub keep: GroupFile,
+    pub remove: Vec<GroupFile>,
+    pub reclaimed_bytes: u64,
+}
+
+/// Nothing is removed while planning, see `api` for carrying a plan out.
+pub fn plan_for_group(group: &GroupSummary, rule: &KeepRule) -> Option<GroupPlan> {
+    let keep = rule.pick(&group.files)?.clone();
+    let remove: Vec<GroupFile> = group.files.iter().filter(|file| file.id != keep.id).cloned().collect();
+    if remove.is_empty() {
+        return None;
+    }
+    let reclaimed_bytes = remove.iter().map(|file| file.size.unwrap_or(group.size)).sum();
+    Some(GroupPlan { hash: group.hash.clone(), keep, remove, reclaimed_bytes })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(id: i64, path: &str, modified: Option<u64>) -> GroupFile {
+        GroupFile { id, path: path.to_string(), size: Some(10), modified }
+    }
+
+    fn group(files: Vec<GroupFile>) -> GroupSummary {
+        GroupSummary { hash: "1".to_string(), copies: files.len() as u64, size: 10, wasted_bytes: 0, newest_modified: None, files }
+    }
+
+    fn kept(rule: KeepRule, files: Vec<GroupFile>) -> Option<i64> {
+        plan_for_group(&group(files), &rule).map(|plan| plan.keep.id)
+    }
+
+    #[test]
+    fn rules_parse() {
+        assert_eq!(KeepRule::parse("oldest", None), Ok(KeepRule::Oldest));
+        assert_eq!(KeepRule::parse("folder", Some(" /keep ")), Ok(KeepRule::UnderFolder("/keep".to_string())));
+        assert!(KeepRule::parse("folder", Some("")).is_err());
+        assert!(KeepRule::parse("largest", None).is_err());
+    }
+
+    #[test]
+    fn each_rule_keeps_the_right_copy() {
+        let files = || vec![
+            file(1, "/backup/2020/photo.jpg", Some(300)),
+            file(2, "/photos/photo.jpg", Some(100)),
+            file(3, "/tmp/p.jpg", Some(200)),
+        ];
+        assert_eq!(kept(KeepRule::Oldest, files()), Some(2));
+        assert_eq!(kept(KeepRule::Newest, files()), Some(1));
+        assert_eq!(kept(KeepRule::ShortestPath, files()), Some(3));
+        assert_eq!(kept(KeepRule::UnderFolder("/backup".to_string()), files()), Some(1));
+        assert_eq!(kept(KeepRule::UnderFolder("/photos/".to_string()), files()), Some(2));
+        // A folder only matches whole path components.
+        assert_eq!(kept(KeepRule::UnderFolder("/back".to_string()), files()), None);
+    }
+
+    #[test]
+    fn ties_and_unknown_times_are_decided_by_path() {
+        let files = vec![file(1, "/b/x", Some(5)), file(2, "/a/x", Some(5)), file(3, "/c", None)];
+        assert_eq!(kept(KeepRule::Oldest, files.clone()), Some(2));
+        assert_eq!(kept(KeepRule::Newest, files.clone()), Some(2));
+        assert_eq!(kept(KeepRule::Oldest, vec![file(1, "/a", None), file(2, "/b", None)]), None);
+    }
+
+    #[test]
+    fn plans_count_what_goes() {
+        let plan = plan_for_group(
+            &group(vec![file(1, "/a", Some(1)), file(2, "/b", Some(2)), GroupFile { size: None, ..file(3, "/c", Some(3)) }]),
+            &KeepRule::Oldest,
+        ).expect("should plan");
+        assert_eq!(plan.keep.id, 1);
+        assert_eq!(plan.remove.iter().map(|file| file.id).collect::<Vec<i64>>(), vec![2, 3]);
+        assert_eq!(plan.reclaimed_bytes, 20);
+    }
+}
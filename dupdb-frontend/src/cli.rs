@@ -0,0 +1,376 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use serde::Deserialize;
+
+use crate::accesslog::LogFormat;
+use crate::thumbs::Thumbnails;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 6969;
+const DEFAULT_POOL_SIZE: usize = 4;
+/// Each worker holds a database connection and a socket, past this it's a typo.
+const MAX_POOL_SIZE: usize = 1024;
+
+/// Serves a page for looking through and cleaning up the duplicates dupdb found.
+///
+/// Everything can also be set through the environment or a TOML config file
+/// using the same names, e.g. `pool_size = 8`. The command line beats the
+/// environment, which beats the config file.
+#[derive(Debug, Parser, Clone)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML file to read anything not given here from
+    #[arg(short = 'c', long = "config", value_name = "FILE", env = "DUPDB_FRONTEND_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The sqlite database dupdb writes to
+    #[arg(long = "db", value_name = "FILE", env = "DUPDB_DATABASE")]
+    pub database: Option<PathBuf>,
+
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long = "host", env = "DUPDB_HOST")]
+    pub host: Option<String>,
+
+    /// Port to listen on [default: 6969]
+    #[arg(short = 'p', long = "port", env = "DUPDB_PORT")]
+    pub port: Option<u16>,
+
+    /// Listen on this Unix socket instead of a host and port
+    #[arg(long = "unix-socket", value_name = "PATH", env = "DUPDB_UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Worker threads answering requests [default: 4]
+    #[arg(short = 's', long = "pool-size", env = "DUPDB_POOL_SIZE", value_parser = parse_pool_size)]
+    pub pool_size: Option<usize>,
+
+    /// Folder files may be served from even when only reached through a symlink, can be repeated
+    #[arg(long = "root", value_name = "DIR")]
+    pub allowed_roots: Vec<PathBuf>,
+
+    /// Token every client must send, as a Bearer token or a Basic auth password
+    #[arg(long = "token", env = "DUPDB_TOKEN", hide_env_values = true)]
+    pub access_token: Option<String>,
+
+    /// Where thumbnails are cached, only ever readable by us [default: $XDG_CACHE_HOME/dupdb-thumbnails or ~/.dupdb/thumbnails]
+    #[arg(long = "thumbnail-dir", value_name = "DIR", env = "DUPDB_THUMBNAIL_DIR")]
+    pub thumbnail_dir: Option<PathBuf>,
+
+    /// Access log format, common or json [default: common]
+    #[arg(long = "log-format", env = "DUPDB_LOG_FORMAT", value_parser = LogFormat::parse)]
+    pub log_format: Option<LogFormat>,
+
+    /// Serve the UI from this folder instead of the copy built in, for working on it
+    #[arg(long = "assets-dir", value_name = "DIR", env = "DUPDB_ASSETS_DIR")]
+    pub assets_dir: Option<PathBuf>,
+
+    /// PEM certificate chain, serves HTTPS along with --tls-key
+    #[arg(long = "tls-cert", value_name = "FILE", env = "DUPDB_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long = "tls-key", value_name = "FILE", env = "DUPDB_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Create --tls-cert and --tls-key with a self-signed certificate if they don't exist yet
+    #[arg(long = "self-signed", env = "DUPDB_SELF_SIGNED")]
+    pub self_signed: bool,
+
+    /// Another host name or address the self-signed certificate is for, can be repeated
+    #[arg(long = "tls-name", value_name = "NAME")]
+    pub tls_names: Vec<String>,
+
+    /// Also listen for plain HTTP on this port, sending everything there to HTTPS
+    #[arg(long = "redirect-http-port", value_name = "PORT", env = "DUPDB_REDIRECT_HTTP_PORT")]
+    pub redirect_http_port: Option<u16>,
+}
+
+/// The config file, same settings as `Cli` under the same names. Relative
+/// paths in it are relative to the file, not wherever we were started from.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    db: Option<PathBuf>,
+    host: Option<String>,
+    port: Option<u16>,
+    unix_socket: Option<PathBuf>,
+    pool_size: Option<usize>,
+    #[serde(default)]
+    roots: Vec<PathBuf>,
+    token: Option<String>,
+    thumbnail_dir: Option<PathBuf>,
+    log_format: Option<String>,
+    assets_dir: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    #[serde(default)]
+    self_signed: bool,
+    #[serde(default)]
+    tls_names: Vec<String>,
+    redirect_http_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bind {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Who a self-signed certificate should be for, if we're to make one
+    /// when `cert` and `key` don't exist yet.
+    pub self_signed_names: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub sqlite_path: PathBuf,
+    pub bind: Bind,
+    pub pool_size: usize,
+    /// Folders files may be served from even when they're only reached
+    /// through a symlink from an indexed path.
+    pub allowed_roots: Vec<PathBuf>,
+    /// Required from every client when set, see `Auth`.
+    pub access_token: Option<String>,
+    pub thumbnail_dir: PathBuf,
+    pub log_format: LogFormat,
+    /// Serves the UI from here instead of the copy built in, for working on it.
+    pub assets_dir: Option<PathBuf>,
+    pub tls: Option<TlsFiles>,
+    /// Plain HTTP port that only redirects to HTTPS, on the same host.
+    pub redirect_http_port: Option<u16>,
+}
+
+impl ServerConfig {
+    /// Reads the command line and environment, and the config file if they
+    /// name one. Exits with a usage error if anything doesn't add up.
+    pub fn parse_env() -> ServerConfig {
+        match ServerConfig::load(Cli::parse()) {
+            Ok(config) => config,
+            Err(message) => Cli::command().error(ErrorKind::ValueValidation, message).exit(),
+        }
+    }
+
+    pub fn load(cli: Cli) -> Result<ServerConfig, String> {
+        let file = match &cli.config {
+            Some(path) => read_config_file(path)?,
+            None => FileConfig::default(),
+        };
+        let log_format = match (cli.log_format, file.log_format) {
+            (Some(format), _) => format,
+            (None, Some(format)) => LogFormat::parse(&format)?,
+            (None, None) => LogFormat::Common,
+        };
+
+        let host = cli.host.or(file.host);
+        let port = cli.port.or(file.port);
+        let bind = match cli.unix_socket.or(file.unix_socket) {
+            Some(_) if host.is_some() || port.is_some() => {
+                return Err("a unix socket can't be combined with a host or port, pick one".to_string());
+            },
+            Some(path) => Bind::Unix(path),
+            None => Bind::Tcp {
+                host: host.unwrap_or_else(|| DEFAULT_HOST.to_string()),
+                port: port.unwrap_or(DEFAULT_PORT),
+            },
+        };
+
+        let pool_size = match (cli.pool_size, file.pool_size) {
+            (Some(size), _) => size,
+            (None, Some(size)) => parse_pool_size(&size.to_string())?,
+            (None, None) => DEFAULT_POOL_SIZE,
+        };
+
+        let self_signed = cli.self_signed || file.self_signed;
+        let tls = match (cli.tls_cert.or(file.tls_cert), cli.tls_key.or(file.tls_key)) {
+            (Some(cert), Some(key)) => {
+                let self_signed_names = self_signed.then(|| {
+                    let tls_names = if cli.tls_names.is_empty() { file.tls_names } else { cli.tls_names };
+                    self_signed_names(&bind, tls_names)
+                });
+                Some(TlsFiles { cert, key, self_signed_names })
+            },
+            (None, None) if self_signed => {
+                return Err("--self-signed needs --tls-cert and --tls-key to know where to write".to_string());
+            },
+            (None, None) => None,
+            (Some(_), None) => return Err("a TLS certificate needs its key too, use --tls-key".to_string()),
+            (None, Some(_)) => return Err("a TLS key needs its certificate too, use --tls-cert".to_string()),
+        };
+        if tls.is_some() && matches!(bind, Bind::Unix(_)) {
+            return Err("TLS is for TCP, leave it to whatever is in front of the unix socket".to_string());
+        }
+
+        let redirect_http_port = cli.redirect_http_port.or(file.redirect_http_port);
+        match (&bind, redirect_http_port) {
+            (_, Some(_)) if tls.is_none() => {
+                return Err("--redirect-http-port only makes sense with --tls-cert and --tls-key".to_string());
+            },
+            (Bind::Tcp { port, .. }, Some(redirect_port)) if *port == redirect_port => {
+                return Err(format!("--redirect-http-port can't be the HTTPS port {port} too"));
+            },
+            _ => {},
+        }
+
+        let sqlite_path = cli.database.or(file.db)
+            .ok_or_else(|| "no database given, use --db or db in the config file".to_string())?;
+
+        Ok(ServerConfig {
+            sqlite_path,
+            bind,
+            pool_size,
+            allowed_roots: if cli.allowed_roots.is_empty() { file.roots } else { cli.allowed_roots },
+            access_token: cli.access_token.or(file.token),
+            thumbnail_dir: cli.thumbnail_dir.or(file.thumbnail_dir).unwrap_or_else(Thumbnails::default_cache_dir),
+            log_format,
+            assets_dir: cli.assets_dir.or(file.assets_dir),
+            tls,
+            redirect_http_port,
+        })
+    }
+}
+
+/// Always good for this machine, plus the address we're bound to unless
+/// that's every address, which no browser would ever ask for.
+fn self_signed_names(bind: &Bind, extra_names: Vec<String>) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    let bound_host = match bind {
+        Bind::Tcp { host, .. } if !host.parse::<IpAddr>().is_ok_and(|address| address.is_unspecified()) => Some(host.clone()),
+        _ => None,
+    };
+    for name in bound_host.into_iter().chain(extra_names) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+fn parse_pool_size(size: &str) -> Result<usize, String> {
+    match size.parse() {
+        Ok(size) if (1..=MAX_POOL_SIZE).contains(&size) => Ok(size),
+        _ => Err(format!("pool size must be a number from 1 to {MAX_POOL_SIZE}, not {size}")),
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<FileConfig, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("could not read config file {}: {error}", path.display()))?;
+    let mut file: FileConfig = toml::from_str(&contents)
+        .map_err(|error| format!("could not parse config file {}: {error}", path.display()))?;
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let relative_to_file = |path: PathBuf| base.join(path);
+    file.db = file.db.map(relative_to_file);
+    file.unix_socket = file.unix_socket.map(relative_to_file);
+    file.roots = file.roots.into_iter().map(relative_to_file).collect();
+    file.thumbnail_dir = file.thumbnail_dir.map(relative_to_file);
+    file.assets_dir = file.assets_dir.map(relative_to_file);
+    file.tls_cert = file.tls_cert.map(relative_to_file);
+    file.tls_key = file.tls_key.map(relative_to_file);
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::FromArgMatches;
+    use crate::test_support::ScratchDir;
+
+    fn scratch_with_config(contents: &str) -> ScratchDir {
+        let scratch = ScratchDir::new("cli");
+        scratch.write("dupdb.toml", contents);
+        scratch
+    }
+
+    /// Parses as `main` would, minus the `DUPDB_*` variables, so whatever
+    /// the person running the tests has exported can't change the answers.
+    fn cli(arguments: &[&str]) -> Cli {
+        let command = Cli::command().mut_args(|argument| argument.env(None));
+        let matches = command.try_get_matches_from(std::iter::once("dupdb-frontend").chain(arguments.iter().copied()));
+        Cli::from_arg_matches(&matches.expect("Could not parse test arguments")).expect("Could not read test arguments")
+    }
+
+    #[test]
+    fn defaults_fill_in_the_rest() {
+        let config = ServerConfig::load(cli(&["--db", "dupdb.sqlite"])).expect("should load");
+        assert_eq!(config.sqlite_path, PathBuf::from("dupdb.sqlite"));
+        assert_eq!(config.bind, Bind::Tcp { host: DEFAULT_HOST.to_string(), port: DEFAULT_PORT });
+        assert_eq!(config.pool_size, DEFAULT_POOL_SIZE);
+        assert_eq!(config.log_format, LogFormat::Common);
+        assert_eq!(config.tls, None);
+    }
+
+    #[test]
+    fn the_command_line_beats_the_config_file() {
+        let scratch = scratch_with_config(
+            "db = \"dupdb.sqlite\"\nport = 8080\npool_size = 2\nroots = [\"photos\", \"/mnt/backup\"]\nlog_format = \"json\"\n"
+        );
+        let config_path = scratch.join("dupdb.toml");
+        let config_path = config_path.to_string_lossy();
+        let config = ServerConfig::load(cli(&["--config", &config_path, "-p", "9000"])).expect("should load");
+        assert_eq!(config.bind, Bind::Tcp { host: DEFAULT_HOST.to_string(), port: 9000 });
+        assert_eq!(config.pool_size, 2);
+        assert_eq!(config.log_format, LogFormat::Json);
+        // Relative to the file, not to wherever the tests run.
+        assert_eq!(config.sqlite_path, scratch.join("dupdb.sqlite"));
+        assert_eq!(config.allowed_roots, vec![scratch.join("photos"), PathBuf::from("/mnt/backup")]);
+
+        let config = ServerConfig::load(cli(&["--config", &config_path, "--root", "elsewhere"])).expect("should load");
+        assert_eq!(config.allowed_roots, vec![PathBuf::from("elsewhere")]);
+    }
+
+    #[test]
+    fn mistakes_are_errors() {
+        assert!(ServerConfig::load(cli(&[])).unwrap_err().contains("--db"));
+        assert!(Cli::try_parse_from(["dupdb-frontend", "--db", "x", "-s", "0"]).is_err());
+        assert!(Cli::try_parse_from(["dupdb-frontend", "--db", "x", "--log-format", "xml"]).is_err());
+        assert!(ServerConfig::load(cli(&["--db", "x", "--tls-cert", "cert.pem"])).unwrap_err().contains("--tls-key"));
+        assert!(ServerConfig::load(cli(&["--db", "x", "--unix-socket", "s", "-p", "1"])).is_err());
+
+        let scratch = scratch_with_config("db = \"x\"\npool_sise = 2\n");
+        let config_path = scratch.join("dupdb.toml");
+        let error = ServerConfig::load(cli(&["--config", &config_path.to_string_lossy()])).unwrap_err();
+        assert!(error.contains("pool_sise"), "{error}");
+
+        let scratch = scratch_with_config("db = \"x\"\npool_size = 0\n");
+        let config_path = scratch.join("dupdb.toml");
+        assert!(ServerConfig::load(cli(&["--config", &config_path.to_string_lossy()])).is_err());
+    }
+
+    #[test]
+    fn tls_settings_hang_together() {
+        let config = ServerConfig::load(cli(&[
+            "--db", "x", "--host", "0.0.0.0", "--tls-cert", "cert.pem", "--tls-key", "key.pem",
+            "--self-signed", "--tls-name", "nas.local", "--tls-name", "localhost", "--redirect-http-port", "8080",
+        ])).expect("should load");
+        let tls = config.tls.expect("should have TLS");
+        assert_eq!(tls.cert, PathBuf::from("cert.pem"));
+        assert_eq!(
+            tls.self_signed_names,
+            Some(vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string(), "nas.local".to_string()])
+        );
+        assert_eq!(config.redirect_http_port, Some(8080));
+
+        assert!(ServerConfig::load(cli(&["--db", "x", "--self-signed"])).is_err());
+        assert!(ServerConfig::load(cli(&["--db", "x", "--redirect-http-port", "8080"])).is_err());
+        assert!(ServerConfig::load(cli(&[
+            "--db", "x", "--tls-cert", "c", "--tls-key", "k", "--redirect-http-port", "6969",
+        ])).is_err());
+        assert!(ServerConfig::load(cli(&["--db", "x", "--tls-cert", "c", "--tls-key", "k", "--unix-socket", "s"])).is_err());
+    }
+
+    #[test]
+    fn unix_sockets_replace_the_port() {
+        let scratch = scratch_with_config("db = \"x\"\nunix_socket = \"dupdb.sock\"\n");
+        let config_path = scratch.join("dupdb.toml");
+        let config = ServerConfig::load(cli(&["--config", &config_path.to_string_lossy()])).expect("should load");
+        assert_eq!(config.bind, Bind::Unix(scratch.join("dupdb.sock")));
+    }
+}
@@ -0,0 +1,383 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use duplicate_file_monitor::{DuplicateDatabase, GroupFile};
+use serde::Serialize;
+
+use crate::files::{FileAccess, FileAccessError};
+use crate::mime;
+
+/// Copies are always compared in full, the diff only ever looks this far in.
+const MAX_DIFF_BYTES: u64 = 256 * 1024;
+/// Past this many lines each side the diff gives up rather than eat memory.
+const MAX_DIFF_INPUT_LINES: usize = 2000;
+const MAX_DIFF_LINES: usize = 200;
+/// How much of a file without a known type we look at to call it text.
+const TEXT_SNIFF_BYTES: u64 = 1024;
+
+/// How the comparison page can show a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preview {
+    Image,
+    Video,
+    Audio,
+    Text,
+    None,
+}
+
+/// One copy as the database remembers it and as it is on disk right now.
+#[derive(Debug, Serialize)]
+pub struct FileMetadata {
+    pub id: i64,
+    pub path: String,
+    pub folder: String,
+    pub name: String,
+    pub indexed_size: Option<u64>,
+    pub indexed_modified: Option<u64>,
+    /// False when the file is gone or isn't somewhere we'll serve from,
+    /// in which case nothing below is filled in.
+    pub available: bool,
+    pub size: Option<u64>,
+    pub modified: Option<u64>,
+    pub owner: Option<String>,
+    /// Like `rw-r--r--`.
+    pub permissions: Option<String>,
+    pub content_type: Option<&'static str>,
+    pub preview: Preview,
+    #[serde(skip)]
+    resolved: Option<PathBuf>,
+}
+
+/// Every copy of a file, and for text whether they really are the same.
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub hash: String,
+    /// The file asked about first, then the rest of its group.
+    pub copies: Vec<FileMetadata>,
+    /// Each other copy against the first, only when the first is text.
+    pub text: Vec<TextComparison>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TextComparison {
+    pub id: i64,
+    pub identical: bool,
+    /// `-` lines are only in the first copy, `+` lines only in this one.
+    pub diff: Vec<String>,
+    /// The diff stopped short of the whole file.
+    pub truncated: bool,
+}
+
+/// None for ids the database doesn't know.
+pub fn metadata(database: &DuplicateDatabase, file_access: &FileAccess, id: i64) -> Result<Option<FileMetadata>, rusqlite::Error> {
+    match database.file_for_id(id)? {
+        Some((_, file)) => describe(database, file_access, file).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// None for ids the database doesn't know. The group comes from
+/// `dups_by_file`, so it's whatever shares a hash with this path.
+pub fn compare(database: &DuplicateDatabase, file_access: &FileAccess, id: i64) -> Result<Option<Comparison>, rusqlite::Error> {
+    let Some((hash, file)) = database.file_for_id(id)? else {
+        return Ok(None);
+    };
+    let mut copies = vec![describe(database, file_access, file.clone())?];
+    for (other_hash, other_path) in database.dups_by_file(&file.path) {
+        if other_hash != hash || other_path == file.path {
+            continue;
+        }
+        let Some(other_id) = database.file_id_for_path(&other_path)? else {
+            continue;
+        };
+        if let Some((_, other)) = database.file_for_id(other_id)? {
+            copies.push(describe(database, file_access, other)?);
+        }
+    }
+
+    let mut text = Vec::new();
+    if let Some(first) = copies[0].resolved.as_deref().filter(|_| copies[0].preview == Preview::Text) {
+        for copy in &copies[1..] {
+            let Some(other) = &copy.resolved else {
+                continue;
+            };
+            match compare_text(first, other) {
+                Ok((identical, diff, truncated)) => text.push(TextComparison { id: copy.id, identical, diff, truncated }),
+                Err(error) => eprintln!("Could not compare {} with {}: {error}", first.display(), other.display()),
+            }
+        }
+    }
+    Ok(Some(Comparison { hash, copies, text }))
+}
+
+fn describe(database: &DuplicateDatabase, file_access: &FileAccess, file: GroupFile) -> Result<FileMetadata, rusqlite::Error> {
+    let path = Path::new(&file.path);
+    let mut metadata = FileMetadata {
+        id: file.id,
+        folder: path.parent().map(|folder| folder.to_string_lossy().to_string()).unwrap_or_default(),
+        name: path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+        path: file.path.clone(),
+        indexed_size: file.size,
+        indexed_modified: file.modified,
+        available: false,
+        size: None,
+        modified: None,
+        owner: None,
+        permissions: None,
+        content_type: None,
+        preview: Preview::None,
+        resolved: None,
+    };
+    let resolved = match file_access.resolve(database, &file.id.to_string()) {
+        Ok(resolved) => resolved,
+        Err(FileAccessError::Database(error)) => return Err(error),
+        // Same as `/file/{id}`, no saying why.
+        Err(_) => return Ok(metadata),
+    };
+    let Ok(on_disk) = fs::metadata(&resolved) else {
+        return Ok(metadata);
+    };
+    metadata.available = true;
+    metadata.size = Some(on_disk.len());
+    metadata.modified = on_disk.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.owner = Some(user_name(on_disk.uid()).unwrap_or_else(|| on_disk.uid().to_string()));
+        metadata.permissions = Some(permissions(on_disk.mode()));
+    }
+    let content_type = File::open(&resolved).and_then(|mut opened| content_type(&resolved, &mut opened)).ok();
+    metadata.preview = content_type.map(preview_for).unwrap_or(Preview::None);
+    metadata.content_type = content_type;
+    metadata.resolved = Some(resolved);
+    Ok(metadata)
+}
+
+/// Like `mime::for_file`, but a file of no type we know that reads as UTF-8
+/// is text, since that's what notes and configs without extensions are, and
+/// a "text" file full of NULs isn't.
+fn content_type(path: &Path, file: &mut File) -> io::Result<&'static str> {
+    let content_type = mime::for_file(path, file)?;
+    let unknown = content_type == mime::FALLBACK;
+    if !unknown && !content_type.starts_with("text/") {
+        return Ok(content_type);
+    }
+    let mut leading_bytes = Vec::new();
+    file.take(TEXT_SNIFF_BYTES).read_to_end(&mut leading_bytes)?;
+    let readable = match std::str::from_utf8(&leading_bytes) {
+        Ok(text) => !text.contains('\0'),
+        // Cut off part way through a character is still text.
+        Err(error) => error.error_len().is_none() && !leading_bytes[..error.valid_up_to()].contains(&0),
+    };
+    Ok(match (unknown, readable) {
+        (true, true) => "text/plain; charset=utf-8",
+        (false, false) => mime::FALLBACK,
+        _ => content_type,
+    })
+}
+
+fn preview_for(content_type: &str) -> Preview {
+    // Only ever served as downloads, but they're text underneath.
+    if mime::is_active(content_type) {
+        return Preview::Text;
+    }
+    match content_type.split('/').next() {
+        Some("image") => Preview::Image,
+        Some("video") => Preview::Video,
+        Some("audio") => Preview::Audio,
+        Some("text") => Preview::Text,
+        _ if content_type == "application/json" => Preview::Text,
+        _ => Preview::None,
+    }
+}
+
+#[cfg(unix)]
+fn permissions(mode: u32) -> String {
+    let mut permissions = String::with_capacity(9);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        permissions.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        permissions.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        permissions.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    permissions
+}
+
+/// Good enough without pulling in libc, users from LDAP and friends just show as their uid.
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let listed_uid: u32 = fields.nth(1)?.parse().ok()?;
+        (listed_uid == uid).then(|| name.to_string())
+    })
+}
+
+/// Whether two files are byte for byte the same and, if not, how their
+/// opening lines differ.
+fn compare_text(first: &Path, other: &Path) -> io::Result<(bool, Vec<String>, bool)> {
+    if same_contents(first, other)? {
+        return Ok((true, Vec::new(), false));
+    }
+    let first_text = read_prefix(first)?;
+    let other_text = read_prefix(other)?;
+    let cut_short = fs::metadata(first)?.len() > MAX_DIFF_BYTES || fs::metadata(other)?.len() > MAX_DIFF_BYTES;
+    let (diff, truncated) = line_diff(&first_text, &other_text);
+    Ok((false, diff, truncated || cut_short))
+}
+
+fn same_contents(first: &Path, other: &Path) -> io::Result<bool> {
+    if fs::metadata(first)?.len() != fs::metadata(other)?.len() {
+        return Ok(false);
+    }
+    let mut first = BufReader::new(File::open(first)?);
+    let mut other = BufReader::new(File::open(other)?);
+    let mut first_buffer = [0; 8192];
+    let mut other_buffer = [0; 8192];
+    loop {
+        let read = first.read(&mut first_buffer)?;
+        if read == 0 {
+            // Same length, so the other one is done too unless it grew under us.
+            return Ok(other.read(&mut other_buffer[..1])? == 0);
+        }
+        other.read_exact(&mut other_buffer[..read])?;
+        if first_buffer[..read] != other_buffer[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+fn read_prefix(path: &Path) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    File::open(path)?.take(MAX_DIFF_BYTES).read_to_end(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Lines only in `first` as `-`, lines only in `other` as `+`, from the
+/// longest common subsequence of the two. True if it had to stop early.
+fn line_diff(first: &str, other: &str) -> (Vec<String>, bool) {
+    let first: Vec<&str> = first.lines().collect();
+    let other: Vec<&str> = other.lines().collect();
+    // What's the same at either end can't be part of the difference.
+    let prefix = first.iter().zip(&other).take_while(|(a, b)| a == b).count();
+    let suffix = first[prefix..].iter().rev().zip(other[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let mut truncated = false;
+    let mut middle = |lines: &[&'_ str]| -> Vec<String> {
+        let middle = &lines[prefix..lines.len() - suffix];
+        truncated |= middle.len() > MAX_DIFF_INPUT_LINES;
+        middle.iter().take(MAX_DIFF_INPUT_LINES).map(|line| line.to_string()).collect()
+    };
+    let (first, other) = (middle(&first), middle(&other));
+
+    // common[i][j] is how many lines first[i..] and other[j..] share in order.
+    let mut common = vec![vec![0u32; other.len() + 1]; first.len() + 1];
+    for i in (0..first.len()).rev() {
+        for j in (0..other.len()).rev() {
+            common[i][j] = if first[i] == other[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < first.len() || j < other.len() {
+        if i < first.len() && j < other.len() && first[i] == other[j] {
+            i += 1;
+            j += 1;
+        } else if j == other.len() || (i < first.len() && common[i + 1][j] >= common[i][j + 1]) {
+            diff.push(format!("-{}", first[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", other[j]));
+            j += 1;
+        }
+    }
+    if diff.len() > MAX_DIFF_LINES {
+        diff.truncate(MAX_DIFF_LINES);
+        truncated = true;
+    }
+    (diff, truncated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+
+    struct Scratch {
+        database: DuplicateDatabase,
+        dir: ScratchDir,
+    }
+
+    impl Scratch {
+        fn new() -> Scratch {
+            let dir = ScratchDir::new("compare");
+            Scratch { database: dir.database(), dir }
+        }
+
+        /// Indexes `contents` under `name` with `hash`, whatever the contents really hash to.
+        fn add(&mut self, name: &str, contents: &str, hash: u64) -> i64 {
+            let path = self.dir.write(name, contents).to_string_lossy().to_string();
+            assert!(self.database.add(hash, path.clone()), "Could not index test file");
+            self.database.file_id_for_path(&path).expect("Could not look up id").expect("Should be indexed")
+        }
+    }
+
+    #[test]
+    fn copies_are_described_from_the_disk() {
+        let mut scratch = Scratch::new();
+        let id = scratch.add("notes.txt", "hello\n", 7);
+        let notes = metadata(&scratch.database, &FileAccess::default(), id).expect("Could not query").expect("Should exist");
+        assert!(notes.available);
+        assert_eq!(notes.name, "notes.txt");
+        assert_eq!(notes.folder, scratch.dir.path().to_string_lossy());
+        assert_eq!(notes.size, Some(6));
+        assert_eq!(notes.preview, Preview::Text);
+        #[cfg(unix)]
+        assert!(notes.owner.is_some() && notes.permissions.as_ref().is_some_and(|permissions| permissions.len() == 9));
+
+        fs::remove_file(scratch.dir.join("notes.txt")).expect("Could not remove test file");
+        let gone = metadata(&scratch.database, &FileAccess::default(), id).expect("Could not query").expect("Still indexed");
+        assert!(!gone.available);
+        assert_eq!(gone.size, None);
+        assert!(metadata(&scratch.database, &FileAccess::default(), 9999).expect("Could not query").is_none());
+    }
+
+    #[test]
+    fn text_copies_are_diffed_against_the_first() {
+        let mut scratch = Scratch::new();
+        let first = scratch.add("a.txt", "one\ntwo\nthree\n", 7);
+        let same = scratch.add("b.txt", "one\ntwo\nthree\n", 7);
+        let changed = scratch.add("c.txt", "one\n2\nthree\nfour\n", 7);
+        scratch.add("unrelated.txt", "one\n", 8);
+
+        let comparison = compare(&scratch.database, &FileAccess::default(), first).expect("Could not query").expect("Should exist");
+        assert_eq!(comparison.copies.len(), 3);
+        assert_eq!(comparison.copies[0].id, first);
+        assert_eq!(comparison.text.len(), 2);
+        let text_for = |id| comparison.text.iter().find(|text| text.id == id).expect("should be compared");
+        assert_eq!(text_for(same), &TextComparison { id: same, identical: true, diff: vec![], truncated: false });
+        assert_eq!(text_for(changed).diff, vec!["-two", "+2", "+four"]);
+    }
+
+    #[test]
+    fn unknown_types_that_read_as_text_are_text() {
+        let mut scratch = Scratch::new();
+        let readme = scratch.add("README", "Plain words\n", 1);
+        let binary = scratch.add("blob", "\0\u{1}\u{2}", 2);
+        let misnamed = scratch.add("photo.txt", "\0\u{1}\u{2}", 3);
+        let preview = |id| metadata(&scratch.database, &FileAccess::default(), id).expect("Could not query").expect("Should exist").preview;
+        assert_eq!(preview(readme), Preview::Text);
+        assert_eq!(preview(binary), Preview::None);
+        assert_eq!(preview(misnamed), Preview::None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use duplicate_file_monitor::DuplicateDatabase;
+use rusqlite::ErrorCode;
+
+/// Long enough to wait out one of the monitor's batch commits, short enough
+/// that a stuck lock gets a 503 before the browser gives up on us.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Comfortably more than the number of different queries we make, so none
+/// of them are ever prepared twice on the same connection.
+const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+/// Read only connections to the dupdb database, kept between requests so
+/// their prepared statements are too.
+///
+/// Each worker only ever needs one at a time, so no more than `max_idle`
+/// are kept. Anything over that, like a live update stream outlasting the
+/// request that started it, gets its own connection that's closed after.
+#[derive(Debug, Clone)]
+pub struct DatabasePool {
+    sqlite_path: PathBuf,
+    busy_timeout: Duration,
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_idle: usize,
+    idle: Mutex<Vec<DuplicateDatabase>>,
+}
+
+/// Opens a connection that may write, for the rare request that changes
+/// the database rather than just reading it. Never pooled, the monitor is
+/// the main writer and shouldn't have to wait on us more than it must.
+#[derive(Debug, Clone)]
+pub struct DatabaseWriter {
+    sqlite_path: PathBuf,
+    busy_timeout: Duration,
+}
+
+/// A connection borrowed from the pool, back in it once dropped.
+#[derive(Debug)]
+pub struct PooledDatabase {
+    database: Option<DuplicateDatabase>,
+    pool: Arc<Inner>,
+}
+
+/// Nothing panics while holding the idle list, so there's nothing to lose
+/// by ignoring poison.
+fn lock(idle: &Mutex<Vec<DuplicateDatabase>>) -> MutexGuard<'_, Vec<DuplicateDatabase>> {
+    idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl DatabasePool {
+    /// Connections are opened when first needed, not up front.
+    pub fn new(sqlite_path: PathBuf, max_idle: usize) -> Self {
+        DatabasePool {
+            sqlite_path,
+            busy_timeout: BUSY_TIMEOUT,
+            inner: Arc::new(Inner { max_idle, idle: Mutex::new(Vec::with_capacity(max_idle)) }),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// An idle connection if there is one, otherwise a new one.
+    pub fn get(&self) -> Result<PooledDatabase, rusqlite::Error> {
+        let idle = lock(&self.inner.idle).pop();
+        let database = match idle {
+            Some(database) => database,
+            None => self.open()?,
+        };
+        Ok(PooledDatabase { database: Some(database), pool: Arc::clone(&self.inner) })
+    }
+
+    fn open(&self) -> Result<DuplicateDatabase, rusqlite::Error> {
+        let database = DuplicateDatabase::open_read_only(&self.sqlite_path)?;
+        database.set_busy_timeout(self.busy_timeout)?;
+        database.set_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(database)
+    }
+
+    pub fn writer(&self) -> DatabaseWriter {
+        DatabaseWriter { sqlite_path: self.sqlite_path.clone(), busy_timeout: self.busy_timeout }
+    }
+
+    #[cfg(test)]
+    fn idle_count(&self) -> usize {
+        lock(&self.inner.idle).len()
+    }
+}
+
+impl DatabaseWriter {
+    pub fn open(&self) -> Result<DuplicateDatabase, rusqlite::Error> {
+        let database = DuplicateDatabase::open(&self.sqlite_path)?;
+        database.set_busy_timeout(self.busy_timeout)?;
+        Ok(database)
+    }
+}
+
+impl Deref for PooledDatabase {
+    type Target = DuplicateDatabase;
+
+    fn deref(&self) -> &DuplicateDatabase {
+        self.database.as_ref().expect("database is only taken when dropped")
+    }
+}
+
+impl Drop for PooledDatabase {
+    fn drop(&mut self) {
+        let Some(database) = self.database.take() else {
+            return;
+        };
+        let mut idle = lock(&self.pool.idle);
+        if idle.len() < self.pool.max_idle {
+            idle.push(database);
+        }
+    }
+}
+
+/// True when the monitor is holding a lock we couldn't wait out, which is
+/// worth trying again shortly rather than treating as broken.
+pub fn is_busy(error: &rusqlite::Error) -> bool {
+    matches!(error.sqlite_error_code(), Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked))
+}
+
+/// 503 for a busy database, 500 for anything else.
+pub fn error_status(error: &rusqlite::Error) -> u16 {
+    if is_busy(error) { 503 } else { 500 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+
+    /// Two copies of one file.
+    fn scratch_database() -> ScratchDir {
+        let scratch = ScratchDir::new("dbpool");
+        let mut database = scratch.database();
+        database.add(1, "/a".to_string());
+        database.add(1, "/b".to_string());
+        scratch
+    }
+
+    #[test]
+    fn connections_are_reused() {
+        let scratch = scratch_database();
+        let pool = DatabasePool::new(scratch.database_path(), 2);
+        assert_eq!(pool.idle_count(), 0);
+
+        let first = pool.get().expect("Could not open connection");
+        assert_eq!(first.duplicate_hashes().expect("select failed"), vec!["1".to_string()]);
+        drop(first);
+        assert_eq!(pool.idle_count(), 1);
+
+        let again = pool.get().expect("Could not reuse connection");
+        assert_eq!(pool.idle_count(), 0);
+        drop(again);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn only_max_idle_connections_are_kept() {
+        let scratch = scratch_database();
+        let pool = DatabasePool::new(scratch.database_path(), 1);
+        let first = pool.get().expect("Could not open connection");
+        let second = pool.get().expect("Could not open connection");
+        drop(first);
+        drop(second);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn a_locked_database_is_busy_not_broken() {
+        let scratch = scratch_database();
+        let pool = DatabasePool::new(scratch.database_path(), 1).with_busy_timeout(Duration::from_millis(10));
+        let writer = rusqlite::Connection::open(scratch.database_path()).expect("Could not open writer");
+        writer.execute_batch("BEGIN EXCLUSIVE").expect("Could not lock");
+
+        let database = pool.get().expect("Opening doesn't need the lock");
+        let error = database.duplicate_hashes().expect_err("should be locked out");
+        assert!(is_busy(&error));
+        assert_eq!(error_status(&error), 503);
+
+        writer.execute_batch("COMMIT").expect("Could not unlock");
+        assert!(database.duplicate_hashes().is_ok());
+    }
+
+    #[test]
+    fn writers_can_change_what_readers_see() {
+        let scratch = scratch_database();
+        let pool = DatabasePool::new(scratch.database_path(), 1);
+        let reader = pool.get().expect("Could not open connection");
+        let mut writer = pool.writer().open().expect("Could not open writer");
+        assert!(writer.rename("/a", "/c"));
+        assert!(reader.file_id_for_path("/a").expect("select failed").is_none());
+        assert!(reader.file_id_for_path("/c").expect("select failed").is_some());
+    }
+
+    #[test]
+    fn missing_databases_are_an_error() {
+        let scratch = ScratchDir::new("dbpool");
+        let pool = DatabasePool::new(scratch.database_path(), 1);
+        assert!(pool.get().is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use duplicate_file_monitor::{DuplicateDatabase, GroupSummary};
+use serde::Serialize;
+
+use crate::dbpool::PooledDatabase;
+use crate::http::Request;
+use crate::listener::Stream;
+use crate::response::Response;
+
+pub const PATH: &str = "/events";
+/// Each client gets a thread of its own for as long as it listens, so
+/// there's a limit on how many can.
+pub const MAX_LIVE_CLIENTS: usize = 8;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Often enough that we notice clients that went away without saying.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const EVENTS_PER_QUERY: usize = 500;
+/// How long browsers wait before reconnecting, in milliseconds.
+const RETRY_MILLISECONDS: u32 = 3000;
+
+/// Counts the clients listening to `/events`.
+#[derive(Debug, Clone)]
+pub struct LiveClients {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Holds one place among the live clients until dropped.
+struct LiveClient {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for LiveClient {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LiveClients {
+    pub fn new(max: usize) -> Self {
+        LiveClients { active: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    fn try_join(&self) -> Option<LiveClient> {
+        self.active.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+            (active < self.max).then_some(active + 1)
+        }).ok()?;
+        Some(LiveClient { active: Arc::clone(&self.active) })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GroupChange {
+    /// A hash that wasn't a duplicate group now is.
+    Created(GroupSummary),
+    /// Copies came or went but it's still a group.
+    Changed(GroupSummary),
+    /// Down to one copy (or none), so not a group any more.
+    Resolved(String),
+}
+
+#[derive(Serialize)]
+struct ResolvedBody<'a> {
+    hash: &'a str,
+}
+
+impl GroupChange {
+    fn event_name(&self) -> &'static str {
+        match self {
+            GroupChange::Created(_) => "group-created",
+            GroupChange::Changed(_) => "group-changed",
+            GroupChange::Resolved(_) => "group-resolved",
+        }
+    }
+
+    fn data(&self) -> Result<String, serde_json::Error> {
+        match self {
+            GroupChange::Created(group) | GroupChange::Changed(group) => serde_json::to_string(group),
+            GroupChange::Resolved(hash) => serde_json::to_string(&ResolvedBody { hash }),
+        }
+    }
+}
+
+/// Turns the monitor's file history into changes to duplicate groups.
+///
+/// Every file event names the hash it touched, so only those groups need
+/// looking at. Which hashes were groups before is remembered to tell a new
+/// group from a changed one.
+pub struct ChangeTracker {
+    known_groups: HashSet<String>,
+    last_event_id: i64,
+}
+
+impl ChangeTracker {
+    /// Starts after `resume_after` when a reconnecting browser sends the
+    /// last event id it saw, otherwise from now.
+    pub fn new(database: &DuplicateDatabase, resume_after: Option<i64>) -> Result<Self, rusqlite::Error> {
+        let latest = database.latest_event_id()?;
+        Ok(ChangeTracker {
+            known_groups: database.duplicate_hashes()?.into_iter().collect(),
+            last_event_id: resume_after.filter(|id| *id <= latest).unwrap_or(latest),
+        })
+    }
+
+    pub fn last_event_id(&self) -> i64 {
+        self.last_event_id
+    }
+
+    pub fn poll(&mut self, database: &DuplicateDatabase) -> Result<Vec<GroupChange>, rusqlite::Error> {
+        let mut touched: Vec<String> = Vec::new();
+        loop {
+            let events = database.events_since(self.last_event_id, EVENTS_PER_QUERY)?;
+            let Some(last) = events.last() else {
+                break;
+            };
+            self.last_event_id = last.id;
+            let fetched = events.len();
+            for event in events {
+                for hash in [event.hash, event.previous_hash].into_iter().flatten() {
+                    if !touched.contains(&hash) {
+                        touched.push(hash);
+                    }
+                }
+            }
+            if fetched < EVENTS_PER_QUERY {
+                break;
+            }
+        }
+
+        let mut changes = Vec::new();
+        for hash in touched {
+            match database.group_summary(&hash)? {
+                Some(group) if self.known_groups.insert(hash.clone()) => changes.push(GroupChange::Created(group)),
+                Some(group) => changes.push(GroupChange::Changed(group)),
+                None if self.known_groups.remove(&hash) => changes.push(GroupChange::Resolved(hash)),
+                None => {},
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Takes over the connection and streams group changes to it from a thread
+/// of its own, leaving the worker that called this free for other requests.
+/// Returns the status the client was answered with.
+pub fn start(stream: Stream, database: PooledDatabase, request: &Request, clients: &LiveClients) -> u16 {
+    let mut stream = stream;
+    let Some(client) = clients.try_join() else {
+        let busy = Response::text(503, "Too many live clients, try again later").with_header("Retry-After", "10");
+        let _ = busy.write_to(&mut stream, false, false);
+        return 503;
+    };
+    let resume_after = request.header("Last-Event-ID").and_then(|id| id.trim().parse().ok());
+    let tracker = match ChangeTracker::new(&database, resume_after) {
+        Ok(tracker) => tracker,
+        Err(error) => {
+            let _ = Response::text(500, &format!("Could not read events: {error}")).write_to(&mut stream, false, false);
+            return 500;
+        }
+    };
+
+    let spawned = thread::Builder::new().name("dupdb-events".to_string()).spawn(move || {
+        let _client = client;
+        if let Err(error) = stream_changes(stream, database, tracker) {
+            // Almost always the browser closing the tab.
+            eprintln!("Live updates stopped: {error}");
+        }
+    });
+    match spawned {
+        Ok(_) => 200,
+        Err(error) => {
+            // The client gets nothing, so sees a dropped connection.
+            eprintln!("Could not start live updates thread: {error}");
+            500
+        },
+    }
+}
+
+fn stream_changes(mut stream: Stream, database: PooledDatabase, mut tracker: ChangeTracker) -> Result<(), Box<dyn std::error::Error>> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\nretry: {RETRY_MILLISECONDS}\n\n"
+    )?;
+    stream.flush()?;
+
+    // Catch up on anything since the Last-Event-ID straight away.
+    let mut data_version = None;
+    let mut last_write = Instant::now();
+    loop {
+        let current_version = database.data_version()?;
+        if data_version != Some(current_version) {
+            data_version = Some(current_version);
+            let changes = tracker.poll(&database)?;
+            if !changes.is_empty() {
+                let mut message = String::new();
+                for change in &changes {
+                    message.push_str(&format!(
+                        "id: {}\nevent: {}\ndata: {}\n\n",
+                        tracker.last_event_id(), change.event_name(), change.data()?
+                    ));
+                }
+                stream.write_all(message.as_bytes())?;
+                stream.flush()?;
+                last_write = Instant::now();
+            }
+        }
+        if last_write.elapsed() >= HEARTBEAT_INTERVAL {
+            stream.write_all(b": still here\n\n")?;
+            stream.flush()?;
+            last_write = Instant::now();
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+
+    fn names(changes: &[GroupChange]) -> Vec<&'static str> {
+        changes.iter().map(GroupChange::event_name).collect()
+    }
+
+    #[test]
+    fn groups_are_created_changed_and_resolved() {
+        let scratch = ScratchDir::new("events");
+        let mut database = scratch.database();
+        database.add(1, "/a".to_string());
+        let mut tracker = ChangeTracker::new(&database, None).expect("tracker failed");
+        assert!(tracker.poll(&database).expect("poll failed").is_empty());
+
+        database.add(1, "/b".to_string());
+        let changes = tracker.poll(&database).expect("poll failed");
+        assert_eq!(names(&changes), vec!["group-created"]);
+
+        database.add(1, "/c".to_string());
+        database.add(2, "/lonely".to_string());
+        let changes = tracker.poll(&database).expect("poll failed");
+        assert_eq!(names(&changes), vec!["group-changed"]);
+        let GroupChange::Changed(group) = &changes[0] else {
+            panic!("expected a changed group");
+        };
+        assert_eq!(group.copies, 3);
+
+        database.remove("/b".to_string());
+        database.remove("/c".to_string());
+        let changes = tracker.poll(&database).expect("poll failed");
+        assert_eq!(changes, vec![GroupChange::Resolved("1".to_string())]);
+        assert!(tracker.poll(&database).expect("poll failed").is_empty());
+    }
+
+    #[test]
+    fn modified_files_leave_their_old_group() {
+        let scratch = ScratchDir::new("events");
+        let mut database = scratch.database();
+        database.add(1, "/a".to_string());
+        database.add(1, "/b".to_string());
+        let mut tracker = ChangeTracker::new(&database, None).expect("tracker failed");
+
+        database.add(2, "/b".to_string());
+        let changes = tracker.poll(&database).expect("poll failed");
+        assert_eq!(changes, vec![GroupChange::Resolved("1".to_string())]);
+    }
+
+    #[test]
+    fn reconnecting_clients_catch_up_from_their_last_event() {
+        let scratch = ScratchDir::new("events");
+        let mut database = scratch.database();
+        database.add(1, "/a".to_string());
+        let seen = database.latest_event_id().expect("select failed");
+        database.add(1, "/b".to_string());
+
+        let mut tracker = ChangeTracker::new(&database, Some(seen)).expect("tracker failed");
+        // It's already a group by the time we look, all we can say is it changed.
+        assert_eq!(names(&tracker.poll(&database).expect("poll failed")), vec!["group-changed"]);
+
+        let mut tracker = ChangeTracker::new(&database, Some(i64::MAX)).expect("tracker failed");
+        assert!(tracker.poll(&database).expect("poll failed").is_empty());
+    }
+
+    #[test]
+    fn live_clients_are_capped() {
+        let clients = LiveClients::new(2);
+        let first = clients.try_join().expect("room for one");
+        let _second = clients.try_join().expect("room for two");
+        assert!(clients.try_join().is_none());
+        drop(first);
+        assert!(clients.try_join().is_some());
+    }
+}
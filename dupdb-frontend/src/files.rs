@@ -0,0 +1,311 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use duplicate_file_monitor::DuplicateDatabase;
+
+/// Decides which files the frontend is willing to hand out.
+///
+/// Files are only ever asked for by their id in the database, never by
+/// path, and whatever the id points at must still resolve to either an
+/// indexed path or somewhere under one of the allowed roots once every
+/// symlink along the way has been followed.
+#[derive(Debug, Clone, Default)]
+pub struct FileAccess {
+    allowed_roots: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum FileAccessError {
+    /// Not a number, or no row with that id.
+    UnknownId,
+    /// The stored path is relative or climbs out with `..`.
+    SuspiciousPath(String),
+    /// Resolving symlinks took us somewhere we don't serve.
+    OutsideAllowedFiles(PathBuf),
+    NotAFile(PathBuf),
+    Io(io::Error),
+    Database(rusqlite::Error),
+}
+
+impl fmt::Display for FileAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileAccessError::UnknownId => write!(f, "No file with that id"),
+            FileAccessError::SuspiciousPath(path) => write!(f, "Refusing suspicious path {path}"),
+            FileAccessError::OutsideAllowedFiles(path) => write!(f, "Refusing to serve {:?}", path),
+            FileAccessError::NotAFile(path) => write!(f, "{:?} is not a file", path),
+            FileAccessError::Io(error) => write!(f, "{error}"),
+            FileAccessError::Database(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl FileAccess {
+    /// Roots that don't exist are dropped with a warning, they could never
+    /// match anything anyway.
+    pub fn new<P: AsRef<Path>>(allowed_roots: &[P]) -> Self {
+        let allowed_roots = allowed_roots.iter().filter_map(|root| {
+            match fs::canonicalize(root) {
+                Ok(root) => Some(root),
+                Err(error) => {
+                    eprintln!("Ignoring allowed root {}: {error}", root.as_ref().display());
+                    None
+                }
+            }
+        }).collect();
+        FileAccess { allowed_roots }
+    }
+
+    /// Turns the `{id}` of a `/file/{id}` request into a path that is safe to open.
+    pub fn resolve(&self, database: &DuplicateDatabase, raw_id: &str) -> Result<PathBuf, FileAccessError> {
+        let id = parse_file_id(raw_id).ok_or(FileAccessError::UnknownId)?;
+        let stored_path = database.path_for_file_id(id)
+            .map_err(FileAccessError::Database)?
+            .ok_or(FileAccessError::UnknownId)?;
+
+        let path = Path::new(&stored_path);
+        let climbs_out = path.components().any(|component| component == Component::ParentDir);
+        if !path.is_absolute() || climbs_out {
+            return Err(FileAccessError::SuspiciousPath(stored_path));
+        }
+
+        let resolved = fs::canonicalize(path).map_err(FileAccessError::Io)?;
+        if !self.is_allowed(database, &resolved)? {
+            return Err(FileAccessError::OutsideAllowedFiles(resolved));
+        }
+        if !resolved.is_file() {
+            return Err(FileAccessError::NotAFile(resolved));
+        }
+        Ok(resolved)
+    }
+
+    /// Checks a folder files may be moved into and creates it if needed.
+    /// Unlike serving, being next to an indexed file isn't enough, it has
+    /// to be under one of the allowed roots, so without any nothing moves.
+    pub fn prepare_directory(&self, dir: &str) -> Result<PathBuf, String> {
+        if self.allowed_roots.is_empty() {
+            return Err("Moving files needs the server started with --root, to say where they may go".to_string());
+        }
+        let path = Path::new(dir);
+        let climbs_out = path.components().any(|component| component == Component::ParentDir);
+        if !path.is_absolute() || climbs_out {
+            return Err(format!("{dir} has to be an absolute path without .."));
+        }
+        // Nothing gets created outside the roots, so check what's already
+        // there before making the rest of the folders.
+        let existing = path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new("/"));
+        let outside = |resolved: &Path| !self.allowed_roots.iter().any(|root| resolved.starts_with(root));
+        if fs::canonicalize(existing).map(|resolved| outside(&resolved)).unwrap_or(true) {
+            return Err(format!("{dir} is not under any allowed root"));
+        }
+        fs::create_dir_all(path).map_err(|error| format!("Could not create {dir}: {error}"))?;
+        let resolved = fs::canonicalize(path).map_err(|error| format!("Could not resolve {dir}: {error}"))?;
+        // A symlink turning up part way down could still lead elsewhere.
+        if outside(&resolved) {
+            return Err(format!("{dir} is not under any allowed root"));
+        }
+        if !resolved.is_dir() {
+            return Err(format!("{dir} is not a folder"));
+        }
+        Ok(resolved)
+    }
+
+    fn is_allowed(&self, database: &DuplicateDatabase, resolved: &Path) -> Result<bool, FileAccessError> {
+        if self.allowed_roots.iter().any(|root| resolved.starts_with(root)) {
+            return Ok(true);
+        }
+        let Some(resolved) = resolved.to_str() else {
+            return Ok(false);
+        };
+        let indexed = database.file_id_for_path(resolved)
+            .map_err(FileAccessError::Database)?;
+        Ok(indexed.is_some())
+    }
+}
+
+/// Only files the database knows are duplicates may go, and only while at
+/// least one other copy is still on disk to take their place. A symlink to
+/// the file, or the same file reached through a symlinked folder, is no copy.
+pub fn check_removable(database: &DuplicateDatabase, path_to_remove: &str) -> Result<(), String> {
+    if database.file_id_for_path(path_to_remove).ok().flatten().is_none() {
+        return Err(format!("{path_to_remove} is not an indexed file"));
+    }
+    if !Path::new(path_to_remove).is_file() {
+        return Err(format!("No file exists at path {path_to_remove}"));
+    }
+    let has_surviving_copy = database.dups_by_file(path_to_remove).iter().any(|(_, other_path)| {
+        other_path != path_to_remove && Path::new(other_path).is_file() && !is_same_file(other_path, path_to_remove)
+    });
+    if !has_surviving_copy {
+        return Err(format!("{path_to_remove} has no other copy left on disk, refusing to remove it"));
+    }
+    Ok(())
+}
+
+/// Whether both paths lead to the one file once symlinks are followed.
+/// Paths that lead nowhere aren't the same as anything.
+pub fn is_same_file(first: impl AsRef<Path>, second: impl AsRef<Path>) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => false,
+    }
+}
+
+/// Ids are plain positive integers, anything else (including `../`) is not an id.
+pub fn parse_file_id(raw_id: &str) -> Option<i64> {
+    if raw_id.is_empty() || !raw_id.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    raw_id.parse().ok().filter(|id| *id > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+
+    /// A scratch folder with an indexed file, an unindexed secret next to it
+    /// and a database to put rows in.
+    struct Scratch {
+        database: DuplicateDatabase,
+        dir: ScratchDir,
+    }
+
+    impl Scratch {
+        fn new() -> Scratch {
+            let dir = ScratchDir::new("files");
+            dir.write("photos/cat.jpg", "meow");
+            dir.write("secret.txt", "hunter2");
+            Scratch { database: dir.database(), dir }
+        }
+
+        fn index(&mut self, path: &str) -> String {
+            self.database.add(1, path.to_string());
+            let id = self.database.file_id_for_path(path).expect("lookup failed").expect("row missing");
+            id.to_string()
+        }
+
+        fn path(&self, relative: &str) -> String {
+            self.dir.path_of(relative)
+        }
+    }
+
+    #[test]
+    fn indexed_files_are_served() {
+        let mut scratch = Scratch::new();
+        let cat = scratch.path("photos/cat.jpg");
+        let id = scratch.index(&cat);
+        let resolved = FileAccess::default().resolve(&scratch.database, &id).expect("should be allowed");
+        assert_eq!(resolved, PathBuf::from(cat));
+    }
+
+    #[test]
+    fn ids_that_are_not_numbers_are_rejected() {
+        let scratch = Scratch::new();
+        for raw_id in ["", "../../etc/passwd", "%2e%2e", "-1", "0", "1.5", "99999"] {
+            let result = FileAccess::default().resolve(&scratch.database, raw_id);
+            assert!(matches!(result, Err(FileAccessError::UnknownId)), "{raw_id} was not rejected");
+        }
+    }
+
+    #[test]
+    fn stored_paths_that_climb_out_are_rejected() {
+        let mut scratch = Scratch::new();
+        let sneaky = scratch.path("photos/../secret.txt");
+        let id = scratch.index(&sneaky);
+        let result = FileAccess::default().resolve(&scratch.database, &id);
+        assert!(matches!(result, Err(FileAccessError::SuspiciousPath(_))));
+
+        let id = scratch.index("secret.txt");
+        let result = FileAccess::default().resolve(&scratch.database, &id);
+        assert!(matches!(result, Err(FileAccessError::SuspiciousPath(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_index_are_rejected() {
+        let mut scratch = Scratch::new();
+        let link = scratch.path("photos/innocent.jpg");
+        std::os::unix::fs::symlink(scratch.path("secret.txt"), &link).expect("Could not make symlink");
+        let id = scratch.index(&link);
+
+        let result = FileAccess::default().resolve(&scratch.database, &id);
+        assert!(matches!(result, Err(FileAccessError::OutsideAllowedFiles(_))));
+
+        // Unless the target is somewhere we've been told is fine.
+        let access = FileAccess::new(&[scratch.dir.path().to_string_lossy().to_string()]);
+        assert!(access.resolve(&scratch.database, &id).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_folders_out_of_the_roots_are_rejected() {
+        let mut scratch = Scratch::new();
+        let elsewhere = scratch.path("elsewhere");
+        fs::create_dir_all(&elsewhere).expect("Could not create dir");
+        fs::write(scratch.dir.join("elsewhere").join("loot.txt"), "gold").expect("Could not write file");
+        std::os::unix::fs::symlink(&elsewhere, scratch.dir.join("photos").join("album")).expect("Could not make symlink");
+        let id = scratch.index(&scratch.path("photos/album/loot.txt"));
+
+        let access = FileAccess::new(&[scratch.path("photos")]);
+        let result = access.resolve(&scratch.database, &id);
+        assert!(matches!(result, Err(FileAccessError::OutsideAllowedFiles(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_copies_are_not_copies() {
+        let mut scratch = Scratch::new();
+        let cat = scratch.path("photos/cat.jpg");
+        scratch.index(&cat);
+        let link = scratch.path("photos/cat link.jpg");
+        std::os::unix::fs::symlink(&cat, &link).expect("Could not make symlink");
+        scratch.index(&link);
+        assert!(check_removable(&scratch.database, &cat).is_err());
+        assert!(Path::new(&cat).is_file());
+
+        let copy = scratch.dir.write("photos/cat copy.jpg", "meow");
+        scratch.index(&copy.to_string_lossy());
+        assert!(check_removable(&scratch.database, &cat).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_seen_through_symlinked_folders_are_not_copies() {
+        let mut scratch = Scratch::new();
+        std::os::unix::fs::symlink(scratch.path("photos"), scratch.dir.join("album")).expect("Could not make symlink");
+        let (cat, same_cat) = (scratch.path("photos/cat.jpg"), scratch.path("album/cat.jpg"));
+        scratch.index(&cat);
+        scratch.index(&same_cat);
+        assert!(check_removable(&scratch.database, &cat).is_err());
+        assert!(check_removable(&scratch.database, &same_cat).is_err());
+    }
+
+    #[test]
+    fn move_targets_have_to_be_under_a_root() {
+        let scratch = Scratch::new();
+        assert!(FileAccess::default().prepare_directory(&scratch.path("photos/new")).is_err());
+
+        let access = FileAccess::new(&[scratch.path("photos")]);
+        let created = access.prepare_directory(&scratch.path("photos/2024/best")).expect("should be allowed");
+        assert_eq!(created, scratch.dir.join("photos/2024/best"));
+        assert!(created.is_dir());
+
+        assert!(access.prepare_directory(&scratch.path("elsewhere")).is_err());
+        assert!(!scratch.dir.join("elsewhere").exists(), "nothing should be created outside the roots");
+        assert!(access.prepare_directory(&scratch.path("photos/../elsewhere")).is_err());
+        assert!(access.prepare_directory("photos").is_err());
+        assert!(access.prepare_directory(&scratch.path("photos/cat.jpg")).is_err());
+    }
+
+    #[test]
+    fn directories_are_not_files() {
+        let mut scratch = Scratch::new();
+        let photos = scratch.path("photos");
+        let id = scratch.index(&photos);
+        let result = FileAccess::default().resolve(&scratch.database, &id);
+        assert!(matches!(result, Err(FileAccessError::NotAFile(_))));
+    }
+}
@@ -0,0 +1,414 @@
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+use form_urlencoded::parse;
+
+/// How much of a request we're willing to hold in memory before giving up on it.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_headers: usize,
+    /// Request line plus every header line, line endings included.
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+    /// Requests to this path take whole files, so get `max_upload_bytes` instead.
+    pub upload_path: Option<&'static str>,
+    pub max_upload_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_headers: 64,
+            max_header_bytes: 16 * 1024,
+            max_body_bytes: 64 * 1024,
+            upload_path: None,
+            max_upload_bytes: 0,
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request. Header names keep the case they were sent in,
+/// look them up with `header` which ignores it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// The request target as sent, query string and all.
+    pub target: String,
+    /// The target up to the `?`.
+    pub path: String,
+    /// Decoded query string parameters, in the order they were sent.
+    pub query: Vec<(String, String)>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// HTTP/1.1 keeps connections open unless told otherwise, 1.0 only when asked.
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let mentions = |option: &str| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case(option));
+        if self.version == "HTTP/1.0" {
+            mentions("keep-alive")
+        } else {
+            !mentions("close")
+        }
+    }
+
+    /// The body decoded as `application/x-www-form-urlencoded`.
+    pub fn form_fields(&self) -> Vec<(String, String)> {
+        parse(&self.body).into_owned().collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The client hung up before sending anything, nobody to answer.
+    ConnectionClosed,
+    Malformed(String),
+    HeadersTooLarge,
+    BodyTooLarge,
+    /// Something we understand but don't do, like chunked bodies.
+    Unsupported(String),
+    TimedOut,
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status line to answer with, if there's anyone to answer.
+    pub fn status(&self) -> Option<(u16, &'static str)> {
+        match self {
+            ParseError::ConnectionClosed => None,
+            ParseError::Malformed(_) => Some((400, "Bad Request")),
+            ParseError::HeadersTooLarge => Some((431, "Request Header Fields Too Large")),
+            ParseError::BodyTooLarge => Some((413, "Content Too Large")),
+            ParseError::Unsupported(_) => Some((501, "Not Implemented")),
+            ParseError::TimedOut => Some((408, "Request Timeout")),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => write!(f, "Connection closed"),
+            ParseError::Malformed(reason) => write!(f, "Malformed request: {reason}"),
+            ParseError::HeadersTooLarge => write!(f, "Too many or too large headers"),
+            ParseError::BodyTooLarge => write!(f, "Request body too large"),
+            ParseError::Unsupported(reason) => write!(f, "Unsupported request: {reason}"),
+            ParseError::TimedOut => write!(f, "Timed out waiting for the request"),
+            ParseError::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::TimedOut,
+            _ => ParseError::Io(error),
+        }
+    }
+}
+
+fn malformed(reason: &str) -> ParseError {
+    ParseError::Malformed(reason.to_string())
+}
+
+/// Reads one request off the reader. Set read timeouts on the socket first,
+/// a client that stops sending otherwise holds a worker forever.
+pub fn read_request<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+    let mut header_budget = limits.max_header_bytes;
+
+    let request_line = match read_line(reader, &mut header_budget)? {
+        None => return Err(ParseError::ConnectionClosed),
+        Some(line) => line,
+    };
+    let (method, target, version) = parse_request_line(&request_line)?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut header_budget)?
+            .ok_or_else(|| malformed("connection closed in the middle of the headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        headers.push(parse_header_line(&line)?);
+    }
+
+    let (path, query_string) = target.split_once('?').unwrap_or((&target, ""));
+    let mut request = Request {
+        path: path.to_string(),
+        query: parse(query_string.as_bytes()).into_owned().collect(),
+        method,
+        target: target.clone(),
+        version,
+        headers,
+        body: Vec::new(),
+    };
+
+    if let Some(transfer_encoding) = request.header("Transfer-Encoding") {
+        return Err(ParseError::Unsupported(format!("Transfer-Encoding {transfer_encoding}")));
+    }
+    let content_length = content_length(&request.headers)?;
+    let max_body_bytes = match limits.upload_path {
+        Some(upload_path) if request.path == upload_path => limits.max_upload_bytes.max(limits.max_body_bytes),
+        _ => limits.max_body_bytes,
+    };
+    if content_length > max_body_bytes {
+        return Err(ParseError::BodyTooLarge);
+    }
+    if content_length > 0 {
+        request.body = vec![0; content_length];
+        reader.read_exact(&mut request.body).map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => malformed("body is shorter than its Content-Length"),
+            _ => ParseError::from(error),
+        })?;
+    }
+    Ok(request)
+}
+
+/// One CRLF (or bare LF) terminated line without its ending, or None if the
+/// stream ended before anything was read. Every byte counts against the budget.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    // One more than the budget so we can tell "exactly fits" from "too long".
+    let read = reader.by_ref().take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > *budget {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    *budget -= read;
+    if line.last() != Some(&b'\n') {
+        return Err(malformed("connection closed in the middle of a line"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| malformed("header lines must be utf-8"))
+}
+
+fn parse_request_line(line: &str) -> Result<(String, String, String), ParseError> {
+    let mut parts = line.split(' '); // https://datatracker.ietf.org/doc/html/rfc9112#section-3
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(malformed("request line must be METHOD TARGET VERSION"));
+    };
+    if method.is_empty() || !method.bytes().all(is_token_byte) {
+        return Err(malformed("invalid method"));
+    }
+    if !target.starts_with('/') || target.bytes().any(|byte| byte.is_ascii_control()) {
+        return Err(malformed("request target must be an absolute path"));
+    }
+    match version {
+        "HTTP/1.0" | "HTTP/1.1" => {},
+        _ if version.starts_with("HTTP/") => return Err(ParseError::Unsupported(format!("version {version}"))),
+        _ => return Err(malformed("invalid HTTP version")),
+    }
+    Ok((method.to_string(), target.to_string(), version.to_string()))
+}
+
+fn parse_header_line(line: &str) -> Result<(String, String), ParseError> {
+    let (name, value) = line.split_once(':').ok_or_else(|| malformed("header line without a colon"))?;
+    // No whitespace before the colon and no obsolete line folding, both are
+    // how request smuggling starts.
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(malformed("invalid header name"));
+    }
+    let value = value.trim_matches([' ', '\t']);
+    if value.bytes().any(|byte| byte.is_ascii_control() && byte != b'\t') {
+        return Err(malformed("control characters in header value"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Zero when absent. Repeats are fine as long as they agree.
+fn content_length(headers: &[(String, String)]) -> Result<usize, ParseError> {
+    let mut found: Option<usize> = None;
+    for (_, value) in headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length")) {
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(malformed("Content-Length must be a number"));
+        }
+        // Too big for usize is certainly too big for us.
+        let length = value.parse().map_err(|_| ParseError::BodyTooLarge)?;
+        if found.is_some_and(|previous| previous != length) {
+            return Err(malformed("conflicting Content-Length headers"));
+        }
+        found = Some(length);
+    }
+    Ok(found.unwrap_or(0))
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc9110#section-5.6.2
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(raw: &str) -> Result<Request, ParseError> {
+        read_request(&mut Cursor::new(raw.as_bytes().to_vec()), &Limits::default())
+    }
+
+    fn status_of(raw: &str) -> Option<u16> {
+        match read(raw) {
+            Ok(_) => Some(200),
+            Err(error) => error.status().map(|(status, _)| status),
+        }
+    }
+
+    #[test]
+    fn reads_a_simple_get() {
+        let request = read("GET /duplicates?sort=count&prefix=%2Fhome%20dir HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .expect("should parse");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/duplicates");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.query, vec![
+            ("sort".to_string(), "count".to_string()),
+            ("prefix".to_string(), "/home dir".to_string()),
+        ]);
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn headers_are_case_insensitive_and_bodies_are_read() {
+        let request = read("POST /remove HTTP/1.1\r\ncontent-LENGTH: 13\r\n\r\npath=%2Fa&b=c").expect("should parse");
+        assert_eq!(request.header("Content-Length"), Some("13"));
+        assert_eq!(request.form_fields(), vec![
+            ("path".to_string(), "/a".to_string()),
+            ("b".to_string(), "c".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_header() {
+        let keep_alive = |raw: &str| read(raw).expect("should parse").wants_keep_alive();
+        assert!(keep_alive("GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn bare_newlines_are_tolerated() {
+        let request = read("GET / HTTP/1.0\nAccept: */*\n\n").expect("should parse");
+        assert_eq!(request.header("accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn only_what_content_length_says_is_read() {
+        let mut cursor = Cursor::new(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nabGET".to_vec());
+        let request = read_request(&mut cursor, &Limits::default()).expect("should parse");
+        assert_eq!(request.body, b"ab");
+        let mut rest = String::new();
+        cursor.read_to_string(&mut rest).expect("read failed");
+        assert_eq!(rest, "GET");
+    }
+
+    #[test]
+    fn empty_connections_have_nobody_to_answer() {
+        assert!(matches!(read(""), Err(ParseError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn malformed_requests_are_bad_requests() {
+        let malformed = [
+            "\r\n\r\n",
+            "GET\r\n\r\n",
+            "GET /\r\n\r\n",
+            "GET / HTTP/1.1 extra\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "G(T / HTTP/1.1\r\n\r\n",
+            "GET relative HTTP/1.1\r\n\r\n",
+            "GET / FTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nNo colon here\r\n\r\n",
+            "GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+            "GET / HTTP/1.1\r\nSpace-Before-Colon : x\r\n\r\n",
+            "GET / HTTP/1.1\r\n: empty name\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n",
+            "GET / HTTP/1.1\r\nX: a\x00b\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: localhost\r\n",
+            "GET / HTTP/1.1\r\nHost: local",
+            "POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: \r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+        ];
+        for raw in malformed {
+            assert_eq!(status_of(raw), Some(400), "{raw:?} should be a 400");
+        }
+    }
+
+    #[test]
+    fn non_utf8_headers_are_bad_requests() {
+        let raw = b"GET / HTTP/1.1\r\nX: \xff\xfe\r\n\r\n".to_vec();
+        let result = read_request(&mut Cursor::new(raw), &Limits::default());
+        assert!(matches!(result, Err(ParseError::Malformed(_))));
+    }
+
+    #[test]
+    fn agreeing_content_lengths_are_fine() {
+        let request = read("POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nab").expect("should parse");
+        assert_eq!(request.body, b"ab");
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let limits = Limits { max_headers: 2, max_header_bytes: 64, max_body_bytes: 4, upload_path: Some("/up"), max_upload_bytes: 8 };
+        let parse = |raw: &str| read_request(&mut Cursor::new(raw.as_bytes().to_vec()), &limits);
+
+        assert!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+        assert!(matches!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"), Err(ParseError::HeadersTooLarge)));
+        let long_line = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(64));
+        assert!(matches!(parse(&long_line), Err(ParseError::HeadersTooLarge)));
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(64));
+        assert!(matches!(parse(&long_target), Err(ParseError::HeadersTooLarge)));
+        assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"), Err(ParseError::BodyTooLarge)));
+        assert!(parse("POST /up HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").is_ok());
+        assert!(matches!(parse("POST /up HTTP/1.1\r\nContent-Length: 9\r\n\r\nhello you"), Err(ParseError::BodyTooLarge)));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n"),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn statuses_for_each_failure() {
+        assert_eq!(status_of("POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n"), Some(413));
+        assert_eq!(status_of(&format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(20_000))), Some(431));
+        assert_eq!(status_of("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"), Some(501));
+        assert_eq!(status_of("GET / HTTP/2.0\r\n\r\n"), Some(501));
+    }
+
+    #[test]
+    fn timeouts_become_408() {
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "stalled"))
+            }
+        }
+        let mut reader = io::BufReader::new(Cursor::new(b"GET / HTTP/1.1\r\n".to_vec()).chain(Stalled));
+        let result = read_request(&mut reader, &Limits::default());
+        assert!(matches!(result, Err(ParseError::TimedOut)));
+        assert_eq!(result.unwrap_err().status(), Some((408, "Request Timeout")));
+    }
+}
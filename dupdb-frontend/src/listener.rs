@@ -0,0 +1,294 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// Where connections come from: a TCP port, or a Unix socket for sitting
+/// behind a reverse proxy on the same machine.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix { listener: UnixListener, path: PathBuf },
+}
+
+/// One client connection, whichever kind of listener it came from.
+pub enum Stream {
+    Tcp(TcpStream),
+    /// Clones share the one TLS session, which is why it's behind a lock.
+    Tls(Arc<Mutex<TlsStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+pub struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+/// Somewhere to connect to so a thread blocked in `Listener::accept` returns.
+#[derive(Debug, Clone)]
+pub enum Waker {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Only ever connecting to ourselves, so this is plenty.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Drop for TlsStream {
+    /// Lets the client know the response really did end here, rather than
+    /// the connection being cut short.
+    fn drop(&mut self) {
+        if self.0.conn.is_handshaking() {
+            return;
+        }
+        self.0.conn.send_close_notify();
+        while self.0.conn.wants_write() {
+            if self.0.conn.write_tls(&mut self.0.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Listener {
+    pub fn bind_tcp(host: &str, port: u16) -> io::Result<Listener> {
+        TcpListener::bind((host, port)).map(Listener::Tcp)
+    }
+
+    /// The socket is only usable by our own user, since the access token is
+    /// optional and nothing else would stop others removing files.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path) -> io::Result<Listener> {
+        // A server that didn't shut down cleanly leaves its socket behind,
+        // which is only safe to clear away if nothing is answering on it.
+        let is_socket = fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false);
+        if is_socket && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(Listener::Unix { listener, path: path.to_path_buf() })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind_unix(_path: &Path) -> io::Result<Listener> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets need a Unix"))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    pub fn waker(&self) -> io::Result<Waker> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut address = listener.local_addr()?;
+                // Listening everywhere includes loopback, which is always there to connect to.
+                if address.ip().is_unspecified() {
+                    address.set_ip(match address.ip() {
+                        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    });
+                }
+                Ok(Waker::Tcp(address))
+            },
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(Waker::Unix(path.clone())),
+        }
+    }
+
+    /// For telling people where to point their browser.
+    pub fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => address.to_string(),
+                Err(_) => "an unknown address".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Waker {
+    /// The connection is dropped straight away, the accepting side only
+    /// needs to notice something happened.
+    pub fn wake(&self) -> io::Result<()> {
+        match self {
+            Waker::Tcp(address) => TcpStream::connect_timeout(address, WAKE_TIMEOUT).map(drop),
+            #[cfg(unix)]
+            Waker::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+impl Stream {
+    /// The handshake happens on first read or write, so not on the accepting thread.
+    pub fn into_tls(self, config: &Arc<ServerConfig>) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => {
+                let connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+                Ok(Stream::Tls(Arc::new(Mutex::new(TlsStream(StreamOwned::new(connection, stream))))))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "TLS is only for TCP connections")),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Tls(stream) => Ok(Stream::Tls(Arc::clone(stream))),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => lock(stream)?.0.sock.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(stream) => lock(stream)?.0.sock.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// None for Unix sockets, whose peers have no address worth logging.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            Stream::Tls(stream) => lock(stream).ok()?.0.sock.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+}
+
+/// A panic mid-write leaves the session unusable, so that's an error rather than a retry.
+fn lock(stream: &Mutex<TlsStream>) -> io::Result<MutexGuard<'_, TlsStream>> {
+    stream.lock().map_err(|_| io::Error::other("TLS session was poisoned"))
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buffer),
+            Stream::Tls(stream) => lock(stream)?.0.read(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buffer),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buffer),
+            Stream::Tls(stream) => lock(stream)?.0.write(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => lock(stream)?.0.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        (&*self).write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use crate::test_support::ScratchDir;
+
+    #[test]
+    fn unix_sockets_are_private_and_cleaned_up() {
+        let scratch = ScratchDir::new("listener");
+        let path = scratch.join("dupdb.sock");
+        let listener = Listener::bind_unix(&path).expect("Could not bind");
+        assert_eq!(fs::metadata(&path).expect("socket should exist").permissions().mode() & 0o777, 0o600);
+        assert_eq!(listener.describe(), format!("unix:{}", path.display()));
+
+        let mut client = UnixStream::connect(&path).expect("Could not connect");
+        let server = listener.accept().expect("Could not accept");
+        assert_eq!(server.peer_addr(), None);
+        client.write_all(b"ping\n").expect("Could not write");
+        let mut line = String::new();
+        BufReader::new(&server).read_line(&mut line).expect("Could not read");
+        assert_eq!(line, "ping\n");
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn wakers_unblock_accept() {
+        let scratch = ScratchDir::new("listener");
+        for listener in [Listener::bind_tcp("0.0.0.0", 0), Listener::bind_unix(&scratch.join("dupdb.sock"))] {
+            let listener = listener.expect("Could not bind");
+            let waker = listener.waker().expect("Could not make waker");
+            let woken = std::thread::spawn(move || listener.accept().is_ok());
+            waker.wake().expect("Could not wake");
+            assert!(woken.join().expect("accept thread panicked"));
+        }
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let scratch = ScratchDir::new("listener");
+        let path = scratch.join("dupdb.sock");
+        let stale = UnixListener::bind(&path).expect("Could not bind");
+        drop(stale);
+        assert!(path.exists(), "std leaves the socket file behind");
+
+        let listener = Listener::bind_unix(&path).expect("Stale socket should be cleared");
+        // But one that's still answering is left alone.
+        assert!(Listener::bind_unix(&path).is_err());
+        drop(listener);
+    }
+}
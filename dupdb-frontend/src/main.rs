@@ -1,90 +1,280 @@
-mod fixedthreadpool;
-use fixedthreadpool::FixedThreadPool;
-use std::env;
-use std::net::{TcpListener, TcpStream};
-use std::io::{BufReader, prelude::*};
-use std::sync::{Arc, Mutex};
-use std::fs;
-use form_urlencoded::parse;
-use urlencoding::decode;
+mod accesslog;
+mod api;
+mod assets;
+mod auth;
+mod bulk;
+mod cli;
+mod compare;
+mod dbpool;
+mod events;
+mod files;
+mod http;
+mod listener;
+mod metrics;
+mod mime;
+mod moves;
+mod response;
+mod shutdown;
+#[cfg(test)]
+mod test_support;
+mod thumbs;
+mod tls;
+use accesslog::{AccessLogEntry, LogFormat};
+use assets::Assets;
+use auth::{Auth, CSRF_FIELD, CSRF_HEADER};
+use cli::{Bind, ServerConfig};
+use dbpool::{DatabasePool, DatabaseWriter, PooledDatabase};
+use files::{FileAccess, FileAccessError};
+use http::{Limits, Request};
+use events::LiveClients;
+use listener::{Listener, Stream};
+use metrics::Metrics;
+use response::Response;
+use shutdown::Shutdown;
+use thumbs::{Thumbnail, Thumbnails};
+use std::net::SocketAddr;
+use std::io::{self, BufReader, prelude::*};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::Arc;
+use std::process::ExitCode;
+use std::thread;
 
-use rusqlite::{Connection, OpenFlags};
+use duplicate_file_monitor::DuplicateDatabase;
+use fixed_thread_pool::{FixedThreadPool, PoolObserver};
 
 
-fn main() {
-    let (sqlite_path, host, port, pool_size) = parse_args();
-    println!("starting dupe db with parameters {:?} {:?} {:?} {:?}", sqlite_path, host, port, pool_size);
+fn main() -> ExitCode {
+    let ServerConfig {
+        sqlite_path, bind, pool_size, allowed_roots, access_token, thumbnail_dir, log_format, assets_dir, tls, redirect_http_port
+    } = ServerConfig::parse_env();
+    println!("starting dupe db with parameters {:?} {:?} {:?} {:?}", sqlite_path, bind, pool_size, allowed_roots);
+    let tls_config = match tls.as_ref().map(tls::prepare).transpose() {
+        Ok(tls_config) => tls_config,
+        Err(error) => {
+            eprintln!("Cannot serve HTTPS: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Verify connection first so that we don't have to worry about
+    // unbinding the port in a moment.
+    // Every worker holds at most one connection, so that's all we keep.
+    let database_pool = DatabasePool::new(sqlite_path.clone(), pool_size);
+    if let Err(error) = database_pool.get() {
+        eprintln!("Cannot open database {}: {error}", sqlite_path.display());
+        return ExitCode::FAILURE;
+    }
 
-    // Verify connection first (this can panic) so that we don't 
-    // have to worry about unbinding the TCP port in a moment
-    let db_connection = open_db_connection(&sqlite_path);
-    drop(db_connection);
+    let thumbnails = Thumbnails::new(thumbnail_dir.clone());
+    if let Err(error) = thumbnails.prepare() {
+        eprintln!("Cannot cache thumbnails in {}: {error}", thumbnail_dir.display());
+        return ExitCode::FAILURE;
+    }
 
-    // this can panic
-    let mut fixed_thread_pool = FixedThreadPool::new(pool_size);
+    let fixed_thread_pool = FixedThreadPool::new(pool_size, pool_size * QUEUED_CONNECTIONS_PER_WORKER);
 
-    // HTTP setup
-    let listener = match TcpListener::bind(format!("{host}:{port}")) {
+    let listener = match &bind {
+        Bind::Tcp { host, port } => Listener::bind_tcp(host, *port),
+        Bind::Unix(path) => Listener::bind_unix(path),
+    };
+    let listener = match listener {
         Ok(listener) => listener,
-        Err(error) => panic!("Could not bind TcpListener {:?}", error),
+        Err(error) => {
+            eprintln!("Could not listen on {bind:?}: {error}");
+            return ExitCode::FAILURE;
+        }
     };
+    if tls_config.is_some() {
+        println!("Serving HTTPS on {}", listener.describe());
+    }
+    if access_token.is_none() {
+        println!("No --token given, anyone who can reach {} can remove files", listener.describe());
+    }
 
+    let state = Arc::new(AppState {
+        file_access: FileAccess::new(&allowed_roots),
+        auth: Auth::new(access_token),
+        thumbnails,
+        live_clients: LiveClients::new(events::MAX_LIVE_CLIENTS),
+        metrics: Metrics::default(),
+        pool: fixed_thread_pool.observer(),
+        log_format,
+        assets: Assets::new(assets_dir),
+        shutdown: Shutdown::new(),
+        database_writer: database_pool.writer(),
+    });
+
+    if let (Some(redirect_port), Bind::Tcp { host, port }) = (redirect_http_port, &bind) {
+        match Listener::bind_tcp(host, redirect_port) {
+            Ok(redirect_listener) => spawn_https_redirects(redirect_listener, *port, Arc::clone(&state)),
+            Err(error) => {
+                eprintln!("Could not listen for plain HTTP on {host}:{redirect_port}: {error}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Err(error) = state.shutdown.request_on_signals() {
+        eprintln!("Could not listen for signals, only POST /shutdown will stop us: {error}");
+    }
+    serve(listener, tls_config, database_pool, fixed_thread_pool, state);
+    ExitCode::SUCCESS
+}
+
+/// Accepts connections and hands them to the workers until a shutdown is
+/// requested, then waits a while for requests already in flight.
+fn serve(
+    listener: Listener,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    database_pool: DatabasePool,
+    fixed_thread_pool: FixedThreadPool,
+    state: Arc<AppState>,
+) {
+    // The accept below blocks, so whoever is told to stop also has to
+    // knock on our own door for the loop to notice.
+    match listener.waker() {
+        Ok(waker) => {
+            let shutdown = state.shutdown.clone();
+            thread::spawn(move || {
+                shutdown.wait();
+                if let Err(error) = waker.wake() {
+                    eprintln!("Could not wake the accept loop, it will stop on the next connection: {error}");
+                }
+            });
+        },
+        Err(error) => eprintln!("Could not set up waking the accept loop, it will stop on the next connection: {error}"),
+    }
 
     // Execution pool and the "job" that runs per request.
-    let shutdown_flag = Arc::new(Mutex::new(false));
-    for event in listener.incoming() {
-        if fixed_thread_pool.needs_reset() {
-            fixed_thread_pool = FixedThreadPool::new(pool_size);
-        }
-        match event {
-            Ok(tcp_stream) => {
-                let flag = Arc::clone(&shutdown_flag);
-                let sqlite_path = sqlite_path.clone();
-
-                fixed_thread_pool.execute(move || {
-                    let db_connection = open_db_connection(&sqlite_path);
-                    if handle_connection(tcp_stream, db_connection) == ProgramSignal::StopProgram {
-                        let mut flag = match flag.lock() {
-                            Ok(guard) => guard,
-                            Err(poisoned) => {
-                                flag.clear_poison();
-                                poisoned.into_inner()
-                            },
-                        };
-                        *flag = true;
+    loop {
+        let accepted = listener.accept().and_then(|stream| match &tls_config {
+            Some(tls_config) => stream.into_tls(tls_config),
+            None => Ok(stream),
+        });
+        // Whatever woke us, the wake-up call included, doesn't get answered.
+        if state.shutdown.is_requested() {
+            break;
+        }
+        match accepted {
+            Ok(stream) => {
+                let database_pool = database_pool.clone();
+                let job_state = Arc::clone(&state);
+                // The job owns the stream, so keep a way to say no if it's refused.
+                let refusal_stream = stream.try_clone();
+
+                let queued = fixed_thread_pool.execute(move || {
+                    let database = match database_pool.get() {
+                        Ok(database) => database,
+                        Err(error) => {
+                            eprintln!("No database connection for a request: {error}");
+                            refuse_busy(&stream, "Database unavailable, try again shortly");
+                            return;
+                        }
+                    };
+                    if handle_connection(stream, database, &job_state) == ProgramSignal::StopProgram {
+                        job_state.shutdown.request();
                     }
                 });
+                if let Err(error) = queued {
+                    eprintln!("Turning a connection away, {error}");
+                    state.metrics.record_rejected_connection();
+                    if let Ok(refusal_stream) = refusal_stream {
+                        refuse_busy(&refusal_stream, "Too busy, try again shortly");
+                    }
+                }
             },
-            Err(error) => eprint!("Could not handle event: {:?}", error),
+            Err(error) => eprintln!("Could not handle event: {:?}", error),
         };
-        let shutdown_flag = match shutdown_flag.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => {
-                shutdown_flag.clear_poison();
-                poisoned.into_inner()
+    }
+
+    // Nothing new gets in while what's already here finishes up.
+    drop(listener);
+    println!("Finishing requests in flight, for up to {}s", SHUTDOWN_TIMEOUT.as_secs());
+    if !fixed_thread_pool.shutdown_with_timeout(SHUTDOWN_TIMEOUT) {
+        eprintln!("Some requests were still running at shutdown");
+    }
+}
+
+/// Often said from the accepting thread, so it gets little time to say it.
+/// That includes any TLS handshake, hence the read timeout.
+fn refuse_busy(stream: &Stream, message: &str) {
+    let _ = stream.set_read_timeout(Some(BUSY_WRITE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(BUSY_WRITE_TIMEOUT));
+    let busy = Response::text(503, message).with_header("Retry-After", RETRY_AFTER_SECONDS);
+    let mut writer = stream;
+    if let Err(error) = busy.write_to(&mut writer, false, false) {
+        eprintln!("Could not tell a client we're busy: {error}");
+    }
+}
+
+
+/// Plain HTTP only ever gets told where the HTTPS version is. It has its own
+/// couple of workers so nobody on the HTTP port can hold up real requests.
+fn spawn_https_redirects(listener: Listener, https_port: u16, state: Arc<AppState>) {
+    let redirect_pool = FixedThreadPool::new(REDIRECT_WORKERS, REDIRECT_WORKERS * QUEUED_CONNECTIONS_PER_WORKER);
+    println!("Redirecting plain HTTP on {} to HTTPS", listener.describe());
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let job_state = Arc::clone(&state);
+                if let Err(error) = redirect_pool.execute(move || redirect_to_https(stream, https_port, &job_state)) {
+                    eprintln!("Turning a plain HTTP connection away, {error}");
+                }
             },
-        };
-        if *shutdown_flag {
-            break;
+            Err(error) => eprintln!("Could not handle plain HTTP event: {:?}", error),
         }
-    }
+    });
 }
 
-/// Panics if db cant be opened.
-fn open_db_connection(sqlite_path: &str) -> Connection {
-    match Connection::open_with_flags(
-        sqlite_path, 
-        OpenFlags::SQLITE_OPEN_READ_ONLY |
-        OpenFlags::SQLITE_OPEN_NO_MUTEX  |
-        OpenFlags::SQLITE_OPEN_URI
-    ) {
-        Err(error) => {
-            panic!("Cannot open database connection {error}");
-        },
-        Ok(conn) => conn
+/// One request, one redirect, then hang up.
+fn redirect_to_https(stream: Stream, https_port: u16, state: &AppState) {
+    let timeouts = stream.set_read_timeout(Some(SOCKET_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(SOCKET_TIMEOUT)));
+    if timeouts.is_err() {
+        return;
     }
+    let client = stream.peer_addr();
+    let started = Instant::now();
+    let request = match http::read_request(&mut BufReader::new(&stream), &Limits::default()) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let response = match tls::https_location(request.header("Host"), https_port, &request.target) {
+        Some(location) => Response::permanent_redirect(&location),
+        None => Response::text(400, "No Host header to redirect to, use https:// instead"),
+    };
+    let head_only = request.method == "HEAD";
+    let status = response.status;
+    let bytes = if head_only { 0 } else { response.content_length() };
+    let mut writer = &stream;
+    let _ = response.write_to(&mut writer, head_only, false);
+    state.record_request(client, Some(&request), status, bytes, started);
+}
+
+/// What every request handler gets to share.
+struct AppState {
+    file_access: FileAccess,
+    auth: Auth,
+    thumbnails: Thumbnails,
+    live_clients: LiveClients,
+    metrics: Metrics,
+    /// Only for reporting on in `/metrics`.
+    pool: PoolObserver,
+    log_format: LogFormat,
+    assets: Assets,
+    shutdown: Shutdown,
+    /// For the few requests that change the database, like moving a file.
+    database_writer: DatabaseWriter,
 }
 
+impl AppState {
+    /// Writes the access log line and counts the request for `/metrics`.
+    fn record_request(&self, client: Option<SocketAddr>, request: Option<&Request>, status: u16, bytes: u64, started: Instant) {
+        let latency = started.elapsed();
+        let method = request.map(|request| request.method.as_str()).unwrap_or("-");
+        self.metrics.record_request(method, status, bytes, latency);
+        let entry = AccessLogEntry { client, request, status, bytes, latency };
+        println!("{}", self.log_format.line(&entry, SystemTime::now()));
+    }
+}
 
 #[derive(Debug, PartialEq)]
 enum ProgramSignal {
@@ -92,256 +282,803 @@ enum ProgramSignal {
     ContinueOnMyWayWardSon,
 }
 
-fn get_http_from(tcp_stream: &TcpStream) -> (Vec<String>, Option<Vec<u8>>) {
-    let mut buf_reader = BufReader::new(tcp_stream);
-    let mut headers: Vec<String> = vec![];
-    loop {
-        let mut utf8_line = String::new();
-        match buf_reader.read_line(&mut utf8_line) {
-            Ok(0) => {
-                return (headers, None);
-            },
-            Ok(_) => {
-                if utf8_line.is_empty() || utf8_line == "\r\n" {
-                    break;
-                }
-                headers.push(utf8_line);
-            },
+/// How long a client may leave us waiting, in either direction, before we hang up.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+/// Idle keep-alive connections each hold a worker, so they don't get to idle long.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often idle keep-alive connections look up to see if we're shutting down.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+/// Connections past this many per worker get a 503 rather than a long wait.
+const QUEUED_CONNECTIONS_PER_WORKER: usize = 16;
+const BUSY_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// What every 503 tells clients to wait before trying again.
+const RETRY_AFTER_SECONDS: &str = "1";
+/// Redirects are quick, a couple of threads keep up with any browser.
+const REDIRECT_WORKERS: usize = 2;
+/// Files sent to be searched for are held in memory while they're hashed,
+/// anything bigger has to be searched for by hash instead.
+const MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+/// How long requests still in flight get to finish once we're told to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers requests on one connection until the client or we decide to close it.
+fn handle_connection(stream: Stream, database: PooledDatabase, state: &AppState) -> ProgramSignal {
+    if let Err(error) = stream.set_write_timeout(Some(SOCKET_TIMEOUT)) {
+        eprintln!("Could not set socket timeouts, dropping connection: {error}");
+        return ProgramSignal::ContinueOnMyWayWardSon;
+    }
+    let client = stream.peer_addr();
+    let limits = Limits { upload_path: Some(api::SEARCH_PATH), max_upload_bytes: MAX_UPLOAD_BYTES, ..Limits::default() };
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    for requests_served in 0..MAX_REQUESTS_PER_CONNECTION {
+        if !wait_for_request(&stream, &mut reader, &state.shutdown) {
+            break;
+        }
+        let started = Instant::now();
+        let request = match http::read_request(&mut reader, &limits) {
+            Ok(request) => request,
             Err(error) => {
-                // I suppose we have received non utf-8 in the header lines.
-                // Which is QUITE odd... let us abort with whatever we have.
-                eprintln!("Error while reading HTTP request: {error}");
-                return (headers, None);
+                if let Some((status, _)) = error.status() {
+                    let response = Response::text(status, &format!("{error}"));
+                    let bytes = response.content_length();
+                    let _ = response.write_to(&mut writer, false, false);
+                    state.record_request(client, None, status, bytes, started);
+                }
+                break;
             }
-        }
-    }
+        };
 
-    let content_length_line = match headers.iter().find(|line| line.starts_with("Content-Length")) {
-        None => return (headers, None),
-        Some(line) => {
-            let raw_content_length = line.split(" ").skip(1).take(1).collect::<String>();
-            match raw_content_length.trim().parse() {
-                Err(bad_number_error) => {
-                    eprintln!("Can't parse content length header. {bad_number_error}");
-                    return (headers, None);
+        let wants_events = request.path == events::PATH && request.method == "GET";
+        if wants_events && state.auth.is_authorized(request.header("Authorization")) {
+            match stream.try_clone() {
+                Ok(event_stream) => {
+                    // Logged as it starts, the stream itself can go on for hours.
+                    let status = events::start(event_stream, database, &request, &state.live_clients);
+                    state.record_request(client, Some(&request), status, 0, started);
                 },
-                Ok(good) => good
+                Err(error) => eprintln!("Could not hand connection over for live updates: {error}"),
             }
+            return ProgramSignal::ContinueOnMyWayWardSon;
         }
+
+        let (mut response, signal) = respond(&request, &database, state);
+        if response.status == 503 && !response.headers.iter().any(|(name, _)| name == "Retry-After") {
+            response = response.with_header("Retry-After", RETRY_AFTER_SECONDS);
+        }
+        let keep_alive = request.wants_keep_alive()
+            && signal == ProgramSignal::ContinueOnMyWayWardSon
+            && !state.shutdown.is_requested()
+            && requests_served + 1 < MAX_REQUESTS_PER_CONNECTION;
+        let head_only = request.method == "HEAD";
+        let status = response.status;
+        let bytes = if head_only || status == 304 { 0 } else { response.content_length() };
+        let written = response.write_to(&mut writer, head_only, keep_alive);
+        state.record_request(client, Some(&request), status, bytes, started);
+        if let Err(error) = written {
+            eprintln!("Failed to write response to output {:?}", error);
+            return signal;
+        }
+        if !keep_alive {
+            return signal;
+        }
+    }
+    ProgramSignal::ContinueOnMyWayWardSon
+}
+
+/// A small JPEG of the file behind `raw_id`, or an icon when it isn't an image.
+/// Same access rules as `/file/{id}`.
+fn thumbnail_response(request: &Request, raw_id: &str, database: &DuplicateDatabase, state: &AppState) -> Response {
+    let requested_size = request.query.iter().find(|(name, _)| name == "size").map(|(_, value)| value.as_str());
+    let size = match thumbs::size_from(requested_size) {
+        Ok(size) => size,
+        Err(message) => return Response::text(400, &message),
+    };
+    let resolved = match state.file_access.resolve(database, raw_id) {
+        Ok(resolved) => resolved,
+        Err(FileAccessError::Database(error)) if dbpool::is_busy(&error) => {
+            return Response::text(503, "Database busy, try again shortly");
+        }
+        Err(error) => {
+            eprintln!("Refused thumbnail request {raw_id}: {error}");
+            return Response::text(404, "Not found");
+        }
+    };
+    let hash = files::parse_file_id(raw_id)
+        .and_then(|id| database.file_for_id(id).ok().flatten())
+        .map(|(hash, _)| hash);
+    let Some(hash) = hash else {
+        return Response::text(404, "Not found");
     };
+    match state.thumbnails.thumbnail_for(&resolved, &hash, size) {
+        Thumbnail::Cached(thumbnail) => match response::file_response(request, &thumbnail) {
+            Ok(response) => response,
+            Err(error) => Response::text(500, &format!("{error}")),
+        },
+        Thumbnail::Placeholder(svg) => Response::new(200)
+            .with_header("Content-Type", "image/svg+xml")
+            .with_header("X-Content-Type-Options", "nosniff")
+            .with_header("Content-Security-Policy", "sandbox")
+            .with_header("Cache-Control", "private, no-cache")
+            .with_body(svg.into_bytes()),
+    }
+}
 
-    let body_bytes: Vec<u8> = buf_reader.bytes().take(content_length_line).map(|u8| u8.unwrap()).collect();
-    (headers, Some(body_bytes))
+/// API clients get their errors as JSON like every other API response.
+fn refusal(path: &str, status: u16, message: &str) -> Response {
+    if path.starts_with(api::PREFIX) {
+        api::error(status, message)
+    } else {
+        Response::text(status, message)
+    }
 }
 
-fn handle_connection(tcp_stream: TcpStream, readonly_connection: Connection) -> ProgramSignal {
-    let (http_request_headers, maybe_http_body) = get_http_from(&tcp_stream);
+/// Waits up to the keep-alive timeout for the next request to start, then
+/// gives the client the full timeout to finish sending it. False if the
+/// client went away, stayed quiet, or we're shutting down in the meantime.
+fn wait_for_request(stream: &Stream, reader: &mut BufReader<&Stream>, shutdown: &Shutdown) -> bool {
+    if stream.set_read_timeout(Some(IDLE_CHECK_INTERVAL)).is_err() {
+        return false;
+    }
+    let started = Instant::now();
+    loop {
+        match reader.fill_buf() {
+            Ok(buffered) if !buffered.is_empty() => return stream.set_read_timeout(Some(SOCKET_TIMEOUT)).is_ok(),
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if shutdown.is_requested() || started.elapsed() >= KEEP_ALIVE_TIMEOUT {
+                    return false;
+                }
+            },
+            _ => return false,
+        }
+    }
+}
+
+fn respond(request: &Request, database: &DuplicateDatabase, state: &AppState) -> (Response, ProgramSignal) {
+    // HEAD is a GET without the body, which write_to takes care of.
+    let method = if request.method == "HEAD" { "GET" } else { request.method.as_str() };
+    let path = request.path.as_str();
+
+    if !state.auth.is_authorized(request.header("Authorization")) {
+        let response = refusal(path, 401, "Unauthorized").with_header("WWW-Authenticate", "Basic realm=\"dupdb\"");
+        return (response, ProgramSignal::ContinueOnMyWayWardSon);
+    }
 
-    let first_line = http_request_headers.iter().next().map_or("Nonsense!", |s| s);
-    match parse_http_request_line(first_line) {
+    // Anything that changes state has to prove it came from our own page.
+    // Searching changes nothing, so scripts can upload to it with just the
+    // access token. Uploads aren't forms either, no sense picking through them.
+    let uploading = path == api::SEARCH_PATH;
+    let form_fields = if uploading { Vec::new() } else { request.form_fields() };
+    if method != "GET" && !uploading {
+        let offered_csrf = request.header(CSRF_HEADER).or_else(|| {
+            form_fields.iter().find(|(name, _)| name == CSRF_FIELD).map(|(_, value)| value.as_str())
+        });
+        if !state.auth.is_valid_csrf(offered_csrf) {
+            let response = refusal(path, 403, "Missing or stale form token, reload the page and try again");
+            return (response, ProgramSignal::ContinueOnMyWayWardSon);
+        }
+    }
+
+    let response = match (method, path) {
+        (_, api_path) if api_path.starts_with(api::PREFIX) => api::respond(method, request, database, state),
+        ("GET", "/duplicates") if !request.query.is_empty() => {
+            let group_query = match api::group_query_from(&request.query) {
+                Ok(group_query) => group_query,
+                Err(message) => return (Response::text(400, &message), ProgramSignal::ContinueOnMyWayWardSon),
+            };
+            let page = match database.query_groups(&group_query) {
+                Ok(page) => page,
+                Err(error) => {
+                    let response = Response::text(dbpool::error_status(&error), &format!("Could not query duplicates: {error}"));
+                    return (response, ProgramSignal::ContinueOnMyWayWardSon);
+                }
+            };
+            let mut response_body = String::new();
+            for group in page.groups {
+                for file in group.files {
+                    response_body.push_str(&format!("{}\n{}\n{}\n\n", group.hash, file.path, file.id));
+                }
+            }
+            let next_cursor = page.next_cursor.map(|cursor| cursor.to_string()).unwrap_or_default();
+            Response::text(200, &response_body).with_header("X-Next-Cursor", &next_cursor)
+        }
         ("GET", "/duplicates") => {
-            let duplicate_tuples = get_dups(&readonly_connection);
+            let duplicate_tuples = match database.duplicates() {
+                Ok(tuples) => tuples,
+                Err(error) if dbpool::is_busy(&error) => {
+                    return (Response::text(503, "Database busy, try again shortly"), ProgramSignal::ContinueOnMyWayWardSon);
+                }
+                Err(error) => {
+                    eprintln!("Unable to select duplicates: {error}");
+                    Vec::new()
+                }
+            };
             let mut response_body = String::new();
             for (hash,file_path) in duplicate_tuples {
                 response_body.push_str(&format!("{hash}\n{file_path}\n\n"));
             }
-            send_200(&response_body, tcp_stream);
+            Response::text(200, &response_body)
         }
+        // Same checks as removing through the API, the path is only there
+        // to make sure the id still means what the page showed.
         ("POST", "/remove") => {
-            if maybe_http_body.is_none() {
-                send_400("Invalid request, send an http body fool!", tcp_stream);
-                return ProgramSignal::ContinueOnMyWayWardSon;
-            }
-            let http_body = maybe_http_body.unwrap();
-            let path_tuple_to_remove = parse(&http_body).into_owned().find(|(name, _)| {
-                name == "path"
-            });
-            if path_tuple_to_remove.is_none() {
-                send_400("Invalid request, no path found in form body", tcp_stream);
-                return ProgramSignal::ContinueOnMyWayWardSon;
+            let field = |name: &str| form_fields.iter().find(|(field_name, _)| field_name == name).map(|(_, value)| value.as_str());
+            match (field("id").and_then(files::parse_file_id), field("path")) {
+                (Some(id), Some(expected_path)) => match api::remove_file_by_id(id, expected_path, database, state) {
+                    Err((status, reason)) => Response::text(status, &reason),
+                    Ok(_) => Response::see_other("/"),
+                },
+                _ => Response::text(400, "Invalid request, the form needs an id and a path"),
             }
-
-            let (_, path_to_remove) = path_tuple_to_remove.unwrap();
-            if !fs::exists(&path_to_remove).unwrap_or(false) {
-                send_400(&format!("No file exists at path {path_to_remove}"), tcp_stream);
-                return ProgramSignal::ContinueOnMyWayWardSon;
+        }
+        ("POST", "/move") => {
+            let field = |name: &str| form_fields.iter().find(|(field_name, _)| field_name == name).map(|(_, value)| value.as_str());
+            let id = field("id").and_then(files::parse_file_id);
+            match (id, field("target_dir")) {
+                (Some(id), Some(target_dir)) => match moves::move_file(id, target_dir, database, state) {
+                    Err((status, reason)) => Response::text(status, &reason),
+                    Ok(_) if field("remove_others").is_none() => Response::see_other("/"),
+                    Ok(moved) => {
+                        let failures: Vec<String> = api::remove_other_copies(id, database, state).into_iter()
+                            .filter_map(|result| result.error.map(|error| format!("{}: {error}", result.path.unwrap_or_default())))
+                            .collect();
+                        if failures.is_empty() {
+                            Response::see_other("/")
+                        } else {
+                            Response::text(409, &format!("Moved {} to {} but could not remove every other copy:\n{}", moved.from, moved.to, failures.join("\n")))
+                        }
+                    },
+                },
+                _ => Response::text(400, "Invalid request, the form needs an id and a target_dir"),
             }
-
-            let _ = fs::remove_file(&path_to_remove);
-            send_303_home(tcp_stream);
         }
-        ("GET", "/shutdown") => {
-            send_200("Shutting down...", tcp_stream);
-            return ProgramSignal::StopProgram
-        },
-        ("GET", "/") => {
-            match fs::read_to_string("index.html") {
-                Ok(bytes) => send_200(&bytes, tcp_stream),
-                Err(error) => send_400(&format!("{error}"), tcp_stream),
-            };
+        (_, "/move") => Response::text(405, "Use POST").with_header("Allow", "POST"),
+        ("POST", "/shutdown") => {
+            return (Response::text(200, "Shutting down..."), ProgramSignal::StopProgram);
         },
-        ("GET", whatever) => {
-            let file_reference = &whatever[1..];
-            let file_reference = match decode(file_reference) {
-                Ok(cow) => cow.into_owned(),
+        (_, "/shutdown") => Response::text(405, "Use POST").with_header("Allow", "POST"),
+        ("GET", "/") => state.assets.page(assets::INDEX, state.auth.csrf_token()),
+        ("GET", "/overlaps") => state.assets.page(assets::OVERLAPS, state.auth.csrf_token()),
+        // The page asks the API for the copies, so any id gets the same page.
+        ("GET", compare_path) if compare_path.starts_with("/compare/") => state.assets.page(assets::COMPARE, state.auth.csrf_token()),
+        ("GET", asset_path) if asset_path.starts_with(assets::PREFIX) => {
+            state.assets.response(request, &asset_path[assets::PREFIX.len()..])
+        }
+        ("GET", file_path) if file_path.starts_with("/file/") => {
+            match state.file_access.resolve(database, &file_path["/file/".len()..]) {
+                Ok(resolved) => match response::file_response(request, &resolved) {
+                    Ok(response) => response,
+                    Err(error) => Response::text(400, &format!("{error}")),
+                },
+                Err(FileAccessError::Database(error)) if dbpool::is_busy(&error) => {
+                    Response::text(503, "Database busy, try again shortly")
+                }
                 Err(error) => {
-                    eprintln!("Can't read file reference properly: {error}");
-                    String::new()
+                    eprintln!("Refused file request {file_path}: {error}");
+                    // Deliberately says nothing about why, so nobody can
+                    // probe for which files exist.
+                    Response::text(404, "Not found")
                 }
+            }
+        }
+        ("GET", metrics::PATH) => {
+            let rendered = state.metrics.render(&state.pool.metrics(), state.live_clients.active());
+            Response::new(200).with_header("Content-Type", metrics::CONTENT_TYPE).with_body(rendered.into_bytes())
+        }
+        ("GET", thumb_path) if thumb_path.starts_with("/thumb/") => {
+            thumbnail_response(request, &thumb_path["/thumb/".len()..], database, state)
+        }
+        (method, uri) => Response::text(400, &format!("Invalid request {method} {uri}")),
+    };
+    (response, ProgramSignal::ContinueOnMyWayWardSon)
+}
+
+/// The whole server on a real socket, so everything from accepting to
+/// writing the last byte is covered and not just the routes.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use duplicate_file_monitor::sql;
+    use std::net::{Ipv4Addr, TcpStream};
+    use crate::test_support::ScratchDir;
+    use duplicate_file_monitor::dupdb::dupdb_hash_bytes;
+
+    /// Two copies of one file and a loner, indexed the way the monitor
+    /// would, behind a server on a port of its own.
+    struct TestServer {
+        dir: ScratchDir,
+        address: SocketAddr,
+        state: Arc<AppState>,
+        serving: Option<thread::JoinHandle<()>>,
+    }
+
+    struct Reply {
+        status: u16,
+        head: String,
+        body: Vec<u8>,
+    }
+
+    impl TestServer {
+        fn start() -> TestServer {
+            TestServer::start_with_token(None)
+        }
+
+        fn start_with_token(access_token: Option<&str>) -> TestServer {
+            let dir = ScratchDir::new("server");
+            let sqlite_path = dir.database_path();
+            let connection = rusqlite::Connection::open(&sqlite_path).expect("Could not open test db");
+            sql::initialize(&connection);
+            for (hash, name, contents) in [(1, "a.txt", "same"), (1, "b.txt", "same"), (2, "solo.txt", "different")] {
+                dir.write(name, contents);
+                assert!(sql::insert_file_hash_with_metadata(&connection, hash, &dir.path_of(name), Some(contents.len() as u64), None));
+            }
+            drop(connection);
+
+            let listener = Listener::bind_tcp("127.0.0.1", 0).expect("Could not bind test listener");
+            let Listener::Tcp(tcp_listener) = &listener else {
+                unreachable!("bound to TCP");
             };
-            if !fs::exists(&file_reference).unwrap_or(false) {
-                send_400("No file there m8", tcp_stream);
-            } else {
-                if file_reference.ends_with(".html") {
-                    match fs::read_to_string(file_reference) {
-                        Ok(content) => {
-                            send_200(&content, tcp_stream)
-                        },
-                        Err(error) => send_400(&format!("{error}"), tcp_stream),
-                    };    
-                } else {
-                    match fs::read(file_reference) {
-                        Ok(bytes) => {
-                            send_200_bytes(&bytes, tcp_stream)
-                        },
-                        Err(error) => send_400(&format!("{error}"), tcp_stream),
-                    };
+            let address = tcp_listener.local_addr().expect("Listener has no address");
+            let database_pool = DatabasePool::new(sqlite_path, 2);
+            let fixed_thread_pool = FixedThreadPool::new(2, 2 * QUEUED_CONNECTIONS_PER_WORKER);
+            let state = Arc::new(AppState {
+                file_access: FileAccess::new(&[dir.path()]),
+                auth: Auth::new(access_token.map(str::to_string)),
+                thumbnails: Thumbnails::new(dir.join("thumbnails")),
+                live_clients: LiveClients::new(1),
+                metrics: Metrics::default(),
+                pool: fixed_thread_pool.observer(),
+                log_format: LogFormat::Common,
+                assets: Assets::new(None),
+                shutdown: Shutdown::new(),
+                database_writer: database_pool.writer(),
+            });
+            let serving_state = Arc::clone(&state);
+            let serving = thread::spawn(move || serve(listener, None, database_pool, fixed_thread_pool, serving_state));
+            TestServer { dir, address, state, serving: Some(serving) }
+        }
+
+        fn connect(&self) -> TcpStream {
+            let stream = TcpStream::connect(self.address).expect("Could not connect to test server");
+            stream.set_read_timeout(Some(SOCKET_TIMEOUT)).expect("Could not set timeout");
+            stream
+        }
+
+        /// Sends `raw` as is and reads until the server hangs up.
+        fn send(&self, raw: &[u8]) -> Reply {
+            let mut stream = self.connect();
+            stream.write_all(raw).expect("Could not send request");
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).expect("Could not read response");
+            Reply::parse(&response)
+        }
+
+        fn request(&self, method: &str, target: &str, extra_headers: &str, body: &str) -> Reply {
+            let raw = format!(
+                "{method} {target} HTTP/1.1\r\nHost: localhost\r\n{extra_headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len(),
+            );
+            self.send(raw.as_bytes())
+        }
+
+        fn get(&self, target: &str) -> Reply {
+            self.request("GET", target, "", "")
+        }
+
+        /// Posts a form from our own page, so with the CSRF token.
+        fn post_form(&self, target: &str, fields: &[(&str, &str)]) -> Reply {
+            let body = form_urlencoded::Serializer::new(String::new())
+                .append_pair(CSRF_FIELD, self.state.auth.csrf_token())
+                .extend_pairs(fields)
+                .finish();
+            self.request("POST", target, "Content-Type: application/x-www-form-urlencoded\r\n", &body)
+        }
+
+        /// Calls the API the way our own page's scripts do, with the CSRF header.
+        fn call_api(&self, method: &str, target: &str, body: &str) -> Reply {
+            let headers = format!("{CSRF_HEADER}: {}\r\nContent-Type: application/json\r\n", self.state.auth.csrf_token());
+            self.request(method, target, &headers, body)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.path_of(name)
+        }
+
+        /// Writes and indexes another file, as if the monitor had just seen it.
+        fn index(&self, hash: u64, name: &str, contents: &[u8]) -> i64 {
+            self.dir.write(name, contents);
+            let connection = rusqlite::Connection::open(self.dir.database_path()).expect("Could not open test db");
+            assert!(sql::insert_file_hash_with_metadata(&connection, hash, &self.path(name), Some(contents.len() as u64), None));
+            self.id_of(name)
+        }
+
+        fn id_of(&self, name: &str) -> i64 {
+            let connection = rusqlite::Connection::open(self.dir.database_path()).expect("Could not open test db");
+            sql::id_for_path(&connection, &self.path(name)).expect("lookup failed").expect("row missing")
+        }
+
+        /// True once the accept loop has returned.
+        fn stopped_within(&mut self, timeout: Duration) -> bool {
+            let started = Instant::now();
+            while self.serving.as_ref().is_some_and(|serving| !serving.is_finished()) {
+                if started.elapsed() > timeout {
+                    return false;
                 }
+                thread::sleep(Duration::from_millis(10));
+            }
+            if let Some(serving) = self.serving.take() {
+                serving.join().expect("serving thread panicked");
             }
+            true
         }
-        (method, uri) => send_400(&format!("Invalid request {method} {uri}"), tcp_stream),
     }
-    ProgramSignal::ContinueOnMyWayWardSon
-}
 
-const SELECT_ALL_DUPES: &str = "
-SELECT hash, file_path
-FROM dupdb_filehashes
-WHERE hash IN (
-    SELECT hash
-    FROM dupdb_filehashes
-    GROUP BY hash
-    HAVING COUNT(DISTINCT file_path) > 1
-)
-ORDER BY hash
-";
-
-fn get_dups(conn: &Connection) -> Vec<(String, String)> {
-    let mut statement = conn.prepare_cached(SELECT_ALL_DUPES)
-        .expect("Could not fetch prepared select_dups query");
-
-    let rows = statement.query_map([], |row| {
-        Ok((
-            row.get::<usize, String>(0).expect("could not retrieve hash column 0 for select row"), 
-            row.get::<usize, String>(1).expect("could not retrieve file_path column 1 for select row")
-        ))
-    });
-
-    let mut dups = Vec::new();
-    match rows {
-        Err(binding_failure) => {
-            eprintln!("Unable to select rows from table: {}", binding_failure);
-        },
-        Ok(mapped_rows) => {
-            for result in mapped_rows {
-                let tuple = result
-                    .expect("Impossible. Expect should have failed in query_map before this ever occured");
-                dups.push(tuple);
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.state.shutdown.request();
+            if let Some(serving) = self.serving.take() {
+                let _ = serving.join();
             }
         }
     }
 
-    dups
-}
+    impl Reply {
+        fn parse(response: &[u8]) -> Reply {
+            let split = response.windows(4).position(|window| window == b"\r\n\r\n").expect("no end of headers");
+            let head = String::from_utf8_lossy(&response[..split]).to_string();
+            let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).expect("no status");
+            Reply { status, head, body: response[split + 4..].to_vec() }
+        }
 
-fn send_200_bytes(content: &Vec<u8>, mut tcp_stream: TcpStream) {
-    let status = 200;
-    let status_line = format!("HTTP/1.1 {status} OK");
-    let length = content.len();
-    let headers = format!("Content-Length: {length}\r\nContent-type: application/octet-stream");
-    let response = format!("{status_line}\r\n{headers}\r\n\r\n");
-    match tcp_stream.write_all(response.as_bytes()) {
-        Ok(_) => {
-            let _ = tcp_stream.write_all(content);
-        },
-        Err(error) => {
-            eprintln!("Failed to write response to output {:?}", error);
+        fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines().skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.body).to_string()
+        }
+
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).expect("should be JSON")
         }
     }
-}
 
-fn send_200(content: &str, mut tcp_stream: TcpStream) {
-    let status = 200;
-    let status_line = format!("HTTP/1.1 {status} OK");
-    let length = content.len();
-    let headers = format!("Content-Length: {length}");
-    let response = format!("{status_line}\r\n{headers}\r\n\r\n{content}");
-    match tcp_stream.write_all(response.as_bytes()) {
-        Ok(_) => return,
-        Err(error) => eprintln!("Failed to write response to output {:?}", error)
+    #[test]
+    fn pages_and_assets_are_served() {
+        let server = TestServer::start();
+        let index = server.get("/");
+        assert_eq!(index.status, 200);
+        assert!(index.text().contains(server.state.auth.csrf_token()), "the page should carry the CSRF token");
+        assert_eq!(server.get("/compare/1").status, 200);
+        assert_eq!(server.get("/overlaps").status, 200);
+        assert_eq!(server.get("/assets/app.js").status, 200);
+        assert_eq!(server.get("/assets/index.html").status, 404);
+
+        let head = server.request("HEAD", "/", "", "");
+        assert_eq!(head.status, 200);
+        assert!(head.body.is_empty());
+        assert_ne!(head.header("Content-Length"), Some("0"));
     }
-}
 
-fn send_303_home(mut tcp_stream: TcpStream) {
-    let status = 303;
-    let status_line = format!("HTTP/1.1 {status} SEE OTHER");
-    let headers = format!("Location: /");
-    let response = format!("{status_line}\r\n{headers}\r\n\r\n");
-    match tcp_stream.write_all(response.as_bytes()) {
-        Ok(_) => return,
-        Err(error) => eprintln!("Failed to write response to output {:?}", error)
+    #[test]
+    fn duplicates_are_listed_as_text() {
+        let server = TestServer::start();
+        let listed = server.get("/duplicates");
+        assert_eq!(listed.status, 200);
+        assert!(listed.text().contains(&format!("1\n{}\n\n", server.path("a.txt"))));
+        assert!(!listed.text().contains("solo.txt"));
+
+        let queried = server.get("/duplicates?limit=10");
+        assert_eq!(queried.status, 200);
+        assert!(queried.text().contains(&format!("{}\n{}\n", server.path("b.txt"), server.id_of("b.txt"))));
+        assert_eq!(queried.header("X-Next-Cursor"), Some(""));
+        assert_eq!(server.get("/duplicates?limit=lots").status, 400);
     }
-}
 
-fn send_400(content: &str, mut tcp_stream: TcpStream) {
-    let status = 400;
-    let status_line = format!("HTTP/1.1 {status} Bad Request");
-    let length = content.len();
-    let headers = format!("Content-Length: {length}");
-    let response = format!("{status_line}\r\n{headers}\r\n\r\n{content}");
-    match tcp_stream.write_all(response.as_bytes()) {
-        Ok(_) => return,
-        Err(error) => eprintln!("Failed to write response to output {:?}", error)
+    #[test]
+    fn files_and_thumbnails_are_served_by_id() {
+        let server = TestServer::start();
+        let a = server.id_of("a.txt");
+        let file = server.get(&format!("/file/{a}"));
+        assert_eq!(file.status, 200);
+        assert_eq!(file.text(), "same");
+        assert_eq!(server.get("/file/9999").status, 404);
+        assert_eq!(server.get("/file/nope").status, 404);
+
+        let thumbnail = server.get(&format!("/thumb/{a}"));
+        assert_eq!(thumbnail.status, 200);
+        assert_eq!(thumbnail.header("Content-Type"), Some("image/svg+xml"));
+        assert_eq!(server.get(&format!("/thumb/{a}?size=huge")).status, 400);
+        assert_eq!(server.get("/thumb/9999").status, 404);
     }
-}
 
-fn parse_http_request_line(line: &str) -> (&str, &str) {
-    let method_and_uri: Vec<&str> =line
-        .split(" ") // https://datatracker.ietf.org/doc/html/rfc2616#autoid-38
-        .take(2)
-        .collect();
-    if method_and_uri.len() != 2 {
-        return ("???", "???");
-    }
-    let method = method_and_uri[0];
-    let uri = method_and_uri[1];
-    return (method, uri);
-}
+    #[test]
+    fn indexed_pages_never_run_as_the_app() {
+        let server = TestServer::start();
+        let page = server.index(3, "saved.html", b"<script>fetch('/').then(steal)</script>");
+        let served = server.get(&format!("/file/{page}"));
+        assert_eq!(served.status, 200);
+        assert_eq!(served.header("Content-Type"), Some("application/octet-stream"));
+        assert_eq!(served.header("Content-Disposition"), Some("attachment"));
+        assert_eq!(served.header("Content-Security-Policy"), Some("sandbox"));
+        assert_eq!(served.header("X-Content-Type-Options"), Some("nosniff"));
 
-fn parse_args() -> (String, String, u16, usize) {
-    let mut sqlite_path = None;
-    let mut host = Some(String::from("127.0.0.1"));
-    let mut port = Some(6969);
-    let mut pool_size = Some(4);
-    let mut args = env::args();
-    args.next(); // Skip program name.
-    while let Some(argument) = args.next() {
-        match &argument[..] {
-            "-db" => sqlite_path = args.next(),
-            "-p" => port = args.next().map(
-                |string| string.parse().expect("Could not parse port as integer")
-            ),
-            "-h" => host = args.next(),
-            "-s" => pool_size = args.next().map(
-                |string| string.parse().expect("Could not parse pool size as number")
-            ),
-            unknown => eprintln!("Unknown flag {unknown}"),
-        };
+        let drawing = server.index(4, "drawing.svg", b"<svg xmlns=\"http://www.w3.org/2000/svg\" onload=\"steal()\"/>");
+        assert_eq!(server.get(&format!("/file/{drawing}")).header("Content-Disposition"), Some("attachment"));
+        let thumbnail = server.get(&format!("/thumb/{drawing}"));
+        assert_eq!(thumbnail.header("Content-Security-Policy"), Some("sandbox"));
+        assert_eq!(thumbnail.header("X-Content-Type-Options"), Some("nosniff"));
+    }
+
+    #[test]
+    fn removing_needs_the_form_token_and_keeps_the_last_copy() {
+        let server = TestServer::start();
+        let remove = |name: &str| server.post_form("/remove", &[("id", &server.id_of(name).to_string()), ("path", &server.path(name))]);
+        let body = format!("id={}&path={}", server.id_of("a.txt"), server.path("a.txt"));
+        let forged = server.request("POST", "/remove", "", &body);
+        assert_eq!(forged.status, 403);
+        assert!(server.dir.join("a.txt").exists());
+
+        let removed = remove("a.txt");
+        assert_eq!(removed.status, 303);
+        assert_eq!(removed.header("Location"), Some("/"));
+        assert!(!server.dir.join("a.txt").exists());
+
+        assert_eq!(remove("b.txt").status, 409);
+        assert_eq!(remove("solo.txt").status, 409);
+        assert_eq!(server.post_form("/remove", &[("path", &server.path("b.txt"))]).status, 400);
+        assert_eq!(server.post_form("/remove", &[]).status, 400);
+        assert!(server.dir.join("b.txt").exists());
+        assert!(server.get("/metrics").text().contains("dupdb_files_removed_total 1\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_form_only_removes_what_the_api_would() {
+        let server = TestServer::start();
+        let elsewhere = ScratchDir::new("server_elsewhere");
+        let outside = elsewhere.write("same.txt", "same");
+        std::os::unix::fs::symlink(&outside, server.dir.join("outside.txt")).expect("Could not make symlink");
+        let connection = rusqlite::Connection::open(server.dir.database_path()).expect("Could not open test db");
+        assert!(sql::insert_file_hash(&connection, 1, &server.path("outside.txt")));
+
+        let id = server.id_of("outside.txt").to_string();
+        assert_eq!(server.post_form("/remove", &[("id", &id), ("path", &server.path("outside.txt"))]).status, 404);
+        assert!(server.dir.join("outside.txt").exists());
+        // Nor anything by a path the id doesn't stand for.
+        assert_eq!(server.post_form("/remove", &[("id", &id), ("path", &server.path("a.txt"))]).status, 409);
+        assert!(server.dir.join("a.txt").exists());
+    }
+
+    #[test]
+    fn moving_from_the_form() {
+        let server = TestServer::start();
+        let a = server.id_of("a.txt").to_string();
+        let target = server.path("kept");
+        let moved = server.post_form("/move", &[("id", &a), ("target_dir", &target), ("remove_others", "1")]);
+        assert_eq!(moved.status, 303);
+        assert!(server.dir.join("kept/a.txt").exists());
+        assert!(!server.dir.join("a.txt").exists());
+        assert!(!server.dir.join("b.txt").exists());
+
+        assert_eq!(server.post_form("/move", &[("id", &a), ("target_dir", "/")]).status, 403);
+        assert_eq!(server.post_form("/move", &[("id", &a)]).status, 400);
+        assert_eq!(server.get("/move").status, 405);
+    }
+
+    #[test]
+    fn the_api_answers_in_json() {
+        let server = TestServer::start();
+        let groups = server.get("/api/v1/groups");
+        assert_eq!(groups.status, 200);
+        assert_eq!(groups.header("Content-Type"), Some("application/json"));
+        assert_eq!(groups.json()["groups"][0]["copies"], 2);
+        assert_eq!(server.get("/api/v1/stats").json()["files_indexed"], 3);
+
+        let missing = server.get("/api/v1/nothing");
+        assert_eq!(missing.status, 404);
+        assert!(missing.json()["error"].is_string());
+
+        let a = server.id_of("a.txt");
+        let target = format!("/api/v1/files/{a}?path={}", server.path("a.txt"));
+        let forged = server.request("DELETE", &target, "", "");
+        assert_eq!(forged.status, 403);
+        assert!(forged.json()["error"].is_string());
+        assert_eq!(server.call_api("DELETE", &target, "").status, 200);
+        assert!(!server.dir.join("a.txt").exists());
+    }
+
+    #[test]
+    fn files_answer_ranges_and_conditional_requests() {
+        let server = TestServer::start();
+        let solo = format!("/file/{}", server.id_of("solo.txt"));
+        let whole = server.get(&solo);
+        assert_eq!(whole.header("Accept-Ranges"), Some("bytes"));
+        let etag = whole.header("ETag").expect("should have an ETag");
+        let last_modified = whole.header("Last-Modified").expect("should have a date");
+
+        let part = server.request("GET", &solo, "Range: bytes=0-3\r\n", "");
+        assert_eq!(part.status, 206);
+        assert_eq!(part.text(), "diff");
+        assert_eq!(part.header("Content-Range"), Some("bytes 0-3/9"));
+        let past_the_end = server.request("GET", &solo, "Range: bytes=20-30\r\n", "");
+        assert_eq!(past_the_end.status, 416);
+        assert_eq!(past_the_end.header("Content-Range"), Some("bytes */9"));
+        let stale = server.request("GET", &solo, "Range: bytes=0-3\r\nIf-Range: \"older\"\r\n", "");
+        assert_eq!((stale.status, stale.text().as_str()), (200, "different"));
+
+        let cached = server.request("GET", &solo, &format!("If-None-Match: {etag}\r\n"), "");
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+        assert_eq!(server.request("GET", &solo, &format!("If-Modified-Since: {last_modified}\r\n"), "").status, 304);
+        assert_eq!(server.request("GET", &solo, "If-None-Match: \"older\"\r\n", "").status, 200);
+    }
+
+    #[test]
+    fn search_takes_uploads_too_big_for_other_requests() {
+        let server = TestServer::start();
+        let found = server.get("/api/v1/search?hash=2");
+        assert_eq!(found.status, 200);
+        assert_eq!(found.json()["files"][0]["id"], server.id_of("solo.txt"));
+        assert_eq!(server.get("/api/v1/search?hash=abc").status, 400);
+
+        // Bigger than any other body may be, and no CSRF token needed.
+        let upload = "0123456789abcdef".repeat(8 * 1024);
+        assert!(upload.len() > Limits::default().max_body_bytes && upload.len() < MAX_UPLOAD_BYTES);
+        server.index(dupdb_hash_bytes(upload.as_bytes()), "upload.bin", upload.as_bytes());
+        let uploaded = server.request("POST", api::SEARCH_PATH, "Content-Type: application/octet-stream\r\n", &upload);
+        assert_eq!(uploaded.status, 200);
+        assert_eq!(uploaded.json()["files"][0]["path"], server.path("upload.bin"));
+
+        // Only the head is sent, the server answers before reading any body.
+        let elsewhere = format!("POST /api/v1/bulk/apply HTTP/1.1\r\nContent-Length: {}\r\n\r\n", upload.len());
+        assert_eq!(server.send(elsewhere.as_bytes()).status, 413);
+        let too_big = format!("POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n", api::SEARCH_PATH, MAX_UPLOAD_BYTES + 1);
+        assert_eq!(server.send(too_big.as_bytes()).status, 413);
+    }
+
+    #[test]
+    fn bulk_removal_is_planned_then_applied() {
+        let server = TestServer::start();
+        let (a, b) = (server.id_of("a.txt"), server.id_of("b.txt"));
+        let plan = server.get("/api/v1/bulk/plan?rule=shortest");
+        assert_eq!(plan.status, 200);
+        assert_eq!(plan.json()["groups"][0]["keep"]["id"], a);
+        assert_eq!(plan.json()["groups"][0]["remove"][0]["id"], b);
+        assert_eq!(server.get("/api/v1/bulk/plan?rule=largest").status, 400);
+
+        let body = format!("{{\"hash\": \"1\", \"keep\": {a}, \"remove\": [{b}]}}");
+        assert_eq!(server.request("POST", "/api/v1/bulk/apply", "", &body).status, 403);
+        assert!(server.dir.join("b.txt").exists());
+        let applied = server.call_api("POST", "/api/v1/bulk/apply", &body);
+        assert_eq!(applied.status, 200);
+        assert_eq!(applied.json()["results"][0]["removed"], true);
+        assert!(server.dir.join("a.txt").exists());
+        assert!(!server.dir.join("b.txt").exists());
+    }
+
+    #[test]
+    fn copies_can_be_compared_and_moved() {
+        let server = TestServer::start();
+        let (a, b) = (server.id_of("a.txt"), server.id_of("b.txt"));
+        let metadata = server.get(&format!("/api/v1/files/{a}/metadata"));
+        assert_eq!(metadata.status, 200);
+        assert_eq!(metadata.json()["name"], "a.txt");
+        let compared = server.get(&format!("/api/v1/files/{b}/compare"));
+        assert_eq!(compared.status, 200);
+        assert_eq!(compared.json()["copies"][1]["id"], a);
+        assert_eq!(compared.json()["text"][0]["identical"], true);
+
+        let move_a = format!("/api/v1/files/{a}/move");
+        let body = format!(r#"{{"target_dir":"{}"}}"#, server.path("kept"));
+        assert_eq!(server.request("POST", &move_a, "", &body).status, 403);
+        assert!(server.dir.join("a.txt").exists());
+        let moved = server.call_api("POST", &move_a, &body);
+        assert_eq!(moved.status, 200);
+        assert_eq!(moved.json()["to"], server.path("kept/a.txt"));
+        assert!(server.dir.join("kept/a.txt").exists());
+        assert!(server.dir.join("b.txt").exists());
+        assert_eq!(server.call_api("POST", &format!("/api/v1/files/{b}/move"), r#"{"target_dir":"/"}"#).status, 403);
+    }
+
+    #[test]
+    fn overlapping_folders_are_reported() {
+        let server = TestServer::start();
+        server.index(1, "copy/a.txt", b"same");
+        server.index(2, "copy/solo.txt", b"different");
+        let overlaps = server.get("/api/v1/overlaps");
+        assert_eq!(overlaps.status, 200);
+        let overlap = &overlaps.json()["overlaps"][0];
+        assert_eq!(overlap["second"]["path"], server.path("copy"));
+        assert_eq!(overlap["shared_files"], 2);
+        assert_eq!(overlap["second"]["fully_contained"], true);
+
+        assert_eq!(server.get("/api/v1/overlaps?min_shared=3").json()["overlaps"], serde_json::json!([]));
+        assert_eq!(server.get("/api/v1/overlaps?min_shared=lots").status, 400);
+    }
+
+    #[test]
+    fn metrics_and_live_updates() {
+        let server = TestServer::start();
+        server.get("/");
+        let metrics = server.get("/metrics");
+        assert_eq!(metrics.status, 200);
+        assert!(metrics.text().contains("dupdb_http_requests_total"));
+
+        // Live updates never end by themselves, so only the head is read.
+        let mut stream = server.connect();
+        stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").expect("Could not send request");
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).expect("Could not read response");
+            head.push(byte[0]);
+        }
+        let events = Reply::parse(&head);
+        assert_eq!(events.status, 200);
+        assert_eq!(events.header("Content-Type"), Some("text/event-stream"));
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        let server = TestServer::start();
+        assert_eq!(server.send(b"nonsense\r\n\r\n").status, 400);
+        assert_eq!(server.send(b"GET / HTTP/2.0\r\n\r\n").status, 501);
+        assert_eq!(server.send(b"POST /remove HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n").status, 413);
+        let huge_header = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "x".repeat(20_000));
+        assert_eq!(server.send(huge_header.as_bytes()).status, 431);
+        assert_eq!(server.get("/nowhere").status, 400);
+
+        // Hanging up without asking anything gets nothing back, and the
+        // server carries on.
+        let mut stream = server.connect();
+        stream.shutdown(std::net::Shutdown::Write).expect("Could not hang up");
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).expect("Could not read response");
+        assert!(response.is_empty());
+        assert_eq!(server.get("/").status, 200);
+    }
+
+    #[test]
+    fn connections_are_kept_alive() {
+        let server = TestServer::start();
+        let raw = "GET /api/v1/stats HTTP/1.1\r\nHost: localhost\r\n\r\nGET /api/v1/stats HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let mut stream = server.connect();
+        stream.write_all(raw.as_bytes()).expect("Could not send requests");
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).expect("Could not read responses");
+        let answered = String::from_utf8_lossy(&response).matches("HTTP/1.1 200 OK").count();
+        assert_eq!(answered, 2);
+    }
+
+    #[test]
+    fn everything_needs_the_token_when_there_is_one() {
+        let server = TestServer::start_with_token(Some("s3cret"));
+        let refused = server.get("/");
+        assert_eq!(refused.status, 401);
+        assert!(refused.header("WWW-Authenticate").is_some());
+        let refused_api = server.get("/api/v1/stats");
+        assert_eq!(refused_api.status, 401);
+        assert!(refused_api.json()["error"].is_string());
+        assert_eq!(server.request("GET", "/", "Authorization: Bearer s3cret\r\n", "").status, 200);
+        assert_eq!(server.request("GET", "/", "Authorization: Bearer wrong\r\n", "").status, 401);
+    }
+
+    #[test]
+    fn shutdown_stops_accepting() {
+        let mut server = TestServer::start();
+        assert_eq!(server.get("/shutdown").status, 405);
+        assert_eq!(server.request("POST", "/shutdown", "", "").status, 403);
+        assert!(!server.stopped_within(Duration::from_millis(300)), "a refused shutdown shouldn't stop anything");
+
+        let stopping = server.post_form("/shutdown", &[]);
+        assert_eq!(stopping.status, 200);
+        assert_eq!(stopping.text(), "Shutting down...");
+        assert!(server.stopped_within(Duration::from_secs(5)), "the accept loop should have returned");
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, server.address.port())).is_err());
     }
-    (
-        sqlite_path.expect("Please provide the sqlite database path for dupdb via '-db path'").to_string(),
-        host.expect("No host was set, provide -h 127.0.0.1 if unsure").to_string(),
-        port.expect("No port was set, please provide a number above 1000 to the -p flag"),
-        pool_size.expect("Please provide a pool size for how many worker threads will handle requests with -s 4 or similar")
-    )
 }
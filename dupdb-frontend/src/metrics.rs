@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use fixed_thread_pool::PoolMetrics;
+
+pub const PATH: &str = "/metrics";
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// Anything else is counted as OTHER, so clients can't invent new series.
+const KNOWN_METHODS: [&str; 4] = ["GET", "HEAD", "POST", "DELETE"];
+
+/// Counters for `/metrics`, in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    /// Not cumulative, each request only counts in the first bucket it fits.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_micros: AtomicU64,
+    response_bytes: AtomicU64,
+    rejected_connections: AtomicU64,
+    files_removed: AtomicU64,
+    files_moved: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_request(&self, method: &str, status: u16, bytes: u64, latency: Duration) {
+        let method = KNOWN_METHODS.into_iter().find(|known| *known == method).unwrap_or("OTHER");
+        *self.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).entry((method, status)).or_default() += 1;
+
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.response_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A connection turned away because the worker pool's queue was full.
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_file_removed(&self) {
+        self.files_removed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_file_moved(&self) {
+        self.files_moved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, pool: &PoolMetrics, live_clients: usize) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+
+        header(&mut out, "dupdb_http_requests_total", "counter", "Requests answered, by method and status.");
+        for ((method, status), count) in &requests {
+            let _ = writeln!(out, "dupdb_http_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}");
+        }
+
+        header(&mut out, "dupdb_http_request_duration_seconds", "histogram", "Time from a request arriving to its response being sent.");
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "dupdb_http_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let total: u64 = requests.values().sum();
+        let _ = writeln!(out, "dupdb_http_request_duration_seconds_bucket{{le=\"+Inf\"}} {total}");
+        let seconds = self.latency_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "dupdb_http_request_duration_seconds_sum {seconds}");
+        let _ = writeln!(out, "dupdb_http_request_duration_seconds_count {total}");
+
+        let counters = [
+            ("dupdb_http_response_bytes_total", "Response body bytes sent.", self.response_bytes.load(Ordering::Relaxed)),
+            ("dupdb_rejected_connections_total", "Connections turned away with a 503 because every worker was busy.", self.rejected_connections.load(Ordering::Relaxed)),
+            ("dupdb_files_removed_total", "Duplicate files removed through the frontend.", self.files_removed.load(Ordering::Relaxed)),
+            ("dupdb_files_moved_total", "Duplicate files moved through the frontend.", self.files_moved.load(Ordering::Relaxed)),
+            ("dupdb_pool_completed_jobs_total", "Connections the worker pool finished handling.", pool.completed as u64),
+            ("dupdb_pool_panicked_jobs_total", "Connections whose handling panicked.", pool.panicked as u64),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        let gauges = [
+            ("dupdb_pool_workers", "Worker threads alive.", pool.workers),
+            ("dupdb_pool_active_jobs", "Connections being handled right now.", pool.active),
+            ("dupdb_pool_queued_jobs", "Connections waiting for a worker.", pool.queued),
+            ("dupdb_live_clients", "Browsers listening for live updates.", live_clients),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line<'a>(rendered: &'a str, metric: &str) -> Option<&'a str> {
+        rendered.lines().find(|line| line.starts_with(metric) && line[metric.len()..].starts_with(' '))
+    }
+
+    #[test]
+    fn requests_are_counted_by_method_and_status() {
+        let metrics = Metrics::default();
+        metrics.record_request("GET", 200, 10, Duration::from_millis(3));
+        metrics.record_request("GET", 200, 5, Duration::from_millis(30));
+        metrics.record_request("BREW", 400, 0, Duration::from_secs(60));
+        let rendered = metrics.render(&PoolMetrics::default(), 0);
+
+        assert_eq!(line(&rendered, "dupdb_http_requests_total{method=\"GET\",status=\"200\"}"), Some("dupdb_http_requests_total{method=\"GET\",status=\"200\"} 2"));
+        assert!(line(&rendered, "dupdb_http_requests_total{method=\"OTHER\",status=\"400\"}").is_some());
+        assert!(!rendered.contains("BREW"));
+        assert_eq!(line(&rendered, "dupdb_http_response_bytes_total"), Some("dupdb_http_response_bytes_total 15"));
+    }
+
+    #[test]
+    fn latency_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.record_request("GET", 200, 0, Duration::from_millis(3));
+        metrics.record_request("GET", 200, 0, Duration::from_millis(30));
+        metrics.record_request("GET", 200, 0, Duration::from_secs(60));
+        let rendered = metrics.render(&PoolMetrics::default(), 0);
+
+        assert!(rendered.contains("dupdb_http_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("dupdb_http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(rendered.contains("dupdb_http_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(rendered.contains("dupdb_http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert_eq!(line(&rendered, "dupdb_http_request_duration_seconds_count"), Some("dupdb_http_request_duration_seconds_count 3"));
+    }
+
+    #[test]
+    fn pool_and_removals_are_reported() {
+        let metrics = Metrics::default();
+        metrics.record_file_removed();
+        metrics.record_file_moved();
+        metrics.record_rejected_connection();
+        let pool = PoolMetrics { workers: 4, active: 1, queued: 2, completed: 9, panicked: 0 };
+        let rendered = metrics.render(&pool, 3);
+
+        assert_eq!(line(&rendered, "dupdb_files_removed_total"), Some("dupdb_files_removed_total 1"));
+        assert_eq!(line(&rendered, "dupdb_files_moved_total"), Some("dupdb_files_moved_total 1"));
+        assert_eq!(line(&rendered, "dupdb_rejected_connections_total"), Some("dupdb_rejected_connections_total 1"));
+        assert_eq!(line(&rendered, "dupdb_pool_queued_jobs"), Some("dupdb_pool_queued_jobs 2"));
+        assert_eq!(line(&rendered, "dupdb_live_clients"), Some("dupdb_live_clients 3"));
+        assert!(rendered.contains("# TYPE dupdb_pool_workers gauge\n"));
+    }
+}
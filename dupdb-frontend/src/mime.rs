@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Good enough to get browsers previewing what people usually have
+/// duplicates of: photos, music, videos and documents.
+const BY_EXTENSION: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("avif", "image/avif"),
+    ("heic", "image/heic"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("json", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("log", "text/plain; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("xml", "application/xml; charset=utf-8"),
+    ("xhtml", "application/xhtml+xml; charset=utf-8"),
+];
+
+/// Types a browser would run script from if shown inline, which for files
+/// served from our own origin would mean running it as the app.
+const ACTIVE: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/javascript",
+    "application/javascript",
+    "application/xml",
+    "text/xml",
+];
+
+/// Leading bytes of formats that often turn up without (or with the wrong)
+/// extension. An offset lets us match the `ftyp` box of mp4 and friends.
+const BY_MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\xFF\xD8\xFF", "image/jpeg"),
+    (0, b"\x89PNG\r\n\x1A\n", "image/png"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1F\x8B", "application/gzip"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"\x1A\x45\xDF\xA3", "video/webm"),
+    (4, b"ftypqt", "video/quicktime"),
+    (4, b"ftyp", "video/mp4"),
+];
+
+pub const FALLBACK: &str = "application/octet-stream";
+
+/// How many bytes of a file `from_magic` wants to see.
+pub const MAGIC_LENGTH: usize = 16;
+
+/// Whether a file of this type may only ever be downloaded, never shown.
+pub fn is_active(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    ACTIVE.iter().any(|active| essence.eq_ignore_ascii_case(active))
+}
+
+pub fn from_extension(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    BY_EXTENSION.iter().find(|(known, _)| *known == extension).map(|(_, mime)| *mime)
+}
+
+/// By extension, falling back to the first few bytes. Leaves `file` where it found it.
+pub fn for_file(path: &Path, file: &mut File) -> io::Result<&'static str> {
+    if let Some(content_type) = from_extension(path) {
+        return Ok(content_type);
+    }
+    let start = file.stream_position()?;
+    let mut leading_bytes = Vec::with_capacity(MAGIC_LENGTH);
+    file.take(MAGIC_LENGTH as u64).read_to_end(&mut leading_bytes)?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok(from_magic(&leading_bytes).unwrap_or(FALLBACK))
+}
+
+pub fn from_magic(leading_bytes: &[u8]) -> Option<&'static str> {
+    // RIFF containers say what they hold 8 bytes in.
+    if leading_bytes.starts_with(b"RIFF") && leading_bytes.len() >= 12 {
+        return match &leading_bytes[8..12] {
+            b"WEBP" => Some("image/webp"),
+            b"WAVE" => Some("audio/wav"),
+            b"AVI " => Some("video/x-msvideo"),
+            _ => None,
+        };
+    }
+    BY_MAGIC.iter()
+        .find(|(offset, magic, _)| leading_bytes.get(*offset..).is_some_and(|bytes| bytes.starts_with(magic)))
+        .map(|(_, _, mime)| *mime)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extensions_are_case_insensitive() {
+        assert_eq!(from_extension(Path::new("/a/b/IMG_0001.JPG")), Some("image/jpeg"));
+        assert_eq!(from_extension(Path::new("clip.mp4")), Some("video/mp4"));
+        assert_eq!(from_extension(Path::new("no_extension")), None);
+        assert_eq!(from_extension(Path::new("weird.xyz")), None);
+    }
+
+    #[test]
+    fn magic_bytes_identify_common_formats() {
+        assert_eq!(from_magic(b"\xFF\xD8\xFF\xE0\x00\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(from_magic(b"\x89PNG\r\n\x1A\n\x00\x00"), Some("image/png"));
+        assert_eq!(from_magic(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(from_magic(b"\x00\x00\x00\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(from_magic(b"\x00\x00\x00\x14ftypqt  "), Some("video/quicktime"));
+        assert_eq!(from_magic(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(from_magic(b"RIFF\x00\x00\x00\x00WAVEfmt "), Some("audio/wav"));
+        assert_eq!(from_magic(b"hello world"), None);
+        assert_eq!(from_magic(b""), None);
+    }
+
+    #[test]
+    fn scriptable_types_are_active() {
+        assert!(is_active("text/html; charset=utf-8"));
+        assert!(is_active("image/SVG+xml"));
+        assert!(is_active(from_extension(Path::new("feed.xml")).expect("known")));
+        assert!(!is_active("text/plain; charset=utf-8"));
+        assert!(!is_active("image/png"));
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use duplicate_file_monitor::DuplicateDatabase;
+
+use crate::AppState;
+use crate::dbpool;
+use crate::files::FileAccessError;
+
+/// Where a copy was and where it is now.
+#[derive(Debug, PartialEq)]
+pub struct Moved {
+    pub from: String,
+    pub to: String,
+}
+
+/// Moves the file behind `id` into `target_dir` under the same name and
+/// points its database row at the new path, so it stays the same file to
+/// everyone holding its id. Never replaces anything already there. Errors
+/// come with the status to answer with.
+pub fn move_file(id: i64, target_dir: &str, database: &DuplicateDatabase, state: &AppState) -> Result<Moved, (u16, String)> {
+    let from = match database.path_for_file_id(id) {
+        Ok(Some(path)) => path,
+        Ok(None) => return Err((404, "No file with that id".to_string())),
+        Err(database_error) => return Err((dbpool::error_status(&database_error), format!("Could not look up file: {database_error}"))),
+    };
+    // Same as removing, nothing we wouldn't also show.
+    match state.file_access.resolve(database, &id.to_string()) {
+        Ok(_) => {},
+        Err(FileAccessError::Database(database_error)) => {
+            return Err((dbpool::error_status(&database_error), format!("Could not look up file: {database_error}")));
+        },
+        Err(_) => return Err((404, "No file with that id".to_string())),
+    }
+    let target_dir = state.file_access.prepare_directory(target_dir).map_err(|reason| (403, reason))?;
+    let Some(name) = Path::new(&from).file_name() else {
+        return Err((409, format!("{from} has no file name to keep")));
+    };
+    let destination = target_dir.join(name);
+    let Some(to) = destination.to_str().map(str::to_string) else {
+        return Err((400, format!("{} is not valid UTF-8", destination.display())));
+    };
+    if to == from {
+        return Err((409, format!("{from} is already there")));
+    }
+    move_without_replacing(Path::new(&from), &destination).map_err(|error| match error.kind() {
+        io::ErrorKind::AlreadyExists => (409, format!("{to} already exists, refusing to replace it")),
+        _ => (500, format!("Could not move {from} to {to}: {error}")),
+    })?;
+    state.metrics.record_file_moved();
+
+    let updated = state.database_writer.open().map(|mut writer| writer.rename(&from, &to));
+    if !matches!(updated, Ok(true)) {
+        // The monitor sorts the row out itself if it's watching either folder.
+        return Err((500, format!("Moved {from} to {to} but could not update the database")));
+    }
+    Ok(Moved { from, to })
+}
+
+/// A hard link can't replace anything, so it's made first and the old name
+/// removed after. Across filesystems it's a copy to a new file instead.
+fn move_without_replacing(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => fs::remove_file(from).inspect_err(|_| {
+            let _ = fs::remove_file(to);
+        }),
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Err(error),
+        // Another filesystem, or one without hard links.
+        Err(_) => copy_then_remove(from, to),
+    }
+}
+
+fn copy_then_remove(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let metadata = source.metadata()?;
+    let mut destination = OpenOptions::new().write(true).create_new(true).open(to)?;
+    let copied = io::copy(&mut source, &mut destination)
+        .and_then(|_| destination.set_permissions(metadata.permissions()))
+        .and_then(|_| metadata.modified().and_then(|modified| destination.set_modified(modified)))
+        .and_then(|_| destination.sync_all())
+        .and_then(|_| fs::remove_file(from));
+    // Half a move would just be another duplicate.
+    if copied.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    copied
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+
+    #[test]
+    fn moves_never_replace_anything() {
+        let scratch = ScratchDir::new("moves");
+        let (from, to) = (scratch.join("a.txt"), scratch.join("b.txt"));
+        fs::write(&from, "a").expect("Could not write test file");
+        fs::write(&to, "b").expect("Could not write test file");
+        let error = move_without_replacing(&from, &to).expect_err("should refuse");
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&to).expect("should still be there"), "b");
+        assert!(from.exists());
+
+        fs::remove_file(&to).expect("Could not remove test file");
+        move_without_replacing(&from, &to).expect("should move");
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).expect("should be moved"), "a");
+    }
+
+    #[test]
+    fn copies_keep_contents_and_times() {
+        let scratch = ScratchDir::new("moves");
+        let (from, to) = (scratch.join("a.txt"), scratch.join("b.txt"));
+        fs::write(&from, "contents").expect("Could not write test file");
+        let modified = fs::metadata(&from).and_then(|metadata| metadata.modified()).expect("no mtime");
+        copy_then_remove(&from, &to).expect("should copy");
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).expect("should be copied"), "contents");
+        assert_eq!(fs::metadata(&to).and_then(|metadata| metadata.modified()).expect("no mtime"), modified);
+
+        fs::write(&from, "again").expect("Could not write test file");
+        assert_eq!(copy_then_remove(&from, &to).expect_err("should refuse").kind(), io::ErrorKind::AlreadyExists);
+        assert!(from.exists());
+    }
+}
@@ -0,0 +1,390 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::http::Request;
+use crate::mime;
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Streamed straight from disk, `length` bytes from wherever the file
+    /// is currently positioned.
+    File { file: File, length: u64 },
+}
+
+/// Everything needed to answer a request. Nothing is written until
+/// `write_to`, which is where HEAD and keep-alive get taken care of.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response { status, headers: Vec::new(), body: Body::Empty }
+    }
+
+    pub fn text(status: u16, content: &str) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(content.as_bytes().to_vec())
+    }
+
+    pub fn html(content: &str) -> Self {
+        Response::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(content.as_bytes().to_vec())
+    }
+
+    pub fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        match serde_json::to_vec(body) {
+            Ok(bytes) => Response::new(status).with_header("Content-Type", "application/json").with_body(bytes),
+            Err(error) => Response::text(500, &format!("Could not serialize response: {error}")),
+        }
+    }
+
+    pub fn see_other(location: &str) -> Self {
+        Response::new(303).with_header("Location", location)
+    }
+
+    /// Unlike a 303, the client repeats the same method and body at `location`.
+    pub fn permanent_redirect(location: &str) -> Self {
+        Response::new(308).with_header("Location", location)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, bytes: Vec<u8>) -> Self {
+        self.body = Body::Bytes(bytes);
+        self
+    }
+
+    pub fn content_length(&self) -> u64 {
+        match &self.body {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File { length, .. } => *length,
+        }
+    }
+
+    /// HEAD gets every header a GET would, Content-Length included, but no body.
+    pub fn write_to<W: Write>(self, writer: &mut W, head_only: bool, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.content_length()));
+        }
+        head.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+        writer.write_all(head.as_bytes())?;
+
+        if !head_only && self.status != 304 {
+            match self.body {
+                Body::Empty => {},
+                Body::Bytes(bytes) => writer.write_all(&bytes)?,
+                Body::File { file, length } => {
+                    let copied = io::copy(&mut file.take(length), writer)?;
+                    if copied != length {
+                        // Content-Length is already out, all we can do is hang up.
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while sending it"));
+                    }
+                },
+            }
+        }
+        writer.flush()
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        303 => "See Other",
+        304 => "Not Modified",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Serves a file that has already been cleared by `FileAccess`, answering
+/// conditional and range requests so browsers can cache and seek. These are
+/// whatever happens to be in the watched folders, so nothing in them gets
+/// to run as our own page: anything scriptable is only ever a download.
+pub fn file_response(request: &Request, path: &Path) -> io::Result<Response> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let length = metadata.len();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let etag = etag_for(length, modified);
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let validators = Response::new(200)
+        .with_header("ETag", &etag)
+        .with_header("Last-Modified", &last_modified)
+        .with_header("Cache-Control", "private, no-cache")
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("X-Content-Type-Options", "nosniff")
+        .with_header("Content-Security-Policy", "sandbox");
+
+    if is_not_modified(request, &etag, modified) {
+        return Ok(Response { status: 304, ..validators });
+    }
+
+    let content_type = mime::for_file(path, &mut file)?;
+    let response = if mime::is_active(content_type) {
+        validators.with_header("Content-Type", mime::FALLBACK).with_header("Content-Disposition", "attachment")
+    } else {
+        validators.with_header("Content-Type", content_type)
+    };
+
+    // A stale If-Range means the client's partial copy is of some older
+    // file, so it gets the whole new one instead.
+    let range_still_valid = match request.header("If-Range") {
+        None => true,
+        Some(if_range) => if_range == etag || if_range == last_modified,
+    };
+    let range = request.header("Range").filter(|_| range_still_valid).and_then(|range| parse_range(range, length));
+    match range {
+        None => Ok(Response { body: Body::File { file, length }, ..response }),
+        Some(Err(())) => Ok(Response {
+            status: 416,
+            ..response.with_header("Content-Range", &format!("bytes */{length}"))
+        }),
+        Some(Ok((start, end))) => {
+            file.seek(SeekFrom::Start(start))?;
+            Ok(Response {
+                status: 206,
+                body: Body::File { file, length: end - start + 1 },
+                ..response.with_header("Content-Range", &format!("bytes {start}-{end}/{length}"))
+            })
+        },
+    }
+}
+
+/// Size and modification time change whenever the content does, near
+/// enough, and cost nothing to check compared to hashing the file.
+fn etag_for(length: u64, modified: SystemTime) -> String {
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("\"{length:x}-{:x}.{:x}\"", since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn is_not_modified(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    // If-None-Match wins when both are sent.
+    if request.header("If-None-Match").is_some() {
+        return etag_matches(request, etag);
+    }
+    let Some(since) = request.header("If-Modified-Since").and_then(|date| httpdate::parse_http_date(date).ok()) else {
+        return false;
+    };
+    // HTTP dates only have whole seconds.
+    let modified_seconds = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let since_seconds = since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    modified_seconds <= since_seconds
+}
+
+/// Whether the client's `If-None-Match` says it already has `etag`.
+pub fn etag_matches(request: &Request, etag: &str) -> bool {
+    let Some(if_none_match) = request.header("If-None-Match") else {
+        return false;
+    };
+    if_none_match.split(',').map(|tag| tag.trim().trim_start_matches("W/")).any(|tag| tag == etag || tag == "*")
+}
+
+/// The first and last byte (inclusive) asked for by a `Range` header.
+/// None means ignore the header and send everything, which is what we do
+/// for anything malformed and for multiple ranges. Err means nothing
+/// asked for exists.
+fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let is_number = |text: &str| !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit());
+
+    if start.is_empty() {
+        // bytes=-500 is the last 500 bytes.
+        if !is_number(end) {
+            return None;
+        }
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((length.saturating_sub(suffix), length - 1)));
+    }
+
+    if !is_number(start) || !(end.is_empty() || is_number(end)) {
+        return None;
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+    if end < start {
+        return None;
+    }
+    if start >= length {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.min(length - 1))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+    use crate::http::{read_request, Limits};
+    use std::io::Cursor;
+
+    /// Removed along with its folder once dropped.
+    struct TestFile {
+        path: std::path::PathBuf,
+        _scratch: ScratchDir,
+    }
+
+    impl TestFile {
+        fn new(name: &str, content: &[u8]) -> TestFile {
+            let scratch = ScratchDir::new("response");
+            TestFile { path: scratch.write(name, content), _scratch: scratch }
+        }
+    }
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /file/1 HTTP/1.1\r\n{headers}\r\n");
+        read_request(&mut Cursor::new(raw.into_bytes()), &Limits::default()).expect("test request should parse")
+    }
+
+    fn written(response: Response, head_only: bool) -> String {
+        let mut output = Vec::new();
+        response.write_to(&mut output, head_only, true).expect("write failed");
+        String::from_utf8(output).expect("test output should be utf-8")
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response.headers.iter().find(|(header_name, _)| header_name == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn ranges_are_parsed_like_rfc_9110_says() {
+        assert_eq!(parse_range("bytes=0-4", 10), Some(Ok((0, 4))));
+        assert_eq!(parse_range("bytes=5-", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=5-100", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Some(Ok((0, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-0", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=4-2", 10), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+        assert_eq!(parse_range("bytes=a-b", 10), None);
+        assert_eq!(parse_range("bytes=-", 10), None);
+    }
+
+    #[test]
+    fn head_responses_have_no_body_but_the_same_length() {
+        let get = written(Response::text(200, "hello"), false);
+        let head = written(Response::text(200, "hello"), true);
+        assert!(get.ends_with("\r\n\r\nhello"));
+        assert!(head.ends_with("\r\n\r\n"));
+        assert!(head.contains("Content-Length: 5\r\n"));
+        assert!(head.contains("Connection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn files_get_a_content_type_and_validators() {
+        let file = TestFile::new("clip.MP4", b"not really a video");
+        let response = file_response(&request(""), &file.path).expect("should serve");
+        assert_eq!(response.status, 200);
+        assert_eq!(header(&response, "Content-Type"), Some("video/mp4"));
+        assert!(header(&response, "ETag").is_some());
+        assert!(header(&response, "Last-Modified").is_some());
+        assert_eq!(response.content_length(), 18);
+        assert!(written(response, false).ends_with("not really a video"));
+    }
+
+    #[test]
+    fn scriptable_files_are_only_downloads() {
+        let file = TestFile::new("page.html", b"<script>alert(1)</script>");
+        let response = file_response(&request(""), &file.path).expect("should serve");
+        assert_eq!(header(&response, "Content-Type"), Some("application/octet-stream"));
+        assert_eq!(header(&response, "Content-Disposition"), Some("attachment"));
+        assert_eq!(header(&response, "Content-Security-Policy"), Some("sandbox"));
+        assert_eq!(header(&response, "X-Content-Type-Options"), Some("nosniff"));
+
+        let file = TestFile::new("photo.jpg", b"\xFF\xD8\xFF");
+        let response = file_response(&request(""), &file.path).expect("should serve");
+        assert_eq!(header(&response, "Content-Disposition"), None);
+        assert_eq!(header(&response, "Content-Security-Policy"), Some("sandbox"));
+    }
+
+    #[test]
+    fn magic_bytes_are_used_without_an_extension() {
+        let file = TestFile::new("scan", b"%PDF-1.4 and so on");
+        let response = file_response(&request(""), &file.path).expect("should serve");
+        assert_eq!(header(&response, "Content-Type"), Some("application/pdf"));
+        // Sniffing must not eat the start of the body.
+        assert!(written(response, false).ends_with("%PDF-1.4 and so on"));
+    }
+
+    #[test]
+    fn matching_validators_get_304() {
+        let file = TestFile::new("a.txt", b"abc");
+        let first = file_response(&request(""), &file.path).expect("should serve");
+        let etag = header(&first, "ETag").expect("etag").to_string();
+        let last_modified = header(&first, "Last-Modified").expect("last modified").to_string();
+
+        let cached = file_response(&request(&format!("If-None-Match: \"nope\", {etag}\r\n")), &file.path).expect("should serve");
+        assert_eq!(cached.status, 304);
+        assert!(!written(cached, false).contains("abc"));
+
+        let cached = file_response(&request(&format!("If-Modified-Since: {last_modified}\r\n")), &file.path).expect("should serve");
+        assert_eq!(cached.status, 304);
+
+        let stale = file_response(&request("If-None-Match: \"nope\"\r\n"), &file.path).expect("should serve");
+        assert_eq!(stale.status, 200);
+        let old = file_response(&request("If-Modified-Since: Thu, 01 Jan 1970 00:00:01 GMT\r\n"), &file.path).expect("should serve");
+        assert_eq!(old.status, 200);
+    }
+
+    #[test]
+    fn ranges_get_206_and_only_the_bytes_asked_for() {
+        let file = TestFile::new("a.txt", b"0123456789");
+        let response = file_response(&request("Range: bytes=2-4\r\n"), &file.path).expect("should serve");
+        assert_eq!(response.status, 206);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 2-4/10"));
+        let output = written(response, false);
+        assert!(output.contains("Content-Length: 3\r\n"));
+        assert!(output.ends_with("\r\n\r\n234"));
+
+        let response = file_response(&request("Range: bytes=20-\r\n"), &file.path).expect("should serve");
+        assert_eq!(response.status, 416);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes */10"));
+
+        let response = file_response(&request("Range: bytes=2-4\r\nIf-Range: \"old\"\r\n"), &file.path).expect("should serve");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_length(), 10);
+    }
+}
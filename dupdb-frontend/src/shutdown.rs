@@ -0,0 +1,101 @@
+use std::io;
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Asked for by SIGINT, SIGTERM or `POST /shutdown`. Anyone can wait on it,
+/// which is how the accept loop finds out without another client turning up.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    requested: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    pub fn request(&self) {
+        *self.lock() = true;
+        self.inner.changed.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.lock()
+    }
+
+    /// Blocks until someone asks us to stop.
+    pub fn wait(&self) {
+        let mut requested = self.lock();
+        while !*requested {
+            requested = match self.inner.changed.wait(requested) {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+    }
+
+    /// The first SIGINT or SIGTERM asks for a shutdown, a second one while
+    /// we're still draining exits straight away.
+    pub fn request_on_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        thread::Builder::new().name("dupdb-signals".to_string()).spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_requested() {
+                    eprintln!("Signal {signal} while shutting down, not waiting any longer");
+                    process::exit(1);
+                }
+                println!("Signal {signal} received, shutting down");
+                shutdown.request();
+            }
+        })?;
+        Ok(())
+    }
+
+    /// A flag is still good to read after a panic elsewhere, so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, bool> {
+        match self.inner.requested.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                self.inner.requested.clear_poison();
+                poisoned.into_inner()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn waiters_wake_when_asked() {
+        let shutdown = Shutdown::new();
+        let (sender, receiver) = mpsc::channel();
+        let waiter = shutdown.clone();
+        thread::spawn(move || {
+            waiter.wait();
+            let _ = sender.send(());
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        assert!(!shutdown.is_requested());
+
+        shutdown.request();
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(shutdown.is_requested());
+        // Anyone turning up late doesn't wait at all.
+        shutdown.wait();
+    }
+}
@@ -0,0 +1,62 @@
+//! What the tests in every module share.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use duplicate_file_monitor::DuplicateDatabase;
+
+static SCRATCH_NO: AtomicU32 = AtomicU32::new(0);
+
+/// A fresh folder for one test, gone with everything in it once dropped.
+/// Canonical, so paths built from it match what `fs::canonicalize` gives.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    /// `prefix` is only there to tell whose leftovers are whose.
+    pub fn new(prefix: &str) -> ScratchDir {
+        let scratch_no = SCRATCH_NO.fetch_add(1, Ordering::SeqCst) + 1;
+        let dir = std::env::temp_dir().join(format!("dupdb_frontend_{prefix}_{}_{scratch_no}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Could not create test dir");
+        ScratchDir(fs::canonicalize(dir).expect("Could not canonicalize test dir"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, relative: impl AsRef<Path>) -> PathBuf {
+        self.0.join(relative)
+    }
+
+    /// `join` as a string, which is how paths go into the database.
+    pub fn path_of(&self, relative: impl AsRef<Path>) -> String {
+        self.join(relative).to_string_lossy().to_string()
+    }
+
+    /// Writes a file, making any folders on the way to it.
+    pub fn write(&self, relative: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Could not create test dir");
+        }
+        fs::write(&path, contents).expect("Could not write test file");
+        path
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        self.join("test.sqlite.db")
+    }
+
+    /// The database at `database_path`, made with all its tables if it isn't there yet.
+    pub fn database(&self) -> DuplicateDatabase {
+        DuplicateDatabase::open(&self.database_path()).expect("Could not open test db")
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,305 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use image::{DynamicImage, ImageReader, Rgb, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+
+use crate::mime;
+
+/// Thumbnails are only ever made in these sizes (longest side, in pixels) so
+/// the cache can't be filled with one copy per size somebody typed in.
+pub const SIZES: [u32; 5] = [64, 128, 256, 512, 1024];
+pub const DEFAULT_SIZE: u32 = 256;
+const JPEG_QUALITY: u8 = 80;
+
+/// Tells apart the half written files of thumbnails made at the same time.
+static PARTIAL_NO: AtomicU64 = AtomicU64::new(0);
+
+/// Makes and keeps small JPEG versions of images.
+///
+/// Cached thumbnails are named after the content hash from the database,
+/// so every copy in a duplicate group shares one, and a file that changes
+/// gets a new one once the monitor rehashes it.
+#[derive(Debug, Clone)]
+pub struct Thumbnails {
+    cache_dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Thumbnail {
+    /// A JPEG on disk, ready to be served.
+    Cached(PathBuf),
+    /// An SVG icon for anything we can't decode, RAW photos and videos included.
+    Placeholder(String),
+}
+
+impl Thumbnails {
+    pub fn new(cache_dir: PathBuf) -> Self {
+        Thumbnails { cache_dir }
+    }
+
+    /// Per user, since thumbnails of private photos are as private as the
+    /// photos. Next to the monitor's database when there's no cache folder.
+    pub fn default_cache_dir() -> PathBuf {
+        let absolute = |name| env::var_os(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+        match (absolute("XDG_CACHE_HOME"), absolute("HOME")) {
+            (Some(cache), _) => cache.join("dupdb-thumbnails"),
+            (None, Some(home)) => home.join(".dupdb").join("thumbnails"),
+            // Windows, where the temp folder is already the user's own.
+            (None, None) => env::temp_dir().join("dupdb-thumbnails"),
+        }
+    }
+
+    /// Makes the cache folder if needed, readable by nobody else. One made
+    /// by someone else is refused, whoever owns it decides what we serve.
+    pub fn prepare(&self) -> io::Result<()> {
+        create_private_dir(&self.cache_dir)?;
+        check_private_dir(&self.cache_dir)
+    }
+
+    /// Never fails, anything that goes wrong gets logged and a placeholder.
+    pub fn thumbnail_for(&self, source: &Path, hash: &str, size: u32) -> Thumbnail {
+        let cached = self.cache_path(hash, size);
+        if cached.is_file() {
+            return Thumbnail::Cached(cached);
+        }
+        match self.generate(source, &cached, size) {
+            Ok(true) => Thumbnail::Cached(cached),
+            Ok(false) => Thumbnail::Placeholder(placeholder_svg(source, size)),
+            Err(error) => {
+                eprintln!("Could not make a thumbnail of {:?}: {error}", source);
+                Thumbnail::Placeholder(placeholder_svg(source, size))
+            }
+        }
+    }
+
+    fn cache_path(&self, hash: &str, size: u32) -> PathBuf {
+        // Hashes are ours, but nothing from the database goes into a path unchecked.
+        let safe_hash: String = hash.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        self.cache_dir.join(format!("{safe_hash}-{size}.jpg"))
+    }
+
+    /// False if the source isn't an image we know how to decode.
+    fn generate(&self, source: &Path, cached: &Path, size: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let reader = ImageReader::open(source)?.with_guessed_format()?;
+        if reader.format().is_none() {
+            return Ok(false);
+        }
+        // The default limits stop a crafted image from asking for gigabytes.
+        let image = match reader.decode() {
+            Ok(image) => image,
+            Err(image::ImageError::Unsupported(_)) => return Ok(false),
+            Err(error) => return Err(error.into()),
+        };
+        let thumbnail = flatten_onto_white(image.thumbnail(size, size));
+
+        // Written next to where it's going and renamed into place, so two
+        // workers making the same thumbnail can't serve each other half a file.
+        create_private_dir(&self.cache_dir)?;
+        let partial_no = PARTIAL_NO.fetch_add(1, Ordering::Relaxed);
+        let partial = cached.with_extension(format!("{}-{partial_no}.partial", std::process::id()));
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let mut writer = BufWriter::new(File::create(&partial)?);
+            JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY).encode_image(&thumbnail)?;
+            writer.into_inner().map_err(|error| error.into_error())?.sync_all()?;
+            fs::rename(&partial, cached)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result.map(|_| true)
+    }
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    match builder.create(dir) {
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        created => created,
+    }
+}
+
+/// Without libc there's no asking for our own uid, but a file we just made
+/// has it.
+#[cfg(unix)]
+fn check_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::other(format!("{} is not a folder", dir.display())));
+    }
+    let probe = dir.join(format!(".owner-{}", std::process::id()));
+    let our_uid = File::options().write(true).create_new(true).open(&probe).and_then(|probe| probe.metadata());
+    let _ = fs::remove_file(&probe);
+    let our_uid = our_uid
+        .map_err(|error| io::Error::new(error.kind(), format!("Could not tell who owns {}: {error}", dir.display())))?
+        .uid();
+    if metadata.uid() != our_uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} belongs to someone else, use another --thumbnail-dir", dir.display()),
+        ));
+    }
+    // Made by an older version, or by hand.
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private_dir(dir: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(dir)?.is_dir() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("{} is not a folder", dir.display()))),
+    }
+}
+
+/// JPEG has no transparency, and black behind a logo looks worse than white.
+fn flatten_onto_white(image: DynamicImage) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [red, green, blue, alpha] = rgba.get_pixel(x, y).0;
+        let blend = |channel: u8| ((channel as u32 * alpha as u32 + 255 * (255 - alpha as u32)) / 255) as u8;
+        Rgb([blend(red), blend(green), blend(blue)])
+    })
+}
+
+/// Rounds a requested `?size=` up to the nearest size we make.
+pub fn size_from(requested: Option<&str>) -> Result<u32, String> {
+    let Some(requested) = requested.filter(|requested| !requested.is_empty()) else {
+        return Ok(DEFAULT_SIZE);
+    };
+    let requested: u32 = requested.parse().map_err(|_| format!("size must be a number of pixels, not {requested}"))?;
+    Ok(SIZES.into_iter().find(|size| *size >= requested).unwrap_or(SIZES[SIZES.len() - 1]))
+}
+
+/// A square with the file's extension on it, coloured by what kind of file it is.
+pub fn placeholder_svg(source: &Path, size: u32) -> String {
+    let content_type = mime::from_extension(source).unwrap_or(mime::FALLBACK);
+    let colour = match content_type.split('/').next() {
+        Some("image") => "#4a90d9",
+        Some("video") => "#d9534f",
+        Some("audio") => "#9b59b6",
+        Some("text") => "#7f8c8d",
+        _ if content_type == "application/pdf" => "#e67e22",
+        _ => "#95a5a6",
+    };
+    // Only letters and digits make it into the markup, so no escaping needed.
+    let label: String = source.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("?")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(5)
+        .collect::<String>()
+        .to_uppercase();
+    let label = if label.is_empty() { "?".to_string() } else { label };
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" viewBox=\"0 0 100 100\">\
+<rect width=\"100\" height=\"100\" rx=\"8\" fill=\"{colour}\"/>\
+<text x=\"50\" y=\"58\" font-family=\"sans-serif\" font-size=\"20\" text-anchor=\"middle\" fill=\"#ffffff\">{label}</text>\
+</svg>"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+    use image::{ImageFormat, Rgba, RgbaImage};
+
+    #[test]
+    fn sizes_round_up_to_the_ones_we_make() {
+        assert_eq!(size_from(None), Ok(DEFAULT_SIZE));
+        assert_eq!(size_from(Some("")), Ok(DEFAULT_SIZE));
+        assert_eq!(size_from(Some("1")), Ok(64));
+        assert_eq!(size_from(Some("400")), Ok(512));
+        assert_eq!(size_from(Some("512")), Ok(512));
+        assert_eq!(size_from(Some("99999")), Ok(1024));
+        assert!(size_from(Some("big")).is_err());
+        assert!(size_from(Some("-5")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cache_folders_are_private() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+
+        let scratch = ScratchDir::new("thumbs");
+        let cache_dir = scratch.join("cache/thumbnails");
+        Thumbnails::new(cache_dir.clone()).prepare().expect("should be made");
+        let mode = |dir: &Path| fs::metadata(dir).expect("should exist").permissions().mode() & 0o777;
+        assert_eq!(mode(&cache_dir), 0o700);
+        assert_eq!(fs::read_dir(&cache_dir).expect("should be readable").count(), 0, "the probe should be gone");
+
+        fs::set_permissions(&cache_dir, fs::Permissions::from_mode(0o755)).expect("Could not loosen test dir");
+        Thumbnails::new(cache_dir.clone()).prepare().expect("should still be ours");
+        assert_eq!(mode(&cache_dir), 0o700);
+
+        // Nobody gets to point our thumbnails somewhere else.
+        let linked = scratch.join("linked");
+        symlink(&cache_dir, &linked).expect("Could not make test symlink");
+        assert!(Thumbnails::new(linked).prepare().is_err());
+    }
+
+    #[test]
+    fn images_are_shrunk_and_cached_by_hash() {
+        let scratch = ScratchDir::new("thumbs");
+        let source = scratch.join("photo.png");
+        RgbaImage::from_pixel(600, 300, Rgba([255, 0, 0, 128]))
+            .save_with_format(&source, ImageFormat::Png)
+            .expect("Could not write test image");
+        let thumbnails = Thumbnails::new(scratch.join("cache"));
+
+        let Thumbnail::Cached(cached) = thumbnails.thumbnail_for(&source, "123", 128) else {
+            panic!("A png should get a real thumbnail");
+        };
+        assert_eq!(cached, scratch.join("cache").join("123-128.jpg"));
+        let thumbnail = image::open(&cached).expect("thumbnail should be a readable image");
+        assert_eq!((thumbnail.width(), thumbnail.height()), (128, 64));
+
+        // Served from the cache even once the source is gone.
+        fs::remove_file(&source).expect("Could not remove source");
+        assert_eq!(thumbnails.thumbnail_for(&source, "123", 128), Thumbnail::Cached(cached));
+    }
+
+    #[test]
+    fn other_files_get_a_placeholder() {
+        let scratch = ScratchDir::new("thumbs");
+        let thumbnails = Thumbnails::new(scratch.join("cache"));
+
+        let video = scratch.join("clip.mp4");
+        fs::write(&video, b"\x00\x00\x00\x18ftypmp42 not really").expect("Could not write test file");
+        let Thumbnail::Placeholder(svg) = thumbnails.thumbnail_for(&video, "1", 256) else {
+            panic!("A video should get a placeholder");
+        };
+        assert!(svg.contains(">MP4<"));
+
+        let broken = scratch.join("broken.jpg");
+        fs::write(&broken, b"\xFF\xD8\xFF and then garbage").expect("Could not write test file");
+        assert!(matches!(thumbnails.thumbnail_for(&broken, "2", 256), Thumbnail::Placeholder(_)));
+        assert!(!scratch.join("cache").join("2-256.jpg").exists());
+    }
+
+    #[test]
+    fn placeholder_labels_are_safe() {
+        let svg = placeholder_svg(Path::new("/x/evil.<b>&"), 64);
+        assert!(!svg.contains("<b>"));
+        assert!(svg.contains(">B<"));
+        assert!(placeholder_svg(Path::new("/x/no_extension"), 64).contains(">?<"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::ServerConfig;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::cli::TlsFiles;
+
+/// Makes the self-signed certificate first if asked to and there isn't one yet.
+pub fn prepare(files: &TlsFiles) -> Result<Arc<ServerConfig>, String> {
+    if let Some(names) = &files.self_signed_names
+        && !files.cert.exists()
+        && !files.key.exists()
+    {
+        generate_self_signed(names, &files.cert, &files.key)?;
+        println!("Wrote a self-signed certificate for {} to {}", names.join(", "), files.cert.display());
+    }
+    server_config(&files.cert, &files.key)
+}
+
+/// Reads the certificate chain and key every HTTPS connection shares.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, String> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("could not read certificates from {}: {error}", cert_path.display()))?;
+    if chain.is_empty() {
+        return Err(format!("no certificates in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|error| format!("could not read a private key from {}: {error}", key_path.display()))?;
+
+    // Named outright rather than left to rustls, which refuses to guess if
+    // anything else in the build ever turns on a second provider.
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|error| format!("could not set up TLS: {error}"))?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|error| format!("{} and {} don't work together: {error}", cert_path.display(), key_path.display()))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Writes a certificate for `names` signed by its own key, good enough for
+/// reviewing duplicates from a laptop on the LAN once the browser is told to
+/// trust it. Never replaces files that are already there.
+pub fn generate_self_signed(names: &[String], cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    let generated = rcgen::generate_simple_self_signed(names.to_vec())
+        .map_err(|error| format!("could not generate a certificate: {error}"))?;
+    write_new(key_path, generated.key_pair.serialize_pem().as_bytes(), 0o600)?;
+    if let Err(error) = write_new(cert_path, generated.cert.pem().as_bytes(), 0o644) {
+        // A key without its certificate would only stop the next attempt.
+        let _ = fs::remove_file(key_path);
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn write_new(path: &Path, contents: &[u8], mode: u32) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(|error| format!("could not write {}: {error}", path.display()))
+}
+
+/// Where a request that came in over plain HTTP should go instead, keeping
+/// the host the client asked for. None when there's no usable Host header.
+pub fn https_location(host_header: Option<&str>, https_port: u16, target: &str) -> Option<String> {
+    let host_header = host_header?.trim();
+    let host = match host_header.strip_prefix('[') {
+        Some(rest) => &host_header[..rest.find(']')? + 2],
+        None => host_header.split(':').next()?,
+    };
+    // It goes straight into a Location header, so nothing that could end
+    // the host early or point somewhere else.
+    let valid = !host.is_empty()
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '[' | ']' | ':'));
+    if !valid {
+        return None;
+    }
+    let target = if target.starts_with('/') { target } else { "/" };
+    match https_port {
+        443 => Some(format!("https://{host}{target}")),
+        port => Some(format!("https://{host}:{port}{target}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ScratchDir;
+
+    #[test]
+    fn self_signed_certificates_load() {
+        let scratch = ScratchDir::new("tls");
+        let (cert, key) = (scratch.join("cert.pem"), scratch.join("key.pem"));
+        generate_self_signed(&["localhost".to_string()], &cert, &key).expect("Could not generate");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&key).expect("key should exist").permissions().mode() & 0o777, 0o600);
+        }
+        assert!(server_config(&cert, &key).is_ok());
+
+        // Existing files are left alone.
+        assert!(generate_self_signed(&["localhost".to_string()], &cert, &key).is_err());
+        assert!(server_config(&cert, &key).is_ok());
+    }
+
+    #[test]
+    fn bad_files_are_explained() {
+        let scratch = ScratchDir::new("tls");
+        let (cert, key) = (scratch.join("cert.pem"), scratch.join("key.pem"));
+        assert!(server_config(&cert, &key).unwrap_err().contains("cert.pem"));
+        fs::write(&cert, "not a certificate").expect("Could not write test file");
+        assert!(server_config(&cert, &key).unwrap_err().contains("no certificates"));
+    }
+
+    #[test]
+    fn redirects_keep_the_host_and_path() {
+        assert_eq!(
+            https_location(Some("nas.local:8080"), 6969, "/duplicates?x=1"),
+            Some("https://nas.local:6969/duplicates?x=1".to_string())
+        );
+        assert_eq!(https_location(Some("192.168.1.5"), 443, "/"), Some("https://192.168.1.5/".to_string()));
+        assert_eq!(https_location(Some("[::1]:80"), 6969, "/"), Some("https://[::1]:6969/".to_string()));
+        assert_eq!(https_location(Some("nas"), 6969, "*"), Some("https://nas:6969/".to_string()));
+        assert_eq!(https_location(None, 6969, "/"), None);
+        assert_eq!(https_location(Some("evil.com/x?"), 6969, "/"), None);
+        assert_eq!(https_location(Some("a\r\nSet-Cookie: x"), 6969, "/"), None);
+    }
+}
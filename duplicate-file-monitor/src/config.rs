@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::sql;
+
+pub const CONFIG_FILE: &str = "dupdb.conf";
+pub const DEFAULT_STATUS_PORT: u16 = 6970;
+
+/// Settings read from the dupdb.conf file that lives next to the database.
+///
+/// The file is plain `key = value` lines, blank lines and lines starting
+/// with `#` are ignored. `watch` may be given more than once:
+///
+/// ```text
+/// # folders to keep an eye on
+/// watch = /home/me/Pictures
+/// watch = /home/me/Downloads
+/// status_port = 6970
+/// # forget file history older than this, leave it out to keep it forever
+/// event_retention_days = 365
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct DupdbConfig {
+    pub watch_roots: Vec<PathBuf>,
+    /// Localhost port the daemon answers `status` requests on.
+    pub status_port: u16,
+    pub event_retention_days: Option<u64>,
+}
+
+impl Default for DupdbConfig {
+    fn default() -> Self {
+        DupdbConfig {
+            watch_roots: Vec::new(),
+            status_port: DEFAULT_STATUS_PORT,
+            event_retention_days: None,
+        }
+    }
+}
+
+pub fn dupdb_config_path() -> PathBuf {
+    sql::dupdb_database_path().with_file_name(CONFIG_FILE)
+}
+
+/// Loads the config file if one exists. A missing file is not an error,
+/// you just get the defaults.
+pub fn dupdb_config_load() -> DupdbConfig {
+    dupdb_config_load_from(&dupdb_config_path())
+}
+
+pub fn dupdb_config_load_from(path: &Path) -> DupdbConfig {
+    match fs::read_to_string(path) {
+        Ok(contents) => dupdb_config_parse(&contents),
+        Err(_) => DupdbConfig::default(),
+    }
+}
+
+pub fn dupdb_config_parse(contents: &str) -> DupdbConfig {
+    let mut config = DupdbConfig::default();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            eprintln!("Ignoring line {} of {CONFIG_FILE}, expected key = value: {line}", line_number + 1);
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "watch" => config.watch_roots.push(PathBuf::from(value)),
+            "status_port" => match value.parse() {
+                Ok(port) => config.status_port = port,
+                Err(error) => eprintln!("Ignoring status_port in {CONFIG_FILE}, {value} is not a port: {error}"),
+            },
+            "event_retention_days" => match value.parse() {
+                Ok(days) => config.event_retention_days = Some(days),
+                Err(error) => eprintln!("Ignoring event_retention_days in {CONFIG_FILE}, {value} is not a number of days: {error}"),
+            },
+            unknown => eprintln!("Ignoring unknown key in {CONFIG_FILE}: {unknown}"),
+        }
+    }
+    config
+}
+
+/// The folders to watch are whatever the config file says, plus the folder
+/// given on the command line. If neither says anything we fall back to ./test
+pub fn dupdb_watch_roots(config: &DupdbConfig, cli_root: Option<&Path>) -> Vec<PathBuf> {
+    let mut roots = config.watch_roots.clone();
+    if let Some(cli_root) = cli_root {
+        roots.push(cli_root.to_path_buf());
+    }
+    if roots.is_empty() {
+        roots.push(PathBuf::from("./test"));
+    }
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_watch_roots_and_skips_comments() {
+        let config = dupdb_config_parse("
+            # comment
+            watch = /a/b
+
+            watch=/c
+            nonsense line
+        ");
+        assert_eq!(config.watch_roots, vec![PathBuf::from("/a/b"), PathBuf::from("/c")]);
+    }
+
+    #[test]
+    fn bad_status_port_keeps_the_default() {
+        let config = dupdb_config_parse("status_port = nope");
+        assert_eq!(config.status_port, DEFAULT_STATUS_PORT);
+        let config = dupdb_config_parse("status_port = 7000");
+        assert_eq!(config.status_port, 7000);
+    }
+
+    #[test]
+    fn cli_root_is_added_to_config_roots() {
+        let config = dupdb_config_parse("watch = /a");
+        let roots = dupdb_watch_roots(&config, Some(Path::new("/b")));
+        assert_eq!(roots, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+    }
+
+    #[test]
+    fn no_roots_anywhere_falls_back_to_test_folder() {
+        let roots = dupdb_watch_roots(&DupdbConfig::default(), None);
+        assert_eq!(roots, vec![PathBuf::from("./test")]);
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sql;
+
+pub const PID_FILE: &str = "dupdb.pid";
+
+pub fn dupdb_pid_file_path() -> PathBuf {
+    sql::dupdb_database_path().with_file_name(PID_FILE)
+}
+
+/// An exclusive lock on the pid file next to the database. Only one monitor
+/// can hold it at a time so two of them can't write the same database.
+///
+/// The OS drops the lock when the process dies, so a pid file left behind by
+/// a crash doesn't keep the next monitor from starting.
+#[derive(Debug)]
+pub struct PidLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl PidLock {
+    pub fn acquire(path: &Path) -> io::Result<PidLock> {
+        loop {
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+            match file.try_lock() {
+                Ok(_) => {},
+                Err(TryLockError::WouldBlock) => {
+                    let holder = fs::read_to_string(path).unwrap_or_default();
+                    let holder = holder.trim();
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("Another monitor (pid {holder}) already holds {:?}", path),
+                    ));
+                },
+                Err(TryLockError::Error(error)) => return Err(error),
+            }
+            // The monitor before us may have removed the file between us
+            // opening and locking it. A lock on a file nobody else can find
+            // keeps nobody out, so start over with whatever is there now.
+            if !is_still_at(&file, path)? {
+                continue;
+            }
+
+            file.set_len(0)?;
+            writeln!(file, "{}", std::process::id())?;
+            file.sync_all()?;
+            return Ok(PidLock { file, path: path.to_path_buf() });
+        }
+    }
+}
+
+/// Whether `file` is still the one found at `path`.
+#[cfg(unix)]
+fn is_still_at(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let current = match fs::metadata(path) {
+        Ok(current) => current,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error),
+    };
+    let opened = file.metadata()?;
+    Ok(opened.dev() == current.dev() && opened.ino() == current.ino())
+}
+
+/// Files that are open can't be removed here, so an open one is still there.
+#[cfg(not(unix))]
+fn is_still_at(_file: &File, path: &Path) -> io::Result<bool> {
+    Ok(path.exists())
+}
+
+impl Drop for PidLock {
+    fn drop(&mut self) {
+        // Remove before unlocking. Anyone who opened it before then gets the
+        // lock on a file that's gone, which `acquire` notices.
+        if let Err(error) = fs::remove_file(&self.path) {
+            eprintln!("Could not remove pid file {:?}: {:?}", self.path, error);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::TestDir;
+
+    #[test]
+    fn second_lock_on_the_same_file_is_refused() {
+        let dir = TestDir::new("daemon");
+        let path = dir.join(PID_FILE);
+
+        let first = PidLock::acquire(&path).expect("First lock should succeed");
+        let written_pid = fs::read_to_string(&path).expect("Pid file should exist");
+        assert_eq!(written_pid.trim(), std::process::id().to_string());
+
+        let second = PidLock::acquire(&path);
+        assert_eq!(second.expect_err("Second lock should fail").kind(), io::ErrorKind::AlreadyExists);
+
+        drop(first);
+        assert!(!path.exists());
+        let third = PidLock::acquire(&path);
+        assert!(third.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn locks_on_a_removed_pid_file_are_noticed() {
+        let dir = TestDir::new("daemon");
+        let path = dir.join(PID_FILE);
+        fs::write(&path, "").expect("Could not write pid file");
+        let opened_early = File::open(&path).expect("Could not open pid file");
+        assert!(is_still_at(&opened_early, &path).expect("Could not compare"));
+
+        fs::remove_file(&path).expect("Could not remove pid file");
+        assert!(!is_still_at(&opened_early, &path).expect("Could not compare"));
+        // Somebody else's new pid file at the same path is not ours either.
+        let lock = PidLock::acquire(&path).expect("Lock should succeed");
+        assert!(!is_still_at(&opened_early, &path).expect("Could not compare"));
+        drop(lock);
+    }
+}
@@ -1,34 +1,336 @@
-pub use std::env;
 use std::path::{self, Path, PathBuf };
 use std::fs::{ self };
+use std::io;
 use std::time::Duration;
-use std::time::Instant;
+use std::time::{Instant, UNIX_EPOCH};
+use std::process::ExitCode;
 
-use notify::{self, RecursiveMode, EventKind};
-use notify_debouncer_full::new_debouncer;
+use notify::{self, RecursiveMode, EventKind, Watcher};
+use notify::event::{ModifyKind, RenameMode};
+use notify_debouncer_full::{new_debouncer, DebounceEventResult, Debouncer, FileIdCache};
 
-use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
 
 use notify_rust::Notification;
 
 use nav_update::RecursiveDirIterator;
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
 
 const APPNAME: &str = "Dup DB";
 
 use crate::sql;
-
+use crate::query::{self, GroupFile, GroupQuery, GroupPage, GroupSummary};
+use crate::history::{self, FileEvent, FileEventKind};
+use crate::overlap::{self, DirectoryOverlap, OverlapQuery};
+use crate::signals::WatchSignals;
+use crate::status::WatchStatus;
+
+/// The dupdb sqlite database. This is the only thing that should know what
+/// the tables look like, the monitor writes through it and the frontend
+/// reads through it.
 #[derive(Debug)]
 pub struct DuplicateDatabase {
-    conn: Connection
+    conn: Connection,
+    in_batch: bool,
+    event_retention_days: Option<u64>,
+}
+
+/// Every path that shares one hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DuplicateStats {
+    pub files_indexed: u64,
+    pub distinct_hashes: u64,
+    pub duplicate_groups: u64,
+    pub duplicate_files: u64,
 }
 
 impl DuplicateDatabase {
-    pub fn add(&mut self, hash: u64, full_file_path: String) {
-        let entered = sql::insert_file_hash(&self.conn, hash, &full_file_path);
+    /// Opens (creating if needed) the database at the given path for reading and writing.
+    pub fn open(path: &Path) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        sql::initialize(&connection);
+        Ok(DuplicateDatabase {
+            conn: connection,
+            in_batch: false,
+            event_retention_days: None,
+        })
+    }
+
+    /// Opens an existing database without any way to write to it.
+    pub fn open_read_only(path: &Path) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY |
+            OpenFlags::SQLITE_OPEN_NO_MUTEX  |
+            OpenFlags::SQLITE_OPEN_URI
+        )?;
+        Ok(DuplicateDatabase {
+            conn: connection,
+            in_batch: false,
+            event_retention_days: None,
+        })
+    }
+
+    /// Groups every write until `commit_batch` into one transaction so a
+    /// batch of events lands all at once or not at all.
+    pub fn begin_batch(&mut self) {
+        if self.in_batch {
+            return;
+        }
+        match sql::begin_transaction(&self.conn) {
+            Ok(_) => self.in_batch = true,
+            Err(error) => eprintln!("Could not begin transaction, writing without one: {}", error),
+        }
+    }
+
+    pub fn commit_batch(&mut self) -> bool {
+        if !self.in_batch {
+            return true;
+        }
+        self.in_batch = false;
+        match sql::commit_transaction(&self.conn) {
+            Ok(_) => true,
+            Err(error) => {
+                eprintln!("Could not commit transaction, rolling back: {}", error);
+                self.rollback_batch();
+                false
+            }
+        }
+    }
+
+    pub fn rollback_batch(&mut self) {
+        self.in_batch = false;
+        if let Err(error) = sql::rollback_transaction(&self.conn) {
+            eprintln!("Could not roll back transaction: {}", error);
+        }
+    }
+
+    /// Returns false if the row could not be written.
+    pub fn add(&mut self, hash: u64, full_file_path: String) -> bool {
+        self.add_with_metadata(hash, full_file_path, None, None)
+    }
+
+    /// Same as `add`, also keeping the size in bytes and the modification
+    /// time in unix seconds so groups can be filtered and sorted by them.
+    ///
+    /// A path we already know with the same hash only gets its metadata
+    /// refreshed. A path we know with a different hash has its row updated,
+    /// keeping its id, and is recorded as modified, a new path as created.
+    pub fn add_with_metadata(&mut self, hash: u64, full_file_path: String, size: Option<u64>, modified: Option<u64>) -> bool {
+        let hash_text = hash.to_string();
+        let known_hashes = match sql::hashes_for_file(&self.conn, &full_file_path) {
+            Ok(hashes) => hashes,
+            Err(error) => {
+                eprintln!("Could not look up existing hashes for {}: {}", full_file_path, error);
+                return false;
+            }
+        };
+
+        if known_hashes == [hash_text.clone()] {
+            if let Err(error) = sql::update_file_metadata(&self.conn, &full_file_path, size, modified) {
+                eprintln!("Could not update metadata for {}: {}", full_file_path, error);
+                return false;
+            }
+            return true;
+        }
+
+        let entered = if known_hashes.is_empty() {
+            sql::insert_file_hash_with_metadata(&self.conn, hash, &full_file_path, size, modified)
+        } else {
+            // In place, so the file keeps the id it was handed out with.
+            match sql::update_file_hash(&self.conn, hash, &full_file_path, size, modified) {
+                Ok(updated) => updated > 0,
+                Err(error) => {
+                    eprintln!("Could not replace old hashes for {}: {}", full_file_path, error);
+                    false
+                }
+            }
+        };
         if !entered {
             eprintln!("Did not enter file path and hash into database: {}, {}", hash, full_file_path);
+            return false;
+        }
+
+        let (kind, previous_hash) = match known_hashes.first() {
+            None => (FileEventKind::Created, None),
+            Some(previous_hash) => (FileEventKind::Modified, Some(previous_hash.as_str())),
+        };
+        self.record_event(kind, &full_file_path, Some(&hash_text), None, previous_hash)
+    }
+
+    /// Moves every row for `from` over to `to` and records the rename.
+    /// Returns false if nothing was known at `from` or the update failed.
+    pub fn rename(&mut self, from_full_file_path: &str, to_full_file_path: &str) -> bool {
+        let hashes = sql::hashes_for_file(&self.conn, from_full_file_path).unwrap_or_default();
+        if hashes.is_empty() {
+            return false;
+        }
+        // Whatever used to be at the destination has been replaced.
+        if let Err(error) = sql::delete_file(&self.conn, to_full_file_path) {
+            eprintln!("Could not clear {} for rename: {}", to_full_file_path, error);
+            return false;
+        }
+        if let Err(error) = sql::rename_file(&self.conn, from_full_file_path, to_full_file_path) {
+            eprintln!("Could not rename {} to {}: {}", from_full_file_path, to_full_file_path, error);
+            return false;
+        }
+        self.record_event(FileEventKind::Renamed, to_full_file_path, hashes.first().map(String::as_str), Some(from_full_file_path), None)
+    }
+
+    fn record_event(&self, kind: FileEventKind, path: &str, hash: Option<&str>, previous_path: Option<&str>, previous_hash: Option<&str>) -> bool {
+        match history::record_event(&self.conn, kind, path, hash, previous_path, previous_hash) {
+            Ok(_) => true,
+            Err(error) => {
+                eprintln!("Could not record {} event for {}: {}", kind, path, error);
+                false
+            }
+        }
+    }
+
+    /// Everything that has happened at a path, oldest first.
+    pub fn timeline_for_path(&self, full_file_path: &str) -> Result<Vec<FileEvent>, rusqlite::Error> {
+        history::timeline_for_path(&self.conn, full_file_path)
+    }
+
+    /// Everything that has happened to files with this hash, oldest first.
+    pub fn timeline_for_hash(&self, hash: &str) -> Result<Vec<FileEvent>, rusqlite::Error> {
+        history::timeline_for_hash(&self.conn, hash)
+    }
+
+    /// Events recorded after `after_id`, oldest first, at most `limit` of them.
+    pub fn events_since(&self, after_id: i64, limit: usize) -> Result<Vec<FileEvent>, rusqlite::Error> {
+        history::events_since(&self.conn, after_id, limit)
+    }
+
+    pub fn latest_event_id(&self) -> Result<i64, rusqlite::Error> {
+        history::latest_event_id(&self.conn)
+    }
+
+    /// Changes whenever another connection commits, which makes it a cheap
+    /// way for a reader to notice the monitor has been writing.
+    pub fn data_version(&self) -> Result<i64, rusqlite::Error> {
+        sql::data_version(&self.conn)
+    }
+
+    /// How long a query waits for another connection's lock before failing
+    /// with SQLITE_BUSY.
+    pub fn set_busy_timeout(&self, timeout: Duration) -> Result<(), rusqlite::Error> {
+        self.conn.busy_timeout(timeout)
+    }
+
+    /// How many prepared statements this connection keeps for reuse. Only
+    /// worth raising for connections that live across many queries.
+    pub fn set_statement_cache_capacity(&self, capacity: usize) {
+        self.conn.set_prepared_statement_cache_capacity(capacity);
+    }
+
+    /// How many days of events `prune_events` keeps, None keeps them forever.
+    pub fn set_event_retention_days(&mut self, days: Option<u64>) {
+        self.event_retention_days = days;
+    }
+
+    /// Drops events older than the retention period, returns how many went.
+    pub fn prune_events(&mut self) -> usize {
+        let Some(days) = self.event_retention_days else {
+            return 0;
+        };
+        let cutoff = history::unix_now().saturating_sub(days.saturating_mul(24 * 60 * 60));
+        match history::prune_events_before(&self.conn, cutoff) {
+            Ok(pruned) => pruned,
+            Err(error) => {
+                eprintln!("Could not prune old events: {}", error);
+                0
+            }
+        }
+    }
+
+    pub fn indexed_file_count(&self) -> u64 {
+        sql::count_of_files(&self.conn)
+    }
+
+    /// Flat (hash, path) pairs for every path that has a duplicate, ordered by hash.
+    pub fn duplicates(&self) -> Result<Vec<(String, String)>, rusqlite::Error> {
+        sql::all_dups(&self.conn)
+    }
+
+    /// Just the hashes of every duplicate group.
+    pub fn duplicate_hashes(&self) -> Result<Vec<String>, rusqlite::Error> {
+        sql::duplicate_hashes(&self.conn)
+    }
+
+    /// Same as `duplicates` but with the paths gathered up under their hash.
+    pub fn duplicate_groups(&self) -> Result<Vec<DuplicateGroup>, rusqlite::Error> {
+        let mut groups: Vec<DuplicateGroup> = Vec::new();
+        for (hash, path) in self.duplicates()? {
+            match groups.last_mut() {
+                Some(group) if group.hash == hash => group.paths.push(path),
+                _ => groups.push(DuplicateGroup { hash, paths: vec![path] }),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// One page of groups matching the query's filters, in the query's order.
+    pub fn query_groups(&self, query: &GroupQuery) -> Result<GroupPage, rusqlite::Error> {
+        query::duplicate_groups(&self.conn, query)
+    }
+
+    /// Like `group_for_hash` but with the sizes and ids `query_groups` gives.
+    pub fn group_summary(&self, hash: &str) -> Result<Option<GroupSummary>, rusqlite::Error> {
+        query::group_summary(&self.conn, hash)
+    }
+
+    /// The hash and metadata of the file behind an id.
+    pub fn file_for_id(&self, id: i64) -> Result<Option<(String, GroupFile)>, rusqlite::Error> {
+        query::file_for_id(&self.conn, id)
+    }
+
+    /// Every file with this hash, for asking whether something is already
+    /// stored before saving another copy of it.
+    pub fn files_for_hash(&self, hash: &str) -> Result<Vec<GroupFile>, rusqlite::Error> {
+        query::files_for_hash(&self.conn, hash)
+    }
+
+    /// Folders sharing the most contents, see `overlap::directory_overlaps`.
+    pub fn directory_overlaps(&self, query: &OverlapQuery) -> Result<Vec<DirectoryOverlap>, rusqlite::Error> {
+        overlap::directory_overlaps(&self.conn, query)
+    }
+
+    /// The group for one hash, or None if nothing else shares it.
+    pub fn group_for_hash(&self, hash: &str) -> Result<Option<DuplicateGroup>, rusqlite::Error> {
+        let paths = sql::paths_for_hash(&self.conn, hash)?;
+        if paths.len() < 2 {
+            return Ok(None);
         }
+        Ok(Some(DuplicateGroup { hash: hash.to_string(), paths }))
+    }
+
+    /// The path behind a file id handed out in a `GroupFile`.
+    pub fn path_for_file_id(&self, id: i64) -> Result<Option<String>, rusqlite::Error> {
+        sql::path_for_id(&self.conn, id)
+    }
+
+    /// The id to hand out for a path, None if the path isn't indexed.
+    pub fn file_id_for_path(&self, full_file_path: &str) -> Result<Option<i64>, rusqlite::Error> {
+        sql::id_for_path(&self.conn, full_file_path)
+    }
+
+    /// Every (hash, path) pair that shares a hash with the given file, including itself.
+    pub fn dups_by_file(&self, full_file_path: &str) -> Vec<(String, String)> {
+        sql::dups_by_file(&self.conn, full_file_path)
+    }
+
+    pub fn stats(&self) -> Result<DuplicateStats, rusqlite::Error> {
+        let (files_indexed, distinct_hashes, duplicate_groups, duplicate_files) = sql::stats(&self.conn)?;
+        Ok(DuplicateStats { files_indexed, distinct_hashes, duplicate_groups, duplicate_files })
     }
 
     pub fn contains_duplicate_for_hash(&self, hash: u64) -> bool {
@@ -37,12 +339,12 @@ impl DuplicateDatabase {
     }
 
     pub fn remove(&mut self, full_file_path: String) {
-        // TODO just replace with a plain DELETE query in sql and save the effort
-        let references = sql::dups_by_file(&self.conn, &full_file_path);
-        let to_remove: Vec<(String, String)> = references.into_iter().filter(|(_, file_path)| *file_path == full_file_path).collect();
-        for (hash, filepath) in to_remove {
+        let hashes = sql::hashes_for_file(&self.conn, &full_file_path).unwrap_or_default();
+        for hash in hashes {
             let numeric_hash = hash.parse().expect("Hash stored in database was not parseable to u64");
-            sql::delete_all_matching(&self.conn, numeric_hash, &filepath);
+            if sql::delete_all_matching(&self.conn, numeric_hash, &full_file_path) > 0 {
+                self.record_event(FileEventKind::Removed, &full_file_path, Some(&hash), None, None);
+            }
         }
     }
 
@@ -59,6 +361,19 @@ impl DuplicateDatabase {
     }
 }
 
+impl Drop for DuplicateDatabase {
+    fn drop(&mut self) {
+        if self.in_batch {
+            eprintln!("Database closed in the middle of a batch, rolling it back");
+            self.rollback_batch();
+        }
+    }
+}
+
+pub fn dupdb_database_path_exists() -> bool {
+    sql::dupdb_database_path().exists()
+}
+
 /// Returns true if new index was created, false otherwise
 pub fn dupdb_initialize_hidden_folder() -> bool {
     let database_exists_already = sql::dupdb_database_path().exists();
@@ -71,11 +386,17 @@ pub fn dupdb_initialize_hidden_folder() -> bool {
 }
 
 
-pub fn dupdb_reset_database_from_existing_files(path: PathBuf, duplicate_database: &mut DuplicateDatabase) {
-    println!("Reseting database according to files within {:?}", path);
+pub fn dupdb_reset_database_from_existing_files(paths: &[PathBuf], duplicate_database: &mut DuplicateDatabase) {
+    println!("Reseting database according to files within {:?}", paths);
     sql::reset_all_data(&duplicate_database.conn);
 
-    let entries = RecursiveDirIterator::new(&path).expect("Could not load path to reindex database");
+    for path in paths {
+        dupdb_index_existing_files(path, duplicate_database);
+    }
+}
+
+pub fn dupdb_index_existing_files(path: &Path, duplicate_database: &mut DuplicateDatabase) {
+    let entries = RecursiveDirIterator::new(path).expect("Could not load path to reindex database");
     let paths = entries
         .filter(|dir_entry| dir_entry.path().extension().is_some()) // Remove directories, keep files only.
         .map(|file| file.path())
@@ -87,60 +408,220 @@ pub fn dupdb_database_load_to_memory() -> DuplicateDatabase {
     let connection = sql::connect_to_sqlite().expect("Unable to connect to sqlite database");
     sql::initialize(&connection);
     DuplicateDatabase {
-        conn: connection
+        conn: connection,
+        in_batch: false,
+        event_retention_days: None,
     }
 }
 
-pub fn dupdb_watch_forever(watch_folder_path: &Path, duplicate_database: &mut DuplicateDatabase) {
+/// How long the debouncer holds on to events before handing them to us.
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the watch loop wakes up to check for signals when nothing is happening.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often old events are pruned while watching.
+const EVENT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What happened while writing one batch of paths to the database.
+#[derive(Debug, Default, PartialEq)]
+pub struct BatchReport {
+    pub paths: usize,
+    pub errors: u64,
+    pub committed: bool,
+}
+
+/// Watches the given roots until a shutdown is requested through `signals`.
+///
+/// A reload request swaps the watched folders for whatever `reload_config`
+/// returns at that moment, it may also update any settings on the database.
+/// On shutdown whatever the debouncer is still
+/// holding is given a chance to arrive and get written before we stop.
+/// Progress is kept up to date in `status` as batches come through.
+pub fn dupdb_watch_forever<F>(
+    watch_roots: Vec<PathBuf>,
+    mut reload_config: F,
+    signals: &WatchSignals,
+    status: &Arc<WatchStatus>,
+    duplicate_database: &mut DuplicateDatabase,
+) -> ExitCode
+where F: FnMut(&mut DuplicateDatabase) -> Vec<PathBuf>,
+{
     let (tx, rx) = mpsc::channel();
 
-    let mut debouncer = new_debouncer(Duration::from_secs(1), None, tx).expect("Failed to configure debouncer");
-    debouncer.watch(watch_folder_path, RecursiveMode::Recursive).expect("Failed to begin file watch");
-    for result in rx {
-        match result {
-            Ok(debounced_events) => {
-                let right_now = Instant::now();
-                let mut paths_and_seconds: Vec<(PathBuf, u64)> = debounced_events.into_iter().filter_map(|event| {
-                    let timestamp = event.time;
-                    let maybe_paths: Option<Vec<PathBuf>> = match event.kind {
-                        EventKind::Remove(_) => Some(event.paths.clone()),
-                        EventKind::Create(_) => Some(event.paths.clone()),
-                        EventKind::Modify(_) => Some(event.paths.clone()), // windows on cut does create+remove, modify gets sent way too much
-                        EventKind::Any => Some(event.paths.clone()),
-                        EventKind::Access(_) => None,
-                        EventKind::Other => None,
-                    };
-
-                    if maybe_paths.is_none() {
-                        return None;
-                    }
+    let queue_status = Arc::clone(status);
+    let event_handler = move |result: DebounceEventResult| {
+        if let Ok(events) = &result {
+            queue_status.events_queued(events.len());
+        }
+        let _ = tx.send(result);
+    };
 
-                    let paths = maybe_paths.unwrap();
+    let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, None, event_handler).expect("Failed to configure debouncer");
+    status.set_files_indexed(duplicate_database.indexed_file_count());
+    let mut watched_roots = Vec::new();
+    dupdb_update_watched_roots(&mut debouncer, &mut watched_roots, watch_roots, false, duplicate_database);
+
+    let mut clean_exit = true;
+    let mut last_prune = Instant::now();
+    duplicate_database.prune_events();
+    loop {
+        if signals.shutdown_requested() {
+            println!("Shutdown requested, finishing up pending events");
+            break;
+        }
+
+        if signals.take_reload_request() {
+            println!("Reloading configuration");
+            let new_roots = reload_config(duplicate_database);
+            dupdb_update_watched_roots(&mut debouncer, &mut watched_roots, new_roots, true, duplicate_database);
+            status.set_files_indexed(duplicate_database.indexed_file_count());
+            duplicate_database.prune_events();
+        }
+
+        if last_prune.elapsed() >= EVENT_PRUNE_INTERVAL {
+            duplicate_database.prune_events();
+            last_prune = Instant::now();
+        }
 
-                    // No time travel please.
-                    assert!(timestamp < right_now);
-                    let rounded_seconds = right_now.saturating_duration_since(timestamp).as_secs();
+        match rx.recv_timeout(SIGNAL_POLL_INTERVAL) {
+            Ok(result) => clean_exit &= dupdb_handle_debounced(result, status, duplicate_database),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                eprintln!("File watcher hung up unexpectedly");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
 
-                    Some(paths.into_iter().map(|p| (p, rounded_seconds)).collect::<Vec<(PathBuf, u64)>>())
-                }).flatten().collect();
-                paths_and_seconds.sort_by_key(|(p, _)| p.clone());
-                // Now filter out any events for the same path that are too close to each otehr
-                paths_and_seconds.dedup();
-                let paths: Vec<PathBuf> = paths_and_seconds.into_iter().map(|(p, _)| p).collect();
-                dupdb_update_hashes_for(paths, duplicate_database);
+    // The debouncer drops anything it hasn't emitted yet when it's stopped,
+    // so wait out one more debounce window before stopping it.
+    let flush_deadline = Instant::now() + DEBOUNCE_TIMEOUT + SIGNAL_POLL_INTERVAL;
+    while let Some(remaining) = flush_deadline.checked_duration_since(Instant::now()) {
+        match rx.recv_timeout(remaining) {
+            Ok(result) => clean_exit &= dupdb_handle_debounced(result, status, duplicate_database),
+            Err(_) => break,
+        }
+    }
+    debouncer.stop();
+    for result in rx.try_iter() {
+        clean_exit &= dupdb_handle_debounced(result, status, duplicate_database);
+    }
+
+    println!("Stopped watching {:?}", watched_roots);
+    if clean_exit {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Watches any root in `new_roots` that we aren't already watching and stops
+/// watching the ones that are gone. When `index_new_roots` is set the files
+/// already sitting in a newly watched root are hashed too.
+fn dupdb_update_watched_roots<W, C>(
+    debouncer: &mut Debouncer<W, C>,
+    watched_roots: &mut Vec<PathBuf>,
+    new_roots: Vec<PathBuf>,
+    index_new_roots: bool,
+    duplicate_database: &mut DuplicateDatabase,
+) where W: Watcher, C: FileIdCache,
+{
+    let (kept, removed): (Vec<PathBuf>, Vec<PathBuf>) = watched_roots
+        .drain(..)
+        .partition(|root| new_roots.contains(root));
+    *watched_roots = kept;
+
+    for root in removed {
+        match debouncer.unwatch(&root) {
+            Ok(_) => println!("Stopped watching {:?}", root),
+            Err(error) => eprintln!("Could not stop watching {:?}: {:?}", root, error),
+        }
+    }
+
+    for root in new_roots {
+        if watched_roots.contains(&root) {
+            continue;
+        }
+        match debouncer.watch(&root, RecursiveMode::Recursive) {
+            Ok(_) => {
+                println!("Watching {:?}", root);
+                if index_new_roots {
+                    dupdb_index_existing_files(&root, duplicate_database);
+                }
+                watched_roots.push(root);
             },
-            Err(error) => eprintln!("Watch error: {:?}", error),
+            Err(error) => eprintln!("Failed to begin file watch on {:?}: {:?}", root, error),
         }
     }
 }
 
-pub fn dupdb_update_hashes_for(paths: Vec<PathBuf>, duplicate_database: &mut DuplicateDatabase) {
+/// Returns false if the batch could not be written to the database.
+fn dupdb_handle_debounced(result: DebounceEventResult, status: &WatchStatus, duplicate_database: &mut DuplicateDatabase) -> bool {
+    match result {
+        Ok(debounced_events) => {
+            let event_count = debounced_events.len();
+            status.record_event();
+            let right_now = Instant::now();
+            let renames: Vec<(PathBuf, PathBuf)> = debounced_events.iter()
+                .filter(|event| matches!(event.kind, EventKind::Modify(ModifyKind::Name(RenameMode::Both))))
+                .filter(|event| event.paths.len() == 2)
+                .map(|event| (event.paths[0].clone(), event.paths[1].clone()))
+                .collect();
+            let mut paths_and_seconds: Vec<(PathBuf, u64)> = debounced_events.into_iter().filter_map(|event| {
+                let timestamp = event.time;
+                let maybe_paths: Option<Vec<PathBuf>> = match event.kind {
+                    EventKind::Remove(_) => Some(event.paths.clone()),
+                    EventKind::Create(_) => Some(event.paths.clone()),
+                    EventKind::Modify(_) => Some(event.paths.clone()), // windows on cut does create+remove, modify gets sent way too much
+                    EventKind::Any => Some(event.paths.clone()),
+                    EventKind::Access(_) => None,
+                    EventKind::Other => None,
+                };
+
+                let paths = maybe_paths?;
+
+                // No time travel please.
+                assert!(timestamp < right_now);
+                let rounded_seconds = right_now.saturating_duration_since(timestamp).as_secs();
+
+                Some(paths.into_iter().map(|p| (p, rounded_seconds)).collect::<Vec<(PathBuf, u64)>>())
+            }).flatten().collect();
+            paths_and_seconds.sort_by_key(|(p, _)| p.clone());
+            // Now filter out any events for the same path that are too close to each otehr
+            paths_and_seconds.dedup();
+            let paths: Vec<PathBuf> = paths_and_seconds.into_iter().map(|(p, _)| p).collect();
+
+            // Renames go in the same transaction as the hashing. Once they're
+            // moved over the old path is simply gone and the new one already
+            // has the right hash, so neither shows up as removed or created.
+            duplicate_database.begin_batch();
+            for (from, to) in renames {
+                duplicate_database.rename(&dupdb_absolute_path(&from), &dupdb_absolute_path(&to));
+            }
+            let report = dupdb_update_hashes_for(paths, duplicate_database);
+            status.events_handled(event_count);
+            status.record_errors(report.errors);
+            status.set_files_indexed(duplicate_database.indexed_file_count());
+            report.committed
+        },
+        Err(errors) => {
+            eprintln!("Watch error: {:?}", errors);
+            status.record_errors(errors.len() as u64);
+            true
+        },
+    }
+}
+
+/// Hashes the given paths into the database as a single transaction and
+/// sends a notification for any duplicates found once it's committed.
+pub fn dupdb_update_hashes_for(paths: Vec<PathBuf>, duplicate_database: &mut DuplicateDatabase) -> BatchReport {
+    let mut report = BatchReport { paths: paths.len(), ..BatchReport::default() };
     let mut duplicates_in_aggregate = Vec::new();
     let mut db_dirty = false;
+    duplicate_database.begin_batch();
     for path in paths.iter() {
-        let absolute_path = path::absolute(path)
-            .expect("Unable to get absolute path for file to hash").to_str()
-            .expect("Unexpected file name containining non utf 8 characters found").to_string();
+        let absolute_path = dupdb_absolute_path(path);
         if !path.exists() {
             duplicate_database.remove(absolute_path);
             db_dirty = true;
@@ -151,8 +632,16 @@ pub fn dupdb_update_hashes_for(paths: Vec<PathBuf>, duplicate_database: &mut Dup
             }
             match fs::read(path) {
                 Ok(bytes) => {
-                    let hash = seahash::hash(&bytes);
-                    duplicate_database.add(hash, absolute_path.clone());
+                    let hash = dupdb_hash_bytes(&bytes);
+                    let modified = fs::metadata(path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map(|since_epoch| since_epoch.as_secs());
+                    let size = Some(bytes.len() as u64);
+                    if !duplicate_database.add_with_metadata(hash, absolute_path.clone(), size, modified) {
+                        report.errors += 1;
+                    }
                     if duplicate_database.contains_duplicate_for_hash(hash) {
                         // send notification
                         println!("Duplicate detected {:?} {:?}", absolute_path, hash);
@@ -164,17 +653,41 @@ pub fn dupdb_update_hashes_for(paths: Vec<PathBuf>, duplicate_database: &mut Dup
                 },
                 Err(error) => {
                     eprintln!("Unexpected failure to read path: {:?} {:?}", error, path);
+                    report.errors += 1;
                 }
             }
         }
     };
 
+    if !duplicate_database.commit_batch() {
+        report.errors += 1;
+        return report;
+    }
+    report.committed = true;
+
     duplicates_in_aggregate.sort();
     duplicates_in_aggregate.dedup();
 
     if db_dirty && !duplicates_in_aggregate.is_empty() {
         dupdb_notifications_send(duplicates_in_aggregate);
     }
+    report
+}
+
+/// How file contents are hashed in the database. Anything that wants to
+/// look a file up by hash has to hash it exactly like this.
+pub fn dupdb_hash_bytes(bytes: &[u8]) -> u64 {
+    seahash::hash(bytes)
+}
+
+pub fn dupdb_hash_file(path: &Path) -> io::Result<u64> {
+    fs::read(path).map(|bytes| dupdb_hash_bytes(&bytes))
+}
+
+fn dupdb_absolute_path(path: &Path) -> String {
+    path::absolute(path)
+        .expect("Unable to get absolute path for file to hash").to_str()
+        .expect("Unexpected file name containining non utf 8 characters found").to_string()
 }
 
 pub fn dupdb_notifications_send(duplicate_paths: Vec<PathBuf>) {
@@ -221,49 +734,31 @@ pub fn dupdb_debug_file_path_print(path: String, duplicate_database: &DuplicateD
 
 #[cfg(test)]
 mod test {
-    // TODO: Should probably put this into a test util or something I guess.
     use super::*;
-    static mut TEST_DB_NO: u32 = 0;
-    use rusqlite::Connection;
-    use std::fs;
-
-    fn get_test_dupdb() -> DuplicateDatabase {
-        let connection;
-
-        unsafe {
-            TEST_DB_NO += 1;
-            let filename = format!("test_{TEST_DB_NO}.sqlite.db");
-            let _ = fs::remove_file(&filename);
-            connection = Connection::open(filename).expect("Cannot open database for test");
-        };
-        crate::sql::initialize(&connection);
-        DuplicateDatabase {
-            conn: connection
-        }
-    }
+    use crate::test_support::open_test_dupdb;
 
     #[test]
     fn adding_a_dupe_can_be_detected () {
-        let mut dupdb = get_test_dupdb();
+        let mut dupdb = open_test_dupdb("dupdb");
         let hash = 12456;
         let fake_path = "the_file_path";
         let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-        assert_eq!(db_has_dupe, false);
+        assert!(!db_has_dupe);
 
         dupdb.add(hash, fake_path.to_string());
 
         let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-        assert_eq!(db_has_dupe, false);
+        assert!(!db_has_dupe);
 
         let fake_path = "the_dup_file_path";
         dupdb.add(hash, fake_path.to_string());
         let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-        assert_eq!(db_has_dupe, true);
+        assert!(db_has_dupe);
     }
 
     #[test]
     fn removing_a_file_removes_detected_dupes () {
-        let mut dupdb = get_test_dupdb();
+        let mut dupdb = open_test_dupdb("dupdb");
         let hash = 12456;
         let fake_path = "the_file_path";
         let dup_path = "the_dup_path";
@@ -272,17 +767,74 @@ mod test {
         dupdb.add(hash, fake_path.to_string());
         dupdb.add(hash, dup_path.to_string());
         let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-        assert_eq!(db_has_dupe, true);
+        assert!(db_has_dupe);
 
         // Main screen turn on
         dupdb.remove(dup_path.to_string());
         let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-        assert_eq!(db_has_dupe, false);
+        assert!(!db_has_dupe);
+    }
+
+    #[test]
+    fn duplicates_are_grouped_by_hash () {
+        let mut dupdb = open_test_dupdb("dupdb");
+        dupdb.add(1, "a".to_string());
+        dupdb.add(1, "b".to_string());
+        dupdb.add(2, "c".to_string());
+        dupdb.add(3, "d".to_string());
+        dupdb.add(3, "e".to_string());
+
+        let groups = dupdb.duplicate_groups().expect("Could not query groups");
+        assert_eq!(groups, vec![
+            DuplicateGroup { hash: "1".to_string(), paths: vec!["a".to_string(), "b".to_string()] },
+            DuplicateGroup { hash: "3".to_string(), paths: vec!["d".to_string(), "e".to_string()] },
+        ]);
+        assert_eq!(dupdb.group_for_hash("2").expect("Could not query group"), None);
+        assert_eq!(dupdb.group_for_hash("3").expect("Could not query group"), Some(groups[1].clone()));
+
+        let stats = dupdb.stats().expect("Could not query stats");
+        assert_eq!(stats.duplicate_groups, 2);
+        assert_eq!(stats.duplicate_files, 4);
+        assert_eq!(stats.files_indexed, 5);
+    }
+
+    #[test]
+    fn history_records_each_change_to_a_path () {
+        let mut dupdb = open_test_dupdb("dupdb");
+        dupdb.add(1, "/a".to_string());
+        dupdb.add(1, "/a".to_string());
+        dupdb.add(2, "/a".to_string());
+        dupdb.rename("/a", "/b");
+        dupdb.remove("/b".to_string());
+
+        let kinds: Vec<FileEventKind> = dupdb.timeline_for_path("/a").expect("Could not query timeline")
+            .into_iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, vec![FileEventKind::Created, FileEventKind::Modified, FileEventKind::Renamed]);
+
+        let removed = dupdb.timeline_for_path("/b").expect("Could not query timeline").pop().expect("No events for /b");
+        assert_eq!(removed.kind, FileEventKind::Removed);
+        assert_eq!(removed.hash.as_deref(), Some("2"));
+
+        let modified = &dupdb.timeline_for_hash("1").expect("Could not query timeline")[1];
+        assert_eq!(modified.previous_hash.as_deref(), Some("1"));
+        assert_eq!(dupdb.indexed_file_count(), 0);
+    }
+
+    #[test]
+    fn files_keep_their_id_through_changes () {
+        let mut dupdb = open_test_dupdb("dupdb");
+        dupdb.add(1, "/a".to_string());
+        let id = dupdb.file_id_for_path("/a").expect("Could not look up id");
+        assert!(dupdb.add(2, "/a".to_string()));
+        assert_eq!(dupdb.file_id_for_path("/a").expect("Could not look up id"), id);
+        assert!(dupdb.rename("/a", "/b"));
+        assert_eq!(dupdb.file_id_for_path("/b").expect("Could not look up id"), id);
+        assert_eq!(dupdb.file_for_id(id.expect("/a was added")).expect("Could not look up file").map(|(hash, _)| hash), Some("2".to_string()));
     }
 
     #[test]
     fn does_not_detect_dupes_in_dir_if_not_there () {
-        let mut dupdb = get_test_dupdb();
+        let mut dupdb = open_test_dupdb("dupdb");
 
         let path: PathBuf = [".", "test", "nodupes"].iter().collect();
         let entries = RecursiveDirIterator::new(&path).expect("Could not load path to reindex database");
@@ -302,13 +854,13 @@ mod test {
         dupdb_update_hashes_for(paths, &mut dupdb);
         for hash in hashes {
             let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-            assert_eq!(db_has_dupe, false);
+            assert!(!db_has_dupe);
         }
     }
 
     #[test]
     fn does_detect_dupes_in_dir_if_not_there () {
-        let mut dupdb = get_test_dupdb();
+        let mut dupdb = open_test_dupdb("dupdb");
 
         let path: PathBuf = [".", "test", "dupes"].iter().collect();
         let entries = RecursiveDirIterator::new(&path).expect("Could not load path to reindex database");
@@ -325,10 +877,24 @@ mod test {
                 seahash::hash(&bytes)
             }).collect();
         
-        dupdb_update_hashes_for(paths, &mut dupdb);
+        let report = dupdb_update_hashes_for(paths, &mut dupdb);
+        assert!(report.committed);
+        assert_eq!(report.errors, 0);
         for hash in hashes {
             let db_has_dupe = dupdb.contains_duplicate_for_hash(hash);
-            assert_eq!(db_has_dupe, true);
+            assert!(db_has_dupe);
         }
     }
+
+    #[test]
+    fn watching_stops_once_shutdown_is_requested () {
+        let mut dupdb = open_test_dupdb("dupdb");
+        let signals = WatchSignals::unregistered();
+        signals.request_shutdown();
+
+        let roots = vec![[".", "test", "nodupes"].iter().collect()];
+        let status = Arc::new(WatchStatus::default());
+        let exit_code = dupdb_watch_forever(roots, |_| Vec::new(), &signals, &status, &mut dupdb);
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, Result, Row};
+
+/// What happened to a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileEventKind {
+    Created,
+    Modified,
+    Removed,
+    Renamed,
+}
+
+impl FileEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileEventKind::Created => "created",
+            FileEventKind::Modified => "modified",
+            FileEventKind::Removed => "removed",
+            FileEventKind::Renamed => "renamed",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<FileEventKind> {
+        match kind {
+            "created" => Some(FileEventKind::Created),
+            "modified" => Some(FileEventKind::Modified),
+            "removed" => Some(FileEventKind::Removed),
+            "renamed" => Some(FileEventKind::Renamed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FileEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One row of the append only dupdb_events table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEvent {
+    pub id: i64,
+    /// Unix seconds.
+    pub occurred_at: u64,
+    pub kind: FileEventKind,
+    pub path: String,
+    /// The hash the path has after the event, or had when it was removed.
+    pub hash: Option<String>,
+    /// Where a renamed file used to be.
+    pub previous_path: Option<String>,
+    /// What a modified file hashed to before.
+    pub previous_hash: Option<String>,
+}
+
+impl fmt::Display for FileEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.occurred_at, self.kind, self.path)?;
+        if let Some(hash) = &self.hash {
+            write!(f, " hash={hash}")?;
+        }
+        if let Some(previous_path) = &self.previous_path {
+            write!(f, " from={previous_path}")?;
+        }
+        if let Some(previous_hash) = &self.previous_hash {
+            write!(f, " previous_hash={previous_hash}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+const SQL_INSERT_EVENT: &str = "
+INSERT INTO dupdb_events (occurred_at, kind, file_path, hash, previous_path, previous_hash)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+";
+
+pub fn record_event(
+    conn: &Connection,
+    kind: FileEventKind,
+    path: &str,
+    hash: Option<&str>,
+    previous_path: Option<&str>,
+    previous_hash: Option<&str>,
+) -> Result<()> {
+    let mut statement = conn.prepare_cached(SQL_INSERT_EVENT)?;
+    statement.execute((unix_now(), kind.as_str(), path, hash, previous_path, previous_hash))?;
+    Ok(())
+}
+
+const SQL_SELECT_EVENTS_FOR_PATH: &str = "
+SELECT id, occurred_at, kind, file_path, hash, previous_path, previous_hash
+FROM dupdb_events
+WHERE file_path = ?1 OR previous_path = ?1
+ORDER BY id
+";
+
+/// Everything that happened at a path, including files renamed away from it.
+pub fn timeline_for_path(conn: &Connection, path: &str) -> Result<Vec<FileEvent>> {
+    let mut statement = conn.prepare_cached(SQL_SELECT_EVENTS_FOR_PATH)?;
+    let rows = statement.query_map([path], event_from_row)?;
+    rows.collect()
+}
+
+const SQL_SELECT_EVENTS_FOR_HASH: &str = "
+SELECT id, occurred_at, kind, file_path, hash, previous_path, previous_hash
+FROM dupdb_events
+WHERE hash = ?1 OR previous_hash = ?1
+ORDER BY id
+";
+
+/// Everything that happened to any file with this content.
+pub fn timeline_for_hash(conn: &Connection, hash: &str) -> Result<Vec<FileEvent>> {
+    let mut statement = conn.prepare_cached(SQL_SELECT_EVENTS_FOR_HASH)?;
+    let rows = statement.query_map([hash], event_from_row)?;
+    rows.collect()
+}
+
+const SQL_SELECT_EVENTS_SINCE: &str = "
+SELECT id, occurred_at, kind, file_path, hash, previous_path, previous_hash
+FROM dupdb_events
+WHERE id > ?1
+ORDER BY id
+LIMIT ?2
+";
+
+/// Up to `limit` events recorded after the event with id `after_id`, oldest first.
+pub fn events_since(conn: &Connection, after_id: i64, limit: usize) -> Result<Vec<FileEvent>> {
+    let mut statement = conn.prepare_cached(SQL_SELECT_EVENTS_SINCE)?;
+    let rows = statement.query_map((after_id, limit as i64), event_from_row)?;
+    rows.collect()
+}
+
+/// The id of the newest event, 0 if nothing has happened yet.
+pub fn latest_event_id(conn: &Connection) -> Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(id), 0) FROM dupdb_events", [], |row| row.get(0))
+}
+
+const SQL_DELETE_EVENTS_BEFORE: &str = "
+DELETE FROM dupdb_events WHERE occurred_at < ?1
+";
+
+/// Forgets events older than the given unix time, returns how many went.
+pub fn prune_events_before(conn: &Connection, unix_seconds: u64) -> Result<usize> {
+    let mut statement = conn.prepare_cached(SQL_DELETE_EVENTS_BEFORE)?;
+    statement.execute([unix_seconds])
+}
+
+fn event_from_row(row: &Row) -> Result<FileEvent> {
+    let kind: String = row.get(2)?;
+    Ok(FileEvent {
+        id: row.get(0)?,
+        occurred_at: row.get(1)?,
+        kind: FileEventKind::parse(&kind).ok_or(rusqlite::Error::InvalidColumnType(
+            2, format!("kind {kind}"), rusqlite::types::Type::Text
+        ))?,
+        path: row.get(3)?,
+        hash: row.get(4)?,
+        previous_path: row.get(5)?,
+        previous_hash: row.get(6)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::open_test_connection;
+
+    #[test]
+    fn path_timeline_follows_renames_away() {
+        let connection = open_test_connection("history");
+        record_event(&connection, FileEventKind::Created, "/a", Some("1"), None, None).expect("insert failed");
+        record_event(&connection, FileEventKind::Modified, "/a", Some("2"), None, Some("1")).expect("insert failed");
+        record_event(&connection, FileEventKind::Renamed, "/b", Some("2"), Some("/a"), None).expect("insert failed");
+        record_event(&connection, FileEventKind::Created, "/c", Some("2"), None, None).expect("insert failed");
+
+        let kinds: Vec<FileEventKind> = timeline_for_path(&connection, "/a").expect("select failed")
+            .into_iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, vec![FileEventKind::Created, FileEventKind::Modified, FileEventKind::Renamed]);
+
+        let paths: Vec<String> = timeline_for_hash(&connection, "1").expect("select failed")
+            .into_iter().map(|event| event.path).collect();
+        assert_eq!(paths, vec!["/a", "/a"]);
+        assert_eq!(timeline_for_hash(&connection, "2").expect("select failed").len(), 3);
+    }
+
+    #[test]
+    fn events_since_pages_through_in_order() {
+        let connection = open_test_connection("history");
+        assert_eq!(latest_event_id(&connection).expect("select failed"), 0);
+        for path in ["/a", "/b", "/c"] {
+            record_event(&connection, FileEventKind::Created, path, Some("1"), None, None).expect("insert failed");
+        }
+        let first = events_since(&connection, 0, 2).expect("select failed");
+        assert_eq!(first.iter().map(|event| event.path.as_str()).collect::<Vec<&str>>(), vec!["/a", "/b"]);
+        let rest = events_since(&connection, first[1].id, 2).expect("select failed");
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].id, latest_event_id(&connection).expect("select failed"));
+    }
+
+    #[test]
+    fn pruning_drops_only_old_events() {
+        let connection = open_test_connection("history");
+        record_event(&connection, FileEventKind::Created, "/a", Some("1"), None, None).expect("insert failed");
+        connection.execute("UPDATE dupdb_events SET occurred_at = 10", ()).expect("could not age event");
+        record_event(&connection, FileEventKind::Removed, "/a", Some("1"), None, None).expect("insert failed");
+
+        assert_eq!(prune_events_before(&connection, 11).expect("prune failed"), 1);
+        let remaining = timeline_for_path(&connection, "/a").expect("select failed");
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].kind, FileEventKind::Removed);
+    }
+}
@@ -0,0 +1,24 @@
+//! # Duplicate File Monitor
+//!
+//! Watches folders for files and keeps a sqlite database of their hashes so
+//! duplicates can be found. The binary does the watching, this library is
+//! how anything else (like the dupdb frontend) reads and changes that
+//! database without having to know what its tables look like.
+
+pub mod sql;
+pub mod query;
+pub mod history;
+pub mod dupdb;
+pub mod config;
+pub mod signals;
+pub mod status;
+pub mod daemon;
+pub mod search;
+pub mod overlap;
+#[cfg(test)]
+mod test_support;
+
+pub use dupdb::{DuplicateDatabase, DuplicateGroup, DuplicateStats};
+pub use history::{FileEvent, FileEventKind};
+pub use query::{GroupQuery, GroupSort, GroupCursor, GroupPage, GroupSummary, GroupFile};
+pub use overlap::{OverlapQuery, DirectoryOverlap, OverlapDirectory};
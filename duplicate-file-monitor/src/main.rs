@@ -1,32 +1,301 @@
+use std::env;
 use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-mod sql;
-mod dupdb;
+use duplicate_file_monitor::dupdb::*;
+use duplicate_file_monitor::config::*;
+use duplicate_file_monitor::signals::WatchSignals;
+use duplicate_file_monitor::status::*;
+use duplicate_file_monitor::daemon::*;
+use duplicate_file_monitor::search::*;
+use duplicate_file_monitor::{GroupFile, OverlapDirectory, OverlapQuery};
 
-use dupdb::*;
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("status") => print_status(),
+        Some("history") => match args.get(1) {
+            Some(path_or_hash) => print_history(path_or_hash),
+            None => {
+                eprintln!("Usage: duplicate-file-monitor history <path or hash>");
+                ExitCode::FAILURE
+            }
+        },
+        Some("search") => search(&args[1..]),
+        Some("overlap") => print_overlaps(&args[1..]),
+        Some("daemon") => watch(args.get(1).map(Path::new), None, true),
+        _ => watch(args.first().map(Path::new), args.get(1), false),
+    }
+}
+
+/// Asks the running daemon how it's doing.
+fn print_status() -> ExitCode {
+    let config = dupdb_config_load();
+    match dupdb_status_fetch(config.status_port) {
+        Ok(report) => {
+            print!("{report}");
+            ExitCode::SUCCESS
+        },
+        Err(error) => {
+            eprintln!("Could not reach a running monitor on port {}: {}", config.status_port, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints the timeline of a path, or of a hash if it isn't a path we know.
+fn print_history(path_or_hash: &str) -> ExitCode {
+    if !dupdb_database_path_exists() {
+        eprintln!("No database yet, nothing has happened.");
+        return ExitCode::FAILURE;
+    }
+    let database = dupdb_database_load_to_memory();
+    let absolute_path = std::path::absolute(path_or_hash)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or(path_or_hash.to_string());
+
+    let timeline = match database.timeline_for_path(&absolute_path) {
+        Ok(timeline) if timeline.is_empty() => database.timeline_for_hash(path_or_hash),
+        other => other,
+    };
+    match timeline {
+        Ok(timeline) if timeline.is_empty() => {
+            println!("No history for {}", path_or_hash);
+            ExitCode::SUCCESS
+        },
+        Ok(timeline) => {
+            for event in timeline {
+                println!("{event}");
+            }
+            ExitCode::SUCCESS
+        },
+        Err(error) => {
+            eprintln!("Could not read history: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+const SEARCH_USAGE: &str = "Usage: duplicate-file-monitor search [--frontend http://host:port] <file>...";
+/// Like grep: found, not found, or couldn't tell.
+const SEARCH_NOT_FOUND: u8 = 1;
+const SEARCH_FAILED: u8 = 2;
 
-fn main() {
-    let folder_name = env::args().nth(1).unwrap_or("./test".to_string());
-    let folder_to_watch = Path::new(&folder_name);
+enum SearchIn {
+    Database(DuplicateDatabase),
+    Frontend(FrontendUrl, Option<String>),
+}
+
+/// Hashes each file here and lists everything already indexed with the same
+/// contents, from the database or from a running frontend if given one.
+/// A frontend started with `--token` wants it in DUPDB_TOKEN.
+fn search(args: &[String]) -> ExitCode {
+    let mut frontend = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frontend" => match args.next().map(|url| FrontendUrl::parse(url)) {
+                Some(Ok(url)) => frontend = Some(url),
+                Some(Err(error)) => {
+                    eprintln!("{error}");
+                    return ExitCode::from(SEARCH_FAILED);
+                },
+                None => {
+                    eprintln!("{SEARCH_USAGE}");
+                    return ExitCode::from(SEARCH_FAILED);
+                },
+            },
+            file => files.push(Path::new(file)),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{SEARCH_USAGE}");
+        return ExitCode::from(SEARCH_FAILED);
+    }
+
+    let source = match frontend {
+        Some(frontend) => SearchIn::Frontend(frontend, env::var("DUPDB_TOKEN").ok().filter(|token| !token.is_empty())),
+        None if dupdb_database_path_exists() => SearchIn::Database(dupdb_database_load_to_memory()),
+        None => {
+            eprintln!("No database yet, nothing is stored.");
+            return ExitCode::from(SEARCH_FAILED);
+        },
+    };
+
+    let mut exit_code = ExitCode::SUCCESS;
+    for file in files {
+        let hash = match dupdb_hash_file(file) {
+            Ok(hash) => hash,
+            Err(error) => {
+                eprintln!("Could not read {}: {}", file.display(), error);
+                exit_code = ExitCode::from(SEARCH_FAILED);
+                continue;
+            }
+        };
+        let matches: Result<Vec<GroupFile>, String> = match &source {
+            SearchIn::Database(database) => database.files_for_hash(&hash.to_string()).map_err(|error| error.to_string()),
+            SearchIn::Frontend(frontend, token) => dupdb_search_frontend(frontend, token.as_deref(), hash).map_err(|error| error.to_string()),
+        };
+        match matches {
+            Ok(matches) if matches.is_empty() => {
+                println!("{} is not stored anywhere", file.display());
+                if exit_code == ExitCode::SUCCESS {
+                    exit_code = ExitCode::from(SEARCH_NOT_FOUND);
+                }
+            },
+            Ok(matches) => {
+                let absolute_path = std::path::absolute(file).map(|path| path.to_string_lossy().to_string()).unwrap_or_default();
+                println!("{} is already stored as:", file.display());
+                for stored in matches {
+                    let this_file = if stored.path == absolute_path { " (this file)" } else { "" };
+                    println!("  {}{}", stored.path, this_file);
+                }
+            },
+            Err(error) => {
+                eprintln!("Could not search for {}: {}", file.display(), error);
+                exit_code = ExitCode::from(SEARCH_FAILED);
+            },
+        }
+    }
+    exit_code
+}
+
+const OVERLAP_USAGE: &str = "Usage: duplicate-file-monitor overlap [--min-shared N] [--limit N] [folder]";
+
+/// Folders that share the most, for finding whole folders copied twice.
+/// With a folder only pairs under it are reported.
+fn print_overlaps(args: &[String]) -> ExitCode {
+    let mut query = OverlapQuery::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--min-shared" | "--limit" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) if arg == "--limit" => query.limit = value,
+                Some(value) => query.min_shared_files = value as u64,
+                None => {
+                    eprintln!("{OVERLAP_USAGE}");
+                    return ExitCode::FAILURE;
+                },
+            },
+            folder if query.path_prefix.is_none() && !folder.starts_with("--") => {
+                let absolute_path = std::path::absolute(folder).map(|path| path.to_string_lossy().to_string());
+                query.path_prefix = Some(absolute_path.unwrap_or(folder.to_string()));
+            },
+            _ => {
+                eprintln!("{OVERLAP_USAGE}");
+                return ExitCode::FAILURE;
+            },
+        }
+    }
+    if !dupdb_database_path_exists() {
+        eprintln!("No database yet, nothing overlaps.");
+        return ExitCode::FAILURE;
+    }
+    let database = dupdb_database_load_to_memory();
+    let overlaps = match database.directory_overlaps(&query) {
+        Ok(overlaps) => overlaps,
+        Err(error) => {
+            eprintln!("Could not work out overlaps: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    if overlaps.is_empty() {
+        println!("No folders share any files");
+    }
+    let describe = |directory: &OverlapDirectory| format!("{}% of {} ({} files)", directory.overlap_percent, directory.path, directory.files);
+    for overlap in overlaps {
+        let (first, second) = (&overlap.first, &overlap.second);
+        match (first.fully_contained, second.fully_contained) {
+            (true, true) => println!("{} and {} are the same", first.path, second.path),
+            (true, false) => println!("{} is fully contained in {}", first.path, second.path),
+            (false, true) => println!("{} is fully contained in {}", second.path, first.path),
+            (false, false) => println!("{} and {} overlap", first.path, second.path),
+        }
+        println!("  {} shared files, {} bytes: {}, {}", overlap.shared_files, overlap.shared_bytes, describe(first), describe(second));
+    }
+    ExitCode::SUCCESS
+}
+
+/// The lock every writer to the database holds, or `None` after saying who
+/// has it instead.
+fn acquire_pid_lock() -> Option<PidLock> {
+    match PidLock::acquire(&dupdb_pid_file_path()) {
+        Ok(lock) => Some(lock),
+        Err(error) => {
+            eprintln!("Refusing to start: {}", error);
+            None
+        }
+    }
+}
+
+/// Watches forever. As a daemon we also answer status requests, either way
+/// we hold the pid lock so nobody else writes to the database under us.
+fn watch(cli_root: Option<&Path>, debug_file_path: Option<&String>, as_daemon: bool) -> ExitCode {
+    // Signals are registered before anything touches the database so that
+    // a ctrl+c during the initial index still lets it finish cleanly.
+    let signals = match WatchSignals::register() {
+        Ok(signals) => signals,
+        Err(error) => {
+            eprintln!("Could not register signal handlers: {:?}", error);
+            return ExitCode::FAILURE;
+        }
+    };
 
     // Initialize .dupdb in folder.
     let needs_reset = dupdb_initialize_hidden_folder();
 
+    let config = dupdb_config_load();
+    let watch_roots = dupdb_watch_roots(&config, cli_root);
+
+    // if 2 argumetns are sent, then second is key to look up for debugging
+    // because I'm getting a lot of conflicts on files that aren't actually duplicates.    
+    // Looking up only reads and can run next to a daemon, but a brand new
+    // database has to be filled in first, and that writes, so it takes the lock.
+    if let Some(file_path) = debug_file_path {
+        let mut database = dupdb_database_load_to_memory();
+        if needs_reset {
+            let Some(_pid_lock) = acquire_pid_lock() else {
+                return ExitCode::FAILURE;
+            };
+            dupdb_reset_database_from_existing_files(&watch_roots, &mut database);
+        }
+        dupdb_debug_file_path_print(file_path.to_string(), &database);
+        return ExitCode::SUCCESS;
+    }
+
+    let Some(_pid_lock) = acquire_pid_lock() else {
+        return ExitCode::FAILURE;
+    };
+
+    let status = Arc::new(WatchStatus::default());
+    if as_daemon {
+        match dupdb_status_bind(config.status_port) {
+            Ok(listener) => {
+                println!("Status available on 127.0.0.1:{}/status", config.status_port);
+                dupdb_status_serve(listener, Arc::clone(&status));
+            },
+            Err(error) => {
+                eprintln!("Could not bind status endpoint on port {}: {}", config.status_port, error);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
     // Load database
     let mut database = dupdb_database_load_to_memory();
+    database.set_event_retention_days(config.event_retention_days);
 
     if needs_reset {
-        dupdb_reset_database_from_existing_files(folder_to_watch.to_path_buf(), &mut database);
-        println!("Initial database saved to {:?}", folder_to_watch);
+        dupdb_reset_database_from_existing_files(&watch_roots, &mut database);
+        println!("Initial database saved to {:?}", watch_roots);
     }        
 
-    // if 2 argumetns are sent, then second is key to look up for debugging
-    // because I'm getting a lot of conflicts on files that aren't actually duplicates.    
-    if let Some(file_path) = env::args().nth(2) {
-        dupdb_debug_file_path_print(file_path, &database);
-        return;
-    }
-
-    dupdb_watch_forever(folder_to_watch, &mut database);
+    let reload_config = |database: &mut DuplicateDatabase| {
+        let config = dupdb_config_load();
+        database.set_event_retention_days(config.event_retention_days);
+        dupdb_watch_roots(&config, cli_root)
+    };
+    dupdb_watch_forever(watch_roots, reload_config, &signals, &status, &mut database)
 }
-
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rusqlite::{Connection, Result};
+use serde::Serialize;
+
+pub const DEFAULT_OVERLAP_LIMIT: usize = 50;
+pub const MAX_OVERLAP_LIMIT: usize = 1000;
+/// Contents found in more folders than this are left out of the pairing,
+/// they're empty files and licenses rather than a folder copied twice, and
+/// every pair of folders they're in would otherwise have to be counted.
+pub const MAX_DIRECTORIES_PER_HASH: usize = 64;
+
+/// Which folder pairs to report on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlapQuery {
+    /// Both folders of a pair have to be this folder or under it. Whole
+    /// names only, `/photos` doesn't take in `/photos backup`.
+    pub path_prefix: Option<String>,
+    /// Pairs sharing fewer files than this are left out.
+    pub min_shared_files: u64,
+    pub limit: usize,
+}
+
+impl Default for OverlapQuery {
+    fn default() -> Self {
+        OverlapQuery {
+            path_prefix: None,
+            min_shared_files: 1,
+            limit: DEFAULT_OVERLAP_LIMIT,
+        }
+    }
+}
+
+/// One folder of an overlapping pair. Only the files directly in it count,
+/// subfolders are folders of their own.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OverlapDirectory {
+    pub path: String,
+    /// Different contents in the folder, so two copies side by side count once.
+    pub files: u64,
+    pub bytes: u64,
+    /// How much of this folder's contents are also in the other one.
+    pub overlap_percent: f64,
+    /// Everything in this folder is also in the other one.
+    pub fully_contained: bool,
+}
+
+/// Two folders with contents in common, biggest overlap first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DirectoryOverlap {
+    pub first: OverlapDirectory,
+    pub second: OverlapDirectory,
+    pub shared_files: u64,
+    pub shared_bytes: u64,
+}
+
+#[derive(Default)]
+struct DirectoryTotals {
+    files: u64,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct SharedTotals {
+    files: u64,
+    bytes: u64,
+}
+
+const SQL_SELECT_ALL_FILES: &str = "
+SELECT file_path, COALESCE(MAX(file_size), 0)
+FROM dupdb_filehashes
+GROUP BY hash, file_path
+";
+
+const SQL_SELECT_DUPLICATED_FILES: &str = "
+SELECT hash, file_path, COALESCE(MAX(file_size), 0)
+FROM dupdb_filehashes
+WHERE hash IN (
+    SELECT hash FROM dupdb_filehashes GROUP BY hash HAVING COUNT(DISTINCT file_path) > 1
+)
+GROUP BY hash, file_path
+ORDER BY hash
+";
+
+/// Adds up duplicates by the folder they're in, to find folders that were
+/// copied rather than single files.
+pub fn directory_overlaps(conn: &Connection, query: &OverlapQuery) -> Result<Vec<DirectoryOverlap>> {
+    let wanted = |directory: &str| query.path_prefix.as_deref().is_none_or(|prefix| Path::new(directory).starts_with(prefix));
+
+    let mut directories: HashMap<String, DirectoryTotals> = HashMap::new();
+    let mut statement = conn.prepare_cached(SQL_SELECT_ALL_FILES)?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        let directory = parent_of(&path);
+        if !wanted(directory) {
+            continue;
+        }
+        let totals = directories.entry(directory.to_string()).or_default();
+        totals.files += 1;
+        totals.bytes += row.get::<_, i64>(1)?.max(0) as u64;
+    }
+
+    let mut pairs: HashMap<(String, String), SharedTotals> = HashMap::new();
+    let mut statement = conn.prepare_cached(SQL_SELECT_DUPLICATED_FILES)?;
+    let mut rows = statement.query([])?;
+    let mut current_hash: Option<String> = None;
+    // Folder and how many copies it has of the current hash.
+    let mut copies: Vec<(String, u64)> = Vec::new();
+    let mut size = 0;
+    loop {
+        let row = rows.next()?;
+        let hash: Option<String> = row.map(|row| row.get(0)).transpose()?;
+        if hash != current_hash {
+            add_group(&copies, size, &mut directories, &mut pairs);
+            copies.clear();
+            size = 0;
+            current_hash = hash;
+        }
+        let Some(row) = row else {
+            break;
+        };
+        let path: String = row.get(1)?;
+        let directory = parent_of(&path);
+        if !wanted(directory) {
+            continue;
+        }
+        size = size.max(row.get::<_, i64>(2)?.max(0) as u64);
+        match copies.iter_mut().find(|(seen, _)| seen == directory) {
+            Some((_, count)) => *count += 1,
+            None => copies.push((directory.to_string(), 1)),
+        }
+    }
+
+    let mut overlaps: Vec<DirectoryOverlap> = pairs.into_iter()
+        .filter(|(_, shared)| shared.files >= query.min_shared_files.max(1))
+        .map(|((first, second), shared)| DirectoryOverlap {
+            first: overlap_directory(first, &directories, &shared),
+            second: overlap_directory(second, &directories, &shared),
+            shared_files: shared.files,
+            shared_bytes: shared.bytes,
+        })
+        .collect();
+    overlaps.sort_by(|a, b| {
+        b.shared_bytes.cmp(&a.shared_bytes)
+            .then(b.shared_files.cmp(&a.shared_files))
+            .then_with(|| (&a.first.path, &a.second.path).cmp(&(&b.first.path, &b.second.path)))
+    });
+    overlaps.truncate(query.limit.clamp(1, MAX_OVERLAP_LIMIT));
+    Ok(overlaps)
+}
+
+/// Counts one hash towards every pair of folders it's in. Extra copies in
+/// the same folder are taken back off that folder's total, it only has
+/// the contents once.
+fn add_group(
+    copies: &[(String, u64)],
+    size: u64,
+    directories: &mut HashMap<String, DirectoryTotals>,
+    pairs: &mut HashMap<(String, String), SharedTotals>,
+) {
+    for (directory, count) in copies {
+        if let Some(totals) = directories.get_mut(directory) {
+            totals.files = totals.files.saturating_sub(count - 1);
+            totals.bytes = totals.bytes.saturating_sub(size * (count - 1));
+        }
+    }
+    if copies.len() > MAX_DIRECTORIES_PER_HASH {
+        return;
+    }
+    for (index, (first, _)) in copies.iter().enumerate() {
+        for (second, _) in &copies[index + 1..] {
+            let key = if first < second { (first.clone(), second.clone()) } else { (second.clone(), first.clone()) };
+            let shared = pairs.entry(key).or_default();
+            shared.files += 1;
+            shared.bytes += size;
+        }
+    }
+}
+
+fn overlap_directory(path: String, directories: &HashMap<String, DirectoryTotals>, shared: &SharedTotals) -> OverlapDirectory {
+    let (files, bytes) = directories.get(&path).map(|totals| (totals.files, totals.bytes)).unwrap_or_default();
+    // Stale rows can leave a folder looking smaller than what it shares.
+    let files = files.max(shared.files);
+    OverlapDirectory {
+        path,
+        files,
+        bytes: bytes.max(shared.bytes),
+        overlap_percent: (shared.files as f64 * 1000.0 / files as f64).round() / 10.0,
+        fully_contained: shared.files == files,
+    }
+}
+
+fn parent_of(path: &str) -> &str {
+    match Path::new(path).parent().and_then(Path::to_str) {
+        Some("") | None => path,
+        Some(parent) => parent,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sql::insert_file_hash_with_metadata;
+    use crate::test_support::{open_test_connection, TestDatabase};
+
+    fn open_test_database() -> TestDatabase<Connection> {
+        let connection = open_test_connection("overlap");
+        // 2019 was backed up whole, and the backup has one more besides.
+        // One photo also ended up in downloads, twice.
+        for (hash, path, size) in [
+            (1, "/photos/2019/a.jpg", 100),
+            (2, "/photos/2019/b.jpg", 200),
+            (3, "/photos/2019/c.jpg", 300),
+            (1, "/backup/photos 2019/a.jpg", 100),
+            (2, "/backup/photos 2019/b.jpg", 200),
+            (3, "/backup/photos 2019/c.jpg", 300),
+            (4, "/backup/photos 2019/d.jpg", 400),
+            (3, "/downloads/c.jpg", 300),
+            (3, "/downloads/c (1).jpg", 300),
+            (5, "/downloads/setup.exe", 5000),
+        ] {
+            insert_file_hash_with_metadata(&connection, hash, path, Some(size), None);
+        }
+        connection
+    }
+
+    fn pair(overlap: &DirectoryOverlap) -> (&str, &str) {
+        (overlap.first.path.as_str(), overlap.second.path.as_str())
+    }
+
+    #[test]
+    fn copied_folders_are_found_and_contained_ones_flagged() {
+        let connection = open_test_database();
+        let overlaps = directory_overlaps(&connection, &OverlapQuery::default()).expect("query failed");
+        assert_eq!(overlaps.iter().map(pair).collect::<Vec<_>>(), vec![
+            ("/backup/photos 2019", "/photos/2019"),
+            ("/backup/photos 2019", "/downloads"),
+            ("/downloads", "/photos/2019"),
+        ]);
+
+        let photos = &overlaps[0];
+        assert_eq!((photos.shared_files, photos.shared_bytes), (3, 600));
+        assert_eq!((photos.first.files, photos.first.bytes), (4, 1000));
+        assert_eq!(photos.first.overlap_percent, 75.0);
+        assert!(!photos.first.fully_contained);
+        assert_eq!(photos.second.overlap_percent, 100.0);
+        assert!(photos.second.fully_contained);
+
+        // Two copies of c.jpg side by side are still only one of its files.
+        let downloads = &overlaps[2];
+        assert_eq!((downloads.first.files, downloads.first.bytes), (2, 5300));
+        assert_eq!(downloads.first.overlap_percent, 50.0);
+    }
+
+    #[test]
+    fn overlaps_can_be_narrowed_down() {
+        let connection = open_test_database();
+        let at_least_two = OverlapQuery { min_shared_files: 2, ..OverlapQuery::default() };
+        let overlaps = directory_overlaps(&connection, &at_least_two).expect("query failed");
+        assert_eq!(overlaps.iter().map(pair).collect::<Vec<_>>(), vec![("/backup/photos 2019", "/photos/2019")]);
+
+        let under_photos = OverlapQuery { path_prefix: Some("/photos".to_string()), ..OverlapQuery::default() };
+        assert!(directory_overlaps(&connection, &under_photos).expect("query failed").is_empty());
+
+        let top = OverlapQuery { limit: 1, ..OverlapQuery::default() };
+        assert_eq!(directory_overlaps(&connection, &top).expect("query failed").len(), 1);
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_folder_names() {
+        let connection = open_test_database();
+        for (hash, path) in [
+            (6, "/home/me/Photos/e.jpg"),
+            (6, "/home/me/Photos/2020/e.jpg"),
+            (6, "/home/me/Photos Backup/e.jpg"),
+            (6, "/home/me/Photos-old/e.jpg"),
+        ] {
+            insert_file_hash_with_metadata(&connection, hash, path, Some(600), None);
+        }
+        for prefix in ["/home/me/Photos", "/home/me/Photos/"] {
+            let under_photos = OverlapQuery { path_prefix: Some(prefix.to_string()), ..OverlapQuery::default() };
+            let overlaps = directory_overlaps(&connection, &under_photos).expect("query failed");
+            assert_eq!(overlaps.iter().map(pair).collect::<Vec<_>>(), vec![("/home/me/Photos", "/home/me/Photos/2020")]);
+        }
+
+        let under_home = OverlapQuery { path_prefix: Some("/home/me".to_string()), ..OverlapQuery::default() };
+        assert_eq!(directory_overlaps(&connection, &under_home).expect("query failed").len(), 6);
+    }
+}
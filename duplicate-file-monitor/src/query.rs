@@ -0,0 +1,454 @@
+use std::fmt;
+
+use rusqlite::{Connection, OptionalExtension, Result, Row, params_from_iter};
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// What order groups come back in. Everything sorts biggest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum GroupSort {
+    /// Size of a copy times the number of extra copies.
+    #[default]
+    WastedBytes,
+    /// Number of copies in the group.
+    Count,
+    /// Most recently modified copy.
+    Newest,
+}
+
+impl GroupSort {
+    pub fn parse(name: &str) -> Option<GroupSort> {
+        match name {
+            "wasted" => Some(GroupSort::WastedBytes),
+            "count" => Some(GroupSort::Count),
+            "newest" => Some(GroupSort::Newest),
+            _ => None,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            GroupSort::WastedBytes => "wasted",
+            GroupSort::Count => "copies",
+            GroupSort::Newest => "newest",
+        }
+    }
+}
+
+/// Where the previous page left off. Opaque to callers, they get one from
+/// a `GroupPage` and hand it back to get the page after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupCursor {
+    sort_key: i64,
+    hash: String,
+}
+
+impl GroupCursor {
+    pub fn parse(cursor: &str) -> Option<GroupCursor> {
+        let (sort_key, hash) = cursor.split_once(':')?;
+        Some(GroupCursor {
+            sort_key: sort_key.parse().ok()?,
+            hash: hash.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for GroupCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.sort_key, self.hash)
+    }
+}
+
+/// Which groups to return. A group is included when any one of its files
+/// matches every filter that is set, and then all of its files are returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupQuery {
+    /// A folder the file has to be in, or the file itself. Whole names
+    /// only, `/photos` doesn't take in `/photos-old`.
+    pub path_prefix: Option<String>,
+    /// Without the leading dot, compared case insensitively.
+    pub extension: Option<String>,
+    pub min_size: Option<u64>,
+    /// Unix seconds.
+    pub modified_since: Option<u64>,
+    pub sort: GroupSort,
+    pub limit: usize,
+    pub cursor: Option<GroupCursor>,
+}
+
+impl Default for GroupQuery {
+    fn default() -> Self {
+        GroupQuery {
+            path_prefix: None,
+            extension: None,
+            min_size: None,
+            modified_since: None,
+            sort: GroupSort::default(),
+            limit: DEFAULT_PAGE_SIZE,
+            cursor: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupFile {
+    /// Never handed to another file, not even once this one is gone, use it
+    /// instead of the path when handing out references to a file.
+    pub id: i64,
+    pub path: String,
+    pub size: Option<u64>,
+    pub modified: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupSummary {
+    pub hash: String,
+    pub copies: u64,
+    pub size: u64,
+    pub wasted_bytes: u64,
+    pub newest_modified: Option<u64>,
+    pub files: Vec<GroupFile>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupPage {
+    pub groups: Vec<GroupSummary>,
+    /// None when this was the last page.
+    pub next_cursor: Option<GroupCursor>,
+}
+
+const SQL_SELECT_GROUPS: &str = "
+SELECT hash, copies, size, wasted, newest FROM (
+    SELECT
+        hash,
+        COUNT(DISTINCT file_path) AS copies,
+        COALESCE(MAX(file_size), 0) AS size,
+        COALESCE(MAX(file_size), 0) * (COUNT(DISTINCT file_path) - 1) AS wasted,
+        COALESCE(MAX(modified), 0) AS newest
+    FROM dupdb_filehashes
+    GROUP BY hash
+    HAVING copies > 1
+)
+WHERE 1 = 1
+";
+
+const SQL_SELECT_GROUP_FILES: &str = "
+SELECT MIN(id), hash, file_path, MAX(file_size), MAX(modified)
+FROM dupdb_filehashes
+WHERE hash IN (SELECT value FROM json_each(?1))
+GROUP BY hash, file_path
+ORDER BY hash, file_path
+";
+
+/// Fetches one page of duplicate groups matching the query.
+pub fn duplicate_groups(conn: &Connection, query: &GroupQuery) -> Result<GroupPage> {
+    let sort_column = query.sort.column();
+    let mut sql = String::from(SQL_SELECT_GROUPS);
+    let mut parameters: Vec<Value> = Vec::new();
+
+    let mut file_filters = Vec::new();
+    if let Some(prefix) = &query.path_prefix {
+        let folder = prefix.trim_end_matches('/');
+        let under_folder = format!("{folder}/");
+        file_filters.push("(file_path = ? OR substr(file_path, 1, length(?)) = ?)");
+        parameters.push(Value::Text(folder.to_string()));
+        parameters.push(Value::Text(under_folder.clone()));
+        parameters.push(Value::Text(under_folder));
+    }
+    if let Some(extension) = &query.extension {
+        let suffix = format!(".{}", extension.trim_start_matches('.').to_lowercase());
+        file_filters.push("lower(substr(file_path, -length(?))) = ?");
+        parameters.push(Value::Text(suffix.clone()));
+        parameters.push(Value::Text(suffix));
+    }
+    if let Some(min_size) = query.min_size {
+        file_filters.push("file_size >= ?");
+        parameters.push(Value::Integer(saturating_i64(min_size)));
+    }
+    if let Some(modified_since) = query.modified_since {
+        file_filters.push("modified >= ?");
+        parameters.push(Value::Integer(saturating_i64(modified_since)));
+    }
+    if !file_filters.is_empty() {
+        sql.push_str(&format!(
+            "AND hash IN (SELECT hash FROM dupdb_filehashes WHERE {})\n",
+            file_filters.join(" AND ")
+        ));
+    }
+
+    if let Some(cursor) = &query.cursor {
+        sql.push_str(&format!("AND ({sort_column} < ? OR ({sort_column} = ? AND hash > ?))\n"));
+        parameters.push(Value::Integer(cursor.sort_key));
+        parameters.push(Value::Integer(cursor.sort_key));
+        parameters.push(Value::Text(cursor.hash.clone()));
+    }
+
+    // Ask for one extra row so we know whether there's another page.
+    let limit = query.limit.clamp(1, MAX_PAGE_SIZE);
+    sql.push_str(&format!("ORDER BY {sort_column} DESC, hash ASC LIMIT {}", limit + 1));
+
+    let mut statement = conn.prepare_cached(&sql)?;
+    let mut groups = statement.query_map(params_from_iter(parameters), summary_from_row)?
+        .collect::<Result<Vec<GroupSummary>>>()?;
+
+    let next_cursor = if groups.len() > limit {
+        groups.truncate(limit);
+        groups.last().map(|last| GroupCursor {
+            sort_key: saturating_i64(match query.sort {
+                GroupSort::WastedBytes => last.wasted_bytes,
+                GroupSort::Count => last.copies,
+                GroupSort::Newest => last.newest_modified.unwrap_or(0),
+            }),
+            hash: last.hash.clone(),
+        })
+    } else {
+        None
+    };
+
+    fill_group_files(conn, &mut groups)?;
+    Ok(GroupPage { groups, next_cursor })
+}
+
+/// The summary of one group by its hash, or None if nothing else shares it.
+pub fn group_summary(conn: &Connection, hash: &str) -> Result<Option<GroupSummary>> {
+    let sql = format!("{SQL_SELECT_GROUPS}AND hash = ?1");
+    let mut statement = conn.prepare_cached(&sql)?;
+    let group = statement.query_row([hash], summary_from_row).optional()?;
+    let Some(group) = group else {
+        return Ok(None);
+    };
+    let mut groups = [group];
+    fill_group_files(conn, &mut groups)?;
+    let [group] = groups;
+    Ok(Some(group))
+}
+
+const SQL_SELECT_FILE_BY_ID: &str = "
+SELECT id, hash, file_path, file_size, modified
+FROM dupdb_filehashes
+WHERE id = ?1
+";
+
+/// A single indexed file and the hash it was last seen with.
+pub fn file_for_id(conn: &Connection, id: i64) -> Result<Option<(String, GroupFile)>> {
+    let mut statement = conn.prepare_cached(SQL_SELECT_FILE_BY_ID)?;
+    statement.query_row([id], |row| {
+        Ok((row.get(1)?, GroupFile {
+            id: row.get(0)?,
+            path: row.get(2)?,
+            size: row.get(3)?,
+            modified: row.get(4)?,
+        }))
+    }).optional()
+}
+
+const SQL_SELECT_FILES_FOR_HASH: &str = "
+SELECT MIN(id), file_path, MAX(file_size), MAX(modified)
+FROM dupdb_filehashes
+WHERE hash = ?1
+GROUP BY file_path
+ORDER BY file_path
+";
+
+/// Every file indexed with a hash, even if it's the only one.
+pub fn files_for_hash(conn: &Connection, hash: &str) -> Result<Vec<GroupFile>> {
+    let mut statement = conn.prepare_cached(SQL_SELECT_FILES_FOR_HASH)?;
+    let rows = statement.query_map([hash], |row| {
+        Ok(GroupFile {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            size: row.get(2)?,
+            modified: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Files are left empty, see `fill_group_files`.
+fn summary_from_row(row: &Row) -> Result<GroupSummary> {
+    Ok(GroupSummary {
+        hash: row.get(0)?,
+        copies: row.get(1)?,
+        size: row.get(2)?,
+        wasted_bytes: row.get(3)?,
+        newest_modified: row.get::<_, Option<u64>>(4)?.filter(|newest| *newest > 0),
+        files: Vec::new(),
+    })
+}
+
+fn fill_group_files(conn: &Connection, groups: &mut [GroupSummary]) -> Result<()> {
+    if groups.is_empty() {
+        return Ok(());
+    }
+    // json_each saves us building an IN (?, ?, ...) list of the right length.
+    let hashes = format!(
+        "[{}]",
+        groups.iter().map(|group| format!("\"{}\"", group.hash)).collect::<Vec<String>>().join(",")
+    );
+    let mut statement = conn.prepare_cached(SQL_SELECT_GROUP_FILES)?;
+    let rows = statement.query_map([hashes], |row| {
+        Ok((row.get::<_, String>(1)?, GroupFile {
+            id: row.get(0)?,
+            path: row.get(2)?,
+            size: row.get(3)?,
+            modified: row.get(4)?,
+        }))
+    })?;
+    for row in rows {
+        let (hash, file) = row?;
+        if let Some(group) = groups.iter_mut().find(|group| group.hash == hash) {
+            group.files.push(file);
+        }
+    }
+    Ok(())
+}
+
+fn saturating_i64(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sql::insert_file_hash_with_metadata;
+    use crate::test_support::{open_test_connection, TestDatabase};
+
+    fn open_test_database() -> TestDatabase<Connection> {
+        let connection = open_test_connection("query");
+        // 3 copies of 10 bytes, 2 copies of 100 bytes, 2 newer copies of 1 byte, 1 loner.
+        for (hash, path, size, modified) in [
+            (1, "/photos/a.jpg", 10, 100),
+            (1, "/backup/a.jpg", 10, 100),
+            (1, "/backup/old/a.jpg", 10, 100),
+            (2, "/videos/b.MP4", 100, 200),
+            (2, "/backup/b.mp4", 100, 200),
+            (3, "/notes/c.txt", 1, 300),
+            (3, "/backup/c.txt", 1, 300),
+            (4, "/photos/alone.jpg", 5000, 400),
+        ] {
+            insert_file_hash_with_metadata(&connection, hash, path, Some(size), Some(modified));
+        }
+        connection
+    }
+
+    fn hashes(page: &GroupPage) -> Vec<&str> {
+        page.groups.iter().map(|group| group.hash.as_str()).collect()
+    }
+
+    #[test]
+    fn groups_sort_by_wasted_bytes_by_default() {
+        let connection = open_test_database();
+        let page = duplicate_groups(&connection, &GroupQuery::default()).expect("query failed");
+        assert_eq!(hashes(&page), vec!["2", "1", "3"]);
+        assert_eq!(page.groups[0].wasted_bytes, 100);
+        assert_eq!(page.groups[1].wasted_bytes, 20);
+        assert_eq!(page.groups[1].files.len(), 3);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn groups_sort_by_count_and_newest() {
+        let connection = open_test_database();
+        let by_count = GroupQuery { sort: GroupSort::Count, ..GroupQuery::default() };
+        let page = duplicate_groups(&connection, &by_count).expect("query failed");
+        assert_eq!(page.groups[0].hash, "1");
+
+        let by_newest = GroupQuery { sort: GroupSort::Newest, ..GroupQuery::default() };
+        let page = duplicate_groups(&connection, &by_newest).expect("query failed");
+        assert_eq!(hashes(&page), vec!["3", "2", "1"]);
+    }
+
+    #[test]
+    fn filters_keep_whole_groups_that_have_a_matching_file() {
+        let connection = open_test_database();
+        let under_photos = GroupQuery { path_prefix: Some("/photos/".to_string()), ..GroupQuery::default() };
+        let page = duplicate_groups(&connection, &under_photos).expect("query failed");
+        assert_eq!(hashes(&page), vec!["1"]);
+        assert_eq!(page.groups[0].files.len(), 3);
+
+        let no_slash = GroupQuery { path_prefix: Some("/photos".to_string()), ..GroupQuery::default() };
+        assert_eq!(hashes(&duplicate_groups(&connection, &no_slash).expect("query failed")), vec!["1"]);
+
+        let mp4s = GroupQuery { extension: Some("mp4".to_string()), ..GroupQuery::default() };
+        let page = duplicate_groups(&connection, &mp4s).expect("query failed");
+        assert_eq!(hashes(&page), vec!["2"]);
+
+        let big_and_recent = GroupQuery { min_size: Some(10), modified_since: Some(150), ..GroupQuery::default() };
+        let page = duplicate_groups(&connection, &big_and_recent).expect("query failed");
+        assert_eq!(hashes(&page), vec!["2"]);
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_folder_names() {
+        let connection = open_test_database();
+        insert_file_hash_with_metadata(&connection, 5, "/photos-old/d.jpg", Some(7), None);
+        insert_file_hash_with_metadata(&connection, 5, "/photos backup/d.jpg", Some(7), None);
+        for prefix in ["/photos", "/photos/"] {
+            let under_photos = GroupQuery { path_prefix: Some(prefix.to_string()), ..GroupQuery::default() };
+            assert_eq!(hashes(&duplicate_groups(&connection, &under_photos).expect("query failed")), vec!["1"]);
+        }
+
+        let just_the_file = GroupQuery { path_prefix: Some("/photos-old/d.jpg".to_string()), ..GroupQuery::default() };
+        assert_eq!(hashes(&duplicate_groups(&connection, &just_the_file).expect("query failed")), vec!["5"]);
+        let everything = GroupQuery { path_prefix: Some("/".to_string()), ..GroupQuery::default() };
+        assert_eq!(duplicate_groups(&connection, &everything).expect("query failed").groups.len(), 4);
+    }
+
+    #[test]
+    fn cursor_walks_through_every_group_once() {
+        let connection = open_test_database();
+        let mut query = GroupQuery { limit: 1, ..GroupQuery::default() };
+        let mut seen = Vec::new();
+        loop {
+            let page = duplicate_groups(&connection, &query).expect("query failed");
+            seen.extend(hashes(&page).into_iter().map(String::from));
+            match page.next_cursor {
+                Some(cursor) => {
+                    let round_tripped = GroupCursor::parse(&cursor.to_string());
+                    assert_eq!(round_tripped.as_ref(), Some(&cursor));
+                    query.cursor = round_tripped;
+                },
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec!["2", "1", "3"]);
+    }
+
+    #[test]
+    fn single_groups_and_files_can_be_looked_up() {
+        let connection = open_test_database();
+        let group = group_summary(&connection, "2").expect("query failed").expect("group missing");
+        assert_eq!(group.copies, 2);
+        assert_eq!(group.files.len(), 2);
+        assert_eq!(group_summary(&connection, "4").expect("query failed"), None);
+        assert_eq!(group_summary(&connection, "nope").expect("query failed"), None);
+
+        let id = group.files[0].id;
+        let (hash, file) = file_for_id(&connection, id).expect("query failed").expect("file missing");
+        assert_eq!(hash, "2");
+        assert_eq!(file, group.files[0]);
+        assert_eq!(file_for_id(&connection, 9999).expect("query failed"), None);
+    }
+
+    #[test]
+    fn files_for_a_hash_include_loners() {
+        let connection = open_test_database();
+        let alone = files_for_hash(&connection, "4").expect("query failed");
+        assert_eq!(alone.len(), 1);
+        assert_eq!(alone[0].path, "/photos/alone.jpg");
+        assert_eq!(alone[0].size, Some(5000));
+
+        let paths: Vec<String> = files_for_hash(&connection, "2").expect("query failed").into_iter().map(|file| file.path).collect();
+        assert_eq!(paths, vec!["/backup/b.mp4", "/videos/b.MP4"]);
+        assert!(files_for_hash(&connection, "nope").expect("query failed").is_empty());
+    }
+
+    #[test]
+    fn nonsense_cursors_do_not_parse() {
+        assert_eq!(GroupCursor::parse("nope"), None);
+        assert_eq!(GroupCursor::parse("abc:123"), None);
+    }
+}
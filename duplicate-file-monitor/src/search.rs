@@ -0,0 +1,157 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::query::GroupFile;
+
+const SEARCH_PATH: &str = "/api/v1/search";
+const FRONTEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct SearchBody {
+    files: Vec<GroupFile>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Where a dupdb-frontend is, picked out of a URL like `http://nas:6969`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrontendUrl {
+    /// `host:port` as given, which is also what goes in the Host header.
+    pub authority: String,
+    pub host: String,
+    pub port: u16,
+    /// Anything after the authority, for frontends behind a proxy under a subpath.
+    pub base_path: String,
+}
+
+impl FrontendUrl {
+    pub fn parse(url: &str) -> Result<FrontendUrl, String> {
+        if url.starts_with("https://") {
+            return Err("HTTPS frontends aren't supported here, use the database instead".to_string());
+        }
+        let rest = url.strip_prefix("http://").unwrap_or(url);
+        let (authority, base_path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // A bare IPv6 address has colons too, only a port follows the closing bracket.
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| format!("{port} in {url} is not a port"))?)
+            },
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("No host in {url}"));
+        }
+        Ok(FrontendUrl { authority: authority.to_string(), host: host.to_string(), port, base_path: base_path.to_string() })
+    }
+}
+
+/// Asks a running dupdb-frontend which indexed files have this hash. The
+/// token is the frontend's `--token`, if it was started with one.
+pub fn dupdb_search_frontend(frontend: &FrontendUrl, token: Option<&str>, hash: u64) -> io::Result<Vec<GroupFile>> {
+    let address = (frontend.host.as_str(), frontend.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", frontend.host)))?;
+    let mut stream = TcpStream::connect_timeout(&address, FRONTEND_TIMEOUT)?;
+    stream.set_read_timeout(Some(FRONTEND_TIMEOUT))?;
+    stream.set_write_timeout(Some(FRONTEND_TIMEOUT))?;
+
+    let authorization = token.map(|token| format!("Authorization: Bearer {token}\r\n")).unwrap_or_default();
+    let request = format!(
+        "GET {}{SEARCH_PATH}?hash={hash} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\n{authorization}Connection: close\r\n\r\n",
+        frontend.base_path,
+        frontend.authority,
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let unexpected = || io::Error::new(io::ErrorKind::InvalidData, "Unexpected response from the frontend");
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(unexpected)?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+    let status = head.split_whitespace().nth(1).ok_or_else(unexpected)?;
+    if status != "200" {
+        let reason = serde_json::from_slice::<ErrorBody>(body)
+            .map(|error_body| error_body.error)
+            .unwrap_or_else(|_| String::from_utf8_lossy(body).trim().to_string());
+        return Err(io::Error::other(format!("The frontend answered {status}: {reason}")));
+    }
+    serde_json::from_slice::<SearchBody>(body)
+        .map(|search_body| search_body.files)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("Could not read the frontend's answer: {error}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn frontend_urls_are_picked_apart() {
+        let url = FrontendUrl::parse("http://nas.local:6969/dupdb/").expect("should parse");
+        assert_eq!(url, FrontendUrl {
+            authority: "nas.local:6969".to_string(),
+            host: "nas.local".to_string(),
+            port: 6969,
+            base_path: "/dupdb".to_string(),
+        });
+        assert_eq!(FrontendUrl::parse("localhost").expect("should parse").port, 80);
+        assert_eq!(FrontendUrl::parse("[::1]:8080").expect("should parse").host, "::1");
+        assert_eq!(FrontendUrl::parse("[::1]").expect("should parse").port, 80);
+        assert!(FrontendUrl::parse("https://nas").is_err());
+        assert!(FrontendUrl::parse("http://nas:port").is_err());
+        assert!(FrontendUrl::parse("http://:80").is_err());
+    }
+
+    /// Answers one request with `response` and hands back the request line and headers.
+    fn answer_once(response: String) -> (FrontendUrl, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("Could not bind test listener");
+        let port = listener.local_addr().expect("Listener has no address").port();
+        let answering = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Could not accept");
+            let mut lines = Vec::new();
+            for line in BufReader::new(&stream).lines() {
+                let line = line.expect("Could not read request");
+                if line.is_empty() {
+                    break;
+                }
+                lines.push(line);
+            }
+            stream.write_all(response.as_bytes()).expect("Could not answer");
+            lines
+        });
+        (FrontendUrl::parse(&format!("http://127.0.0.1:{port}")).expect("should parse"), answering)
+    }
+
+    #[test]
+    fn matches_come_back_from_the_frontend() {
+        let body = r#"{"hash":"42","files":[{"id":3,"path":"/a/b.jpg","size":10,"modified":null}]}"#;
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+        let (url, answering) = answer_once(response);
+        let files = dupdb_search_frontend(&url, Some("s3cret"), 42).expect("search should work");
+        assert_eq!(files, vec![GroupFile { id: 3, path: "/a/b.jpg".to_string(), size: Some(10), modified: None }]);
+
+        let request = answering.join().expect("answering thread panicked");
+        assert_eq!(request[0], "GET /api/v1/search?hash=42 HTTP/1.1");
+        assert!(request.contains(&"Authorization: Bearer s3cret".to_string()));
+    }
+
+    #[test]
+    fn frontend_errors_are_passed_on() {
+        let (url, _) = answer_once("HTTP/1.1 401 Unauthorized\r\nContent-Length: 24\r\n\r\n{\"error\":\"Unauthorized\"}".to_string());
+        let error = dupdb_search_frontend(&url, None, 42).expect_err("should be refused");
+        assert_eq!(error.to_string(), "The frontend answered 401: Unauthorized");
+    }
+}
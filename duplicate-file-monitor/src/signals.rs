@@ -0,0 +1,53 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+/// Flags flipped by the OS signal handlers. The handlers themselves only
+/// ever touch these atomics, the watch loop polls them between batches so
+/// that the database is never left mid transaction.
+#[derive(Debug, Clone)]
+pub struct WatchSignals {
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl WatchSignals {
+    /// Registers SIGINT and SIGTERM to request a shutdown and, on unix, SIGHUP
+    /// to request a reload of the config. Sending SIGINT or SIGTERM a second
+    /// time while we're already shutting down exits immediately.
+    pub fn register() -> io::Result<Self> {
+        let signals = WatchSignals::unregistered();
+        for signal in [SIGINT, SIGTERM] {
+            // Order matters, the conditional exit must see the flag before it's set.
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&signals.shutdown))?;
+            flag::register(signal, Arc::clone(&signals.shutdown))?;
+        }
+        #[cfg(unix)]
+        flag::register(signal_hook::consts::SIGHUP, Arc::clone(&signals.reload))?;
+        Ok(signals)
+    }
+
+    /// Flags that nothing but the caller will ever set. Handy for tests.
+    pub fn unregistered() -> Self {
+        WatchSignals {
+            shutdown: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once per reload request.
+    pub fn take_reload_request(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+}
@@ -1,4 +1,4 @@
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, OptionalExtension, Result};
 use std::path::{Path, PathBuf };
 
 pub const DATABASE_FILE: &str = "dupdb.sqlite.db";
@@ -24,28 +24,111 @@ pub fn connect_to_sqlite() -> Result<Connection, rusqlite::Error> {
     Connection::open(dupdb_database_path())
 }
 
+/// file_size is in bytes and modified is unix seconds, both are NULL for
+/// rows written before they were tracked. id is what a file is handed out
+/// as, AUTOINCREMENT so one is never given to another file, not even after
+/// the newest row is deleted.
 const SQL_CREATE_TABLE: &str = "
 CREATE TABLE IF NOT EXISTS dupdb_filehashes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
     hash TEXT NOT NULL,
-    file_path TEXT NOT NULL
+    file_path TEXT NOT NULL,
+    file_size INTEGER,
+    modified INTEGER
 )";
 
 const SQL_CREATE_INDICES: &str = "
 CREATE INDEX IF NOT EXISTS hash_index ON dupdb_filehashes (hash);
 ";
 
+const SQL_CREATE_FILE_PATH_INDEX: &str = "
+CREATE INDEX IF NOT EXISTS file_path_index ON dupdb_filehashes (file_path);
+";
+
+/// Append only history of what happened to each path. occurred_at is unix
+/// seconds, previous_path is only set for renames and previous_hash only
+/// for modifications.
+const SQL_CREATE_EVENTS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS dupdb_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    occurred_at INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    hash TEXT,
+    previous_path TEXT,
+    previous_hash TEXT
+)";
+
+const SQL_CREATE_EVENTS_INDICES: &str = "
+CREATE INDEX IF NOT EXISTS events_file_path_index ON dupdb_events (file_path);
+CREATE INDEX IF NOT EXISTS events_previous_path_index ON dupdb_events (previous_path);
+CREATE INDEX IF NOT EXISTS events_hash_index ON dupdb_events (hash);
+CREATE INDEX IF NOT EXISTS events_occurred_at_index ON dupdb_events (occurred_at);
+";
+
+/// Columns added after the table was first created, so older databases
+/// get them added on open.
+const ADDED_COLUMNS: [(&str, &str); 2] = [
+    ("file_size", "ALTER TABLE dupdb_filehashes ADD COLUMN file_size INTEGER"),
+    ("modified", "ALTER TABLE dupdb_filehashes ADD COLUMN modified INTEGER"),
+];
+
+/// Tables from before the id column are moved aside and copied into a new
+/// one that has it. The rowids they had become the ids, so links already
+/// handed out still work.
+const SQL_SET_ASIDE_TABLE_WITHOUT_ID: &str = "
+ALTER TABLE dupdb_filehashes RENAME TO dupdb_filehashes_without_id;
+";
+
+const SQL_COPY_TABLE_WITHOUT_ID: &str = "
+INSERT INTO dupdb_filehashes (id, hash, file_path, file_size, modified)
+    SELECT rowid, hash, file_path, file_size, modified FROM dupdb_filehashes_without_id;
+DROP TABLE dupdb_filehashes_without_id;
+";
+
 pub fn initialize(sqlite_connection: &Connection) {
 	sqlite_connection.execute(SQL_CREATE_TABLE, ()).expect("Could not create sqlite table");
+    sqlite_connection.execute(SQL_CREATE_EVENTS_TABLE, ()).expect("Could not create sqlite events table");
+    migrate(sqlite_connection);
+    // After migrating, a rebuilt table has lost the indices of the old one.
     sqlite_connection.execute(SQL_CREATE_INDICES, ()).expect("Could not setup indices on sqlite db");
+    sqlite_connection.execute(SQL_CREATE_FILE_PATH_INDEX, ()).expect("Could not setup indices on sqlite db");
+    sqlite_connection.execute_batch(SQL_CREATE_EVENTS_INDICES).expect("Could not setup event indices on sqlite db");
+}
+
+fn migrate(sqlite_connection: &Connection) {
+    let existing_columns: Vec<String> = sqlite_connection
+        .prepare("SELECT name FROM pragma_table_info('dupdb_filehashes')")
+        .and_then(|mut statement| statement.query_map([], |row| row.get(0))?.collect())
+        .expect("Could not read columns of dupdb_filehashes");
+
+    for (column, alter_statement) in ADDED_COLUMNS {
+        if !existing_columns.iter().any(|existing| existing == column) {
+            sqlite_connection.execute(alter_statement, ()).expect("Could not add column to dupdb_filehashes");
+        }
+    }
+
+    if !existing_columns.iter().any(|existing| existing == "id") {
+        let rebuild = format!(
+            "SAVEPOINT add_id_column;{SQL_SET_ASIDE_TABLE_WITHOUT_ID}{SQL_CREATE_TABLE};{SQL_COPY_TABLE_WITHOUT_ID}RELEASE add_id_column;"
+        );
+        sqlite_connection.execute_batch(&rebuild).expect("Could not add an id column to dupdb_filehashes");
+    }
 }
 
 const SQL_INSERT_HASH_AND_FILEPATH: &str = "
-INSERT INTO dupdb_filehashes (hash, file_path) VALUES (?1, ?2)
+INSERT INTO dupdb_filehashes (hash, file_path, file_size, modified) VALUES (?1, ?2, ?3, ?4)
 ";
 
 pub fn insert_file_hash(conn: &Connection, hash: u64, absolute_path: &str) -> bool {
+    insert_file_hash_with_metadata(conn, hash, absolute_path, None, None)
+}
+
+/// Same as `insert_file_hash` but also records the size in bytes and the
+/// modification time in unix seconds when we know them.
+pub fn insert_file_hash_with_metadata(conn: &Connection, hash: u64, absolute_path: &str, size: Option<u64>, modified: Option<u64>) -> bool {
 	let mut statement = conn.prepare_cached(SQL_INSERT_HASH_AND_FILEPATH).expect("could not prepare insertion statement");
-    match statement.execute((hash.to_string(), absolute_path)) {
+    match statement.execute((hash.to_string(), absolute_path, size, modified)) {
     	Ok(rows_inserted) => rows_inserted == 1,
     	Err(err) => {
     		eprintln!("Unable to insert into table failed: {}", err);
@@ -104,6 +187,165 @@ pub fn dups_by_file(conn: &Connection, absolute_path: &str) -> Vec<(String, Stri
 	dups
 }
 
+const SQL_SELECT_COUNT_OF_FILES: &str = "
+SELECT COUNT(distinct file_path) FROM dupdb_filehashes
+";
+
+pub fn count_of_files(conn: &Connection) -> u64 {
+	let mut statement = conn.prepare_cached(SQL_SELECT_COUNT_OF_FILES)
+		.expect("Could not fetch prepared count of files query");
+
+	match statement.query_one([], |row| row.get::<_, u64>(0)) {
+		Err(err) => {
+    		eprintln!("Unable to count files in table: {}", err);
+    		0
+    	},
+		Ok(count) => count
+	}
+}
+
+const SQL_SELECT_ALL_DUPES: &str = "
+SELECT hash, file_path
+FROM dupdb_filehashes
+WHERE hash IN (
+    SELECT hash
+    FROM dupdb_filehashes
+    GROUP BY hash
+    HAVING COUNT(DISTINCT file_path) > 1
+)
+ORDER BY hash
+";
+
+/// Every (hash, path) pair that has at least one other path with the same hash.
+pub fn all_dups(conn: &Connection) -> Result<Vec<(String, String)>> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_ALL_DUPES)?;
+	let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+	rows.collect()
+}
+
+const SQL_SELECT_DUPLICATE_HASHES: &str = "
+SELECT hash
+FROM dupdb_filehashes
+GROUP BY hash
+HAVING COUNT(DISTINCT file_path) > 1
+";
+
+/// Every hash shared by more than one path.
+pub fn duplicate_hashes(conn: &Connection) -> Result<Vec<String>> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_DUPLICATE_HASHES)?;
+	let rows = statement.query_map([], |row| row.get(0))?;
+	rows.collect()
+}
+
+/// Bumped by SQLite whenever another connection commits a change.
+pub fn data_version(conn: &Connection) -> Result<i64> {
+	conn.query_row("PRAGMA data_version", [], |row| row.get(0))
+}
+
+const SQL_SELECT_PATHS_FOR_HASH: &str = "
+SELECT DISTINCT file_path FROM dupdb_filehashes WHERE hash = ?1 ORDER BY file_path
+";
+
+pub fn paths_for_hash(conn: &Connection, hash: &str) -> Result<Vec<String>> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_PATHS_FOR_HASH)?;
+	let rows = statement.query_map([hash], |row| row.get(0))?;
+	rows.collect()
+}
+
+const SQL_SELECT_STATS: &str = "
+SELECT
+    (SELECT COUNT(DISTINCT file_path) FROM dupdb_filehashes),
+    (SELECT COUNT(DISTINCT hash) FROM dupdb_filehashes),
+    COUNT(*),
+    COALESCE(SUM(copies), 0)
+FROM (
+    SELECT COUNT(DISTINCT file_path) AS copies
+    FROM dupdb_filehashes
+    GROUP BY hash
+    HAVING copies > 1
+)
+";
+
+/// (files, distinct hashes, duplicate groups, files that are in a duplicate group)
+pub fn stats(conn: &Connection) -> Result<(u64, u64, u64, u64)> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_STATS)?;
+	statement.query_one([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+}
+
+const SQL_SELECT_HASHES_FOR_FILE: &str = "
+SELECT DISTINCT hash FROM dupdb_filehashes WHERE file_path = ?1
+";
+
+pub fn hashes_for_file(conn: &Connection, absolute_path: &str) -> Result<Vec<String>> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_HASHES_FOR_FILE)?;
+	let rows = statement.query_map([absolute_path], |row| row.get(0))?;
+	rows.collect()
+}
+
+const SQL_SELECT_PATH_FOR_ID: &str = "
+SELECT file_path FROM dupdb_filehashes WHERE id = ?1
+";
+
+/// Rows are addressed by their id when we need to refer to one without
+/// handing out its path.
+pub fn path_for_id(conn: &Connection, id: i64) -> Result<Option<String>> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_PATH_FOR_ID)?;
+	statement.query_row([id], |row| row.get(0)).optional()
+}
+
+const SQL_SELECT_ID_FOR_PATH: &str = "
+SELECT MIN(id) FROM dupdb_filehashes WHERE file_path = ?1
+";
+
+pub fn id_for_path(conn: &Connection, absolute_path: &str) -> Result<Option<i64>> {
+	let mut statement = conn.prepare_cached(SQL_SELECT_ID_FOR_PATH)?;
+	statement.query_row([absolute_path], |row| row.get(0))
+}
+
+const SQL_UPDATE_METADATA_FOR_FILE: &str = "
+UPDATE dupdb_filehashes SET file_size = ?2, modified = ?3 WHERE file_path = ?1
+";
+
+pub fn update_file_metadata(conn: &Connection, absolute_path: &str, size: Option<u64>, modified: Option<u64>) -> Result<usize> {
+	let mut statement = conn.prepare_cached(SQL_UPDATE_METADATA_FOR_FILE)?;
+	statement.execute((absolute_path, size, modified))
+}
+
+const SQL_DELETE_EXTRA_ROWS_FOR_FILE: &str = "
+DELETE FROM dupdb_filehashes
+WHERE file_path = ?1 AND id > (SELECT MIN(id) FROM dupdb_filehashes WHERE file_path = ?1)
+";
+
+const SQL_UPDATE_HASH_FOR_FILE: &str = "
+UPDATE dupdb_filehashes SET hash = ?2, file_size = ?3, modified = ?4 WHERE file_path = ?1
+";
+
+/// Gives a known path its new contents in place, so it keeps its id.
+/// Returns how many rows were updated, 0 if the path wasn't known.
+pub fn update_file_hash(conn: &Connection, hash: u64, absolute_path: &str, size: Option<u64>, modified: Option<u64>) -> Result<usize> {
+	conn.prepare_cached(SQL_DELETE_EXTRA_ROWS_FOR_FILE)?.execute([absolute_path])?;
+	let mut statement = conn.prepare_cached(SQL_UPDATE_HASH_FOR_FILE)?;
+	statement.execute((absolute_path, hash.to_string(), size, modified))
+}
+
+const SQL_DELETE_BY_FILE: &str = "
+DELETE FROM dupdb_filehashes WHERE file_path = ?1
+";
+
+pub fn delete_file(conn: &Connection, absolute_path: &str) -> Result<usize> {
+	let mut statement = conn.prepare_cached(SQL_DELETE_BY_FILE)?;
+	statement.execute([absolute_path])
+}
+
+const SQL_UPDATE_FILE_PATH: &str = "
+UPDATE dupdb_filehashes SET file_path = ?2 WHERE file_path = ?1
+";
+
+pub fn rename_file(conn: &Connection, from_absolute_path: &str, to_absolute_path: &str) -> Result<usize> {
+	let mut statement = conn.prepare_cached(SQL_UPDATE_FILE_PATH)?;
+	statement.execute([from_absolute_path, to_absolute_path])
+}
+
 const SQL_DELETE_BY_HASH_AND_FILE: &str ="
 DELETE FROM dupdb_filehashes WHERE hash = ?1 AND file_path = ?2
 ";
@@ -121,13 +363,27 @@ pub fn delete_all_matching(conn: &Connection, hash: u64, absolute_path: &str) ->
     }
 }
 
-const SQL_DROP_TABLE: &str = "
-DROP TABLE dupdb_filehashes
+pub fn begin_transaction(conn: &Connection) -> Result<()> {
+    conn.execute_batch("BEGIN")
+}
+
+pub fn commit_transaction(conn: &Connection) -> Result<()> {
+    conn.execute_batch("COMMIT")
+}
+
+pub fn rollback_transaction(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ROLLBACK")
+}
+
+/// Emptied rather than dropped, dropping would start the ids over and hand
+/// out ones that stale pages still point at.
+const SQL_DELETE_ALL_FILES: &str = "
+DELETE FROM dupdb_filehashes
 ";
 
 pub fn reset_all_data(sqlite_connection: &Connection) {
-	sqlite_connection.execute(SQL_DROP_TABLE, ())
-		.expect("Could not drop database. Go delete it yourself.");
+	sqlite_connection.execute(SQL_DELETE_ALL_FILES, ())
+		.expect("Could not empty database. Go delete it yourself.");
 
 	initialize(sqlite_connection);
 }
@@ -135,26 +391,11 @@ pub fn reset_all_data(sqlite_connection: &Connection) {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::fs;
- 
-    static mut TEST_DB_NO: u32 = 0;
-
-    fn open_test_database() -> Connection {
-        let connection;
-
-        unsafe {
-            TEST_DB_NO += 1;
-            let filename = format!("test_{TEST_DB_NO}.sqlite.db");
-            let _ = fs::remove_file(&filename);
-            connection = Connection::open(filename).expect("Cannot open database for test");
-        };
-        initialize(&connection);
-        connection
-    }
+    use crate::test_support::open_test_connection;
 
     #[test]
     fn count_of_non_existing_hash_should_be_0() {
-        let connection = open_test_database();
+        let connection = open_test_connection("sql");
         let count_of_nothingness = count_of_same_hash(&connection, 196248234750);
         let marking_time_waiting_for_death = 0;
         assert_eq!(count_of_nothingness, marking_time_waiting_for_death);
@@ -162,7 +403,7 @@ mod test {
 
     #[test]
     fn count_of_existing_hash_should_be_n() {
-        let connection = open_test_database();
+        let connection = open_test_connection("sql");
         let hash = 123456789;
         let path = "12345689";
         // Insert something other than the one we're testing too
@@ -178,7 +419,7 @@ mod test {
 
     #[test]
     fn select_dupes_based_on_filepath_hash() {
-        let connection = open_test_database();
+        let connection = open_test_connection("sql");
         // Insert something other than the one we're testing too
         insert_file_hash(&connection, 9876543211, "987654321");
         let hash = 1234567;
@@ -196,7 +437,7 @@ mod test {
 
     #[test]
     fn can_delete_from_database_for_matches() {
-        let connection = open_test_database();
+        let connection = open_test_connection("sql");
         // Insert something other than the one we're testing too
         insert_file_hash(&connection, 9876543211, "987654321");
         let hash = 1234567;
@@ -211,4 +452,84 @@ mod test {
         let should_be_zero = count_of_same_hash(&connection, hash);
         assert_eq!(should_be_zero, 0);
     }
+
+    #[test]
+    fn all_dups_only_returns_hashes_with_more_than_one_path() {
+        let connection = open_test_connection("sql");
+        insert_file_hash(&connection, 1, "lonely");
+        insert_file_hash(&connection, 2, "first");
+        insert_file_hash(&connection, 2, "second");
+        let dups = all_dups(&connection).expect("could not select dups");
+        assert_eq!(dups, vec![
+            ("2".to_string(), "first".to_string()),
+            ("2".to_string(), "second".to_string()),
+        ]);
+        let (files, hashes, groups, duplicate_files) = stats(&connection).expect("could not select stats");
+        assert_eq!((files, hashes, groups, duplicate_files), (3, 2, 1, 2));
+        assert_eq!(duplicate_hashes(&connection).expect("could not select hashes"), vec!["2".to_string()]);
+    }
+
+    #[test]
+    fn old_databases_get_the_new_columns() {
+        let connection = open_test_connection("sql");
+        connection.execute_batch("
+            DROP TABLE dupdb_filehashes;
+            CREATE TABLE dupdb_filehashes (hash TEXT NOT NULL, file_path TEXT NOT NULL);
+            INSERT INTO dupdb_filehashes (hash, file_path) VALUES ('1', 'old');
+        ").expect("could not set up old table");
+
+        initialize(&connection);
+        insert_file_hash_with_metadata(&connection, 1, "new", Some(10), Some(20));
+        let sizes: Vec<Option<u64>> = connection
+            .prepare("SELECT file_size FROM dupdb_filehashes ORDER BY file_path DESC")
+            .and_then(|mut statement| statement.query_map([], |row| row.get(0))?.collect())
+            .expect("could not select sizes");
+        assert_eq!(sizes, vec![None, Some(10)]);
+        assert_eq!(id_for_path(&connection, "old").expect("could not select id"), Some(1));
+        assert_eq!(id_for_path(&connection, "new").expect("could not select id"), Some(2));
+        let indices: u32 = connection
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'dupdb_filehashes'", [], |row| row.get(0))
+            .expect("could not count indices");
+        assert_eq!(indices, 2);
+    }
+
+    #[test]
+    fn ids_are_never_handed_out_twice() {
+        let connection = open_test_connection("sql");
+        insert_file_hash(&connection, 1, "first");
+        insert_file_hash(&connection, 1, "second");
+        let second = id_for_path(&connection, "second").expect("could not select id").expect("second is indexed");
+        delete_file(&connection, "second").expect("could not delete");
+        insert_file_hash(&connection, 1, "third");
+        let third = id_for_path(&connection, "third").expect("could not select id").expect("third is indexed");
+        assert!(third > second);
+        assert_eq!(path_for_id(&connection, second).expect("could not select path"), None);
+
+        reset_all_data(&connection);
+        insert_file_hash(&connection, 1, "fourth");
+        let fourth = id_for_path(&connection, "fourth").expect("could not select id").expect("fourth is indexed");
+        assert!(fourth > third);
+    }
+
+    #[test]
+    fn data_version_moves_when_another_connection_commits() {
+        let writer = open_test_connection("sql");
+        let filename = writer.path().expect("test database has a file").to_string();
+        let reader = Connection::open(filename).expect("Cannot open second connection");
+        let before = data_version(&reader).expect("could not read data version");
+        assert_eq!(data_version(&reader).expect("could not read data version"), before);
+        insert_file_hash(&writer, 1, "somewhere");
+        assert_ne!(data_version(&reader).expect("could not read data version"), before);
+    }
+
+    #[test]
+    fn rolled_back_inserts_are_not_kept() {
+        let connection = open_test_connection("sql");
+        let hash = 55555;
+        begin_transaction(&connection).expect("could not begin transaction");
+        insert_file_hash(&connection, hash, "in-flight");
+        assert_eq!(count_of_same_hash(&connection, hash), 1);
+        rollback_transaction(&connection).expect("could not roll back transaction");
+        assert_eq!(count_of_same_hash(&connection, hash), 0);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,160 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const STATUS_PATH: &str = "/status";
+const STATUS_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters the watch loop keeps up to date for the status endpoint.
+/// Everything is atomic so the status thread never has to wait on the
+/// watcher (or the database) to answer.
+#[derive(Debug)]
+pub struct WatchStatus {
+    started: Instant,
+    files_indexed: AtomicU64,
+    last_event_unix_seconds: AtomicU64,
+    queue_depth: AtomicUsize,
+    errors: AtomicU64,
+}
+
+impl Default for WatchStatus {
+    fn default() -> Self {
+        WatchStatus {
+            started: Instant::now(),
+            files_indexed: AtomicU64::new(0),
+            last_event_unix_seconds: AtomicU64::new(0),
+            queue_depth: AtomicUsize::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl WatchStatus {
+    pub fn set_files_indexed(&self, count: u64) {
+        self.files_indexed.store(count, Ordering::Relaxed);
+    }
+
+    pub fn record_event(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        self.last_event_unix_seconds.store(now, Ordering::Relaxed);
+    }
+
+    /// Called by the debouncer's thread when it hands us events.
+    pub fn events_queued(&self, count: usize) {
+        self.queue_depth.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Called by the watch loop once it has dealt with queued events.
+    pub fn events_handled(&self, count: usize) {
+        // Never underflow, a miscount here shouldn't show 18 quintillion events.
+        let _ = self.queue_depth.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+            Some(depth.saturating_sub(count))
+        });
+    }
+
+    pub fn record_errors(&self, count: u64) {
+        self.errors.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// `key: value` lines, one per stat, so it reads fine in a terminal and
+    /// is trivial to pick apart with grep or a script.
+    pub fn report(&self) -> String {
+        let last_event = match self.last_event_unix_seconds.load(Ordering::Relaxed) {
+            0 => "never".to_string(),
+            seconds => seconds.to_string(),
+        };
+        format!(
+            "pid: {}\nuptime_seconds: {}\nfiles_indexed: {}\nlast_event_unix_seconds: {}\nqueue_depth: {}\nerrors: {}\n",
+            std::process::id(),
+            self.started.elapsed().as_secs(),
+            self.files_indexed.load(Ordering::Relaxed),
+            last_event,
+            self.queue_depth.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Binds the status endpoint on localhost only, nobody else has any business
+/// knowing what we're up to.
+pub fn dupdb_status_bind(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+}
+
+/// Answers `GET /status` on its own thread for as long as the process lives.
+pub fn dupdb_status_serve(listener: TcpListener, status: Arc<WatchStatus>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(error) = dupdb_status_respond(stream, &status) {
+                        eprintln!("Could not answer status request: {:?}", error);
+                    }
+                },
+                Err(error) => eprintln!("Could not accept status connection: {:?}", error),
+            }
+        }
+    })
+}
+
+fn dupdb_status_respond(mut stream: TcpStream, status: &WatchStatus) -> io::Result<()> {
+    stream.set_read_timeout(Some(STATUS_CLIENT_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(STATUS_PATH)) => {
+            let body = status.report();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+        },
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes())
+}
+
+/// Asks a running monitor for its status and returns the report body.
+pub fn dupdb_status_fetch(port: u16) -> io::Result<String> {
+    let mut stream = TcpStream::connect_timeout(&SocketAddr::from((Ipv4Addr::LOCALHOST, port)), STATUS_CLIENT_TIMEOUT)?;
+    stream.set_read_timeout(Some(STATUS_CLIENT_TIMEOUT))?;
+    stream.write_all(format!("GET {STATUS_PATH} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.split_once("\r\n\r\n") {
+        Some((head, body)) if head.starts_with("HTTP/1.1 200") => Ok(body.to_string()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected status response: {response}"))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queue_depth_never_goes_negative() {
+        let status = WatchStatus::default();
+        status.events_queued(2);
+        status.events_handled(5);
+        assert!(status.report().contains("queue_depth: 0\n"));
+    }
+
+    #[test]
+    fn status_can_be_fetched_over_the_endpoint() {
+        let status = Arc::new(WatchStatus::default());
+        status.set_files_indexed(42);
+        status.record_errors(3);
+
+        let listener = dupdb_status_bind(0).expect("Could not bind status listener for test");
+        let port = listener.local_addr().expect("Listener has no address").port();
+        dupdb_status_serve(listener, Arc::clone(&status));
+
+        let report = dupdb_status_fetch(port).expect("Could not fetch status");
+        assert!(report.contains("files_indexed: 42\n"));
+        assert!(report.contains("errors: 3\n"));
+        assert!(report.contains("last_event_unix_seconds: never\n"));
+    }
+}
@@ -0,0 +1,72 @@
+//! What the tests in every module share.
+
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use rusqlite::Connection;
+
+use crate::dupdb::DuplicateDatabase;
+use crate::sql::initialize;
+
+static TEST_DIR_NO: AtomicU32 = AtomicU32::new(0);
+
+/// A fresh folder under the temp dir, gone with everything in it once dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// `prefix` is only there to tell whose leftovers are whose.
+    pub fn new(prefix: &str) -> TestDir {
+        let test_dir_no = TEST_DIR_NO.fetch_add(1, Ordering::SeqCst) + 1;
+        let dir = std::env::temp_dir().join(format!("duplicate_file_monitor_{prefix}_{}_{test_dir_no}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Could not create test dir");
+        TestDir(dir)
+    }
+
+    pub fn join(&self, relative: impl AsRef<Path>) -> PathBuf {
+        self.0.join(relative)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A database in a `TestDir` of its own, used as whatever opened it.
+pub struct TestDatabase<T> {
+    // Declared first so it's closed before its folder goes.
+    database: T,
+    _dir: TestDir,
+}
+
+impl<T> Deref for TestDatabase<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.database
+    }
+}
+
+impl<T> DerefMut for TestDatabase<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.database
+    }
+}
+
+/// A plain connection to a database with every table made.
+pub fn open_test_connection(prefix: &str) -> TestDatabase<Connection> {
+    let dir = TestDir::new(prefix);
+    let connection = Connection::open(dir.join("test.sqlite.db")).expect("Cannot open database for test");
+    initialize(&connection);
+    TestDatabase { database: connection, _dir: dir }
+}
+
+pub fn open_test_dupdb(prefix: &str) -> TestDatabase<DuplicateDatabase> {
+    let dir = TestDir::new(prefix);
+    let database = DuplicateDatabase::open(&dir.join("test.sqlite.db")).expect("Cannot open database for test");
+    TestDatabase { database, _dir: dir }
+}
@@ -0,0 +1,405 @@
+//! # Fixed Thread Pool
+//!
+//! A set number of worker threads taking jobs off a bounded queue. When the
+//! queue is full `execute` says so straight away instead of letting work
+//! pile up, so a server can turn people away while it still can.
+//!
+//! ```
+//! let pool = fixed_thread_pool::FixedThreadPool::new(2, 8);
+//! let handle = pool.execute(|| 1 + 1).expect("queue has room");
+//! assert_eq!(handle.join(), Ok(2));
+//! ```
+
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How often `shutdown_with_timeout` checks whether the workers are done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+	/// Every worker is busy and the queue is at capacity, try again later.
+	QueueFull,
+	/// The pool is shut down, or lost every worker and couldn't start new ones.
+	ShutDown,
+}
+
+impl fmt::Display for ExecuteError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ExecuteError::QueueFull => write!(f, "the job queue is full"),
+			ExecuteError::ShutDown => write!(f, "the pool is shut down"),
+		}
+	}
+}
+
+impl std::error::Error for ExecuteError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+	/// The job panicked, with the panic message if it had one.
+	Panicked(String),
+	/// The job was dropped without running, which only happens if its worker
+	/// died some way other than a panic.
+	Lost,
+}
+
+impl fmt::Display for JobError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			JobError::Panicked(message) => write!(f, "job panicked: {message}"),
+			JobError::Lost => write!(f, "job was dropped before it finished"),
+		}
+	}
+}
+
+impl std::error::Error for JobError {}
+
+/// The result of a job, for when the caller cares. Dropping the handle is
+/// fine, the job still runs.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+	receiver: Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+	/// Blocks until the job has run.
+	pub fn join(self) -> Result<T, JobError> {
+		self.receiver.recv().unwrap_or(Err(JobError::Lost))
+	}
+}
+
+/// A snapshot of what the pool is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolMetrics {
+	/// Worker threads currently alive.
+	pub workers: usize,
+	/// Jobs running right now.
+	pub active: usize,
+	/// Jobs waiting for a worker.
+	pub queued: usize,
+	/// Jobs that ran to the end, panicked ones not included.
+	pub completed: usize,
+	/// Jobs that panicked. Each one cost a worker, which was replaced.
+	pub panicked: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+	workers: AtomicUsize,
+	active: AtomicUsize,
+	queued: AtomicUsize,
+	completed: AtomicUsize,
+	panicked: AtomicUsize,
+}
+
+/// What every worker, and whoever replaces it, needs.
+struct Shared {
+	receiver: Mutex<Receiver<Job>>,
+	counters: Arc<Counters>,
+	/// One slot per worker. A replacement worker takes the slot of the one
+	/// it replaces so shutdown can find it.
+	threads: Mutex<Vec<Option<JoinHandle<()>>>>,
+}
+
+/// Workers only panic in jobs, and jobs never run while a lock is held, so
+/// a poisoned lock has nothing half done behind it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	match mutex.lock() {
+		Ok(guard) => guard,
+		Err(poisoned) => {
+			mutex.clear_poison();
+			poisoned.into_inner()
+		},
+	}
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message.to_string()
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message.clone()
+	} else {
+		"unknown panic".to_string()
+	}
+}
+
+/// Starts worker `id` in its slot, false if the OS wouldn't give us a thread.
+fn spawn_worker(id: usize, shared: &Arc<Shared>) -> bool {
+	let worker_shared = Arc::clone(shared);
+	// Counted first, a worker can be done before spawn even returns.
+	shared.counters.workers.fetch_add(1, Ordering::SeqCst);
+	let spawned = thread::Builder::new()
+		.name(format!("pool-worker-{id}"))
+		.spawn(move || run_worker(id, worker_shared));
+	match spawned {
+		Ok(thread) => {
+			let mut threads = lock(&shared.threads);
+			// The worker being replaced is about to return, so its handle can go.
+			threads[id] = Some(thread);
+			true
+		},
+		Err(error) => {
+			shared.counters.workers.fetch_sub(1, Ordering::SeqCst);
+			eprintln!("Could not start worker {id}: {error}");
+			false
+		},
+	}
+}
+
+fn run_worker(id: usize, shared: Arc<Shared>) {
+	loop {
+		let message = lock(&shared.receiver).recv();
+		let Ok(job) = message else {
+			println!("Worker {id} disconnected; shutting down");
+			break;
+		};
+		shared.counters.queued.fetch_sub(1, Ordering::SeqCst);
+		shared.counters.active.fetch_add(1, Ordering::SeqCst);
+		let outcome = panic::catch_unwind(AssertUnwindSafe(job));
+		// Counted before the job stops being active, so nobody sees it as neither.
+		let counter = if outcome.is_ok() { &shared.counters.completed } else { &shared.counters.panicked };
+		counter.fetch_add(1, Ordering::SeqCst);
+		shared.counters.active.fetch_sub(1, Ordering::SeqCst);
+		if outcome.is_err() {
+			// Whatever the job left in thread locals goes with this thread.
+			eprintln!("Worker {id} had a job panic; starting a new worker in its place");
+			spawn_worker(id, &shared);
+			break;
+		}
+	}
+	shared.counters.workers.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Reads a pool's metrics without keeping the pool alive.
+#[derive(Clone)]
+pub struct PoolObserver {
+	counters: Arc<Counters>,
+}
+
+impl PoolObserver {
+	pub fn metrics(&self) -> PoolMetrics {
+		let counters = &self.counters;
+		PoolMetrics {
+			workers: counters.workers.load(Ordering::SeqCst),
+			active: counters.active.load(Ordering::SeqCst),
+			queued: counters.queued.load(Ordering::SeqCst),
+			completed: counters.completed.load(Ordering::SeqCst),
+			panicked: counters.panicked.load(Ordering::SeqCst),
+		}
+	}
+}
+
+pub struct FixedThreadPool {
+	shared: Arc<Shared>,
+	sender: Option<SyncSender<Job>>,
+}
+
+impl FixedThreadPool {
+	/// Create a new fixed size thread pool
+	///
+	/// Size is the number of threads in the pool, and queue capacity how many
+	/// jobs may wait for one of them before `execute` turns more away. A queue
+	/// capacity of 0 only takes jobs when a worker is free to start them.
+	///
+	/// # Panics
+	///
+	/// The new function will panic if the size is 0 or no thread could be started
+	pub fn new(size: usize, queue_capacity: usize) -> Self {
+		assert!(size > 0);
+
+		let (sender, receiver) = mpsc::sync_channel(queue_capacity);
+		let shared = Arc::new(Shared {
+			receiver: Mutex::new(receiver),
+			counters: Arc::default(),
+			threads: Mutex::new((0..size).map(|_| None).collect()),
+		});
+		let started = (0..size).filter(|id| spawn_worker(*id, &shared)).count();
+		assert!(started > 0, "Could not start any worker threads");
+
+		FixedThreadPool { shared, sender: Some(sender) }
+	}
+
+	/// Queues the job, or refuses it if the queue is full.
+	pub fn execute<F, T>(&self, thunk: F) -> Result<JobHandle<T>, ExecuteError>
+	where
+		F: FnOnce() -> T + Send + 'static,
+		T: Send + 'static,
+	{
+		let sender = self.sender.as_ref().ok_or(ExecuteError::ShutDown)?;
+		if self.shared.counters.workers.load(Ordering::SeqCst) == 0 {
+			return Err(ExecuteError::ShutDown);
+		}
+		let (result_sender, receiver) = mpsc::channel();
+		// Nobody listening is fine, most callers never join.
+		let job: Job = Box::new(move || match panic::catch_unwind(AssertUnwindSafe(thunk)) {
+			Ok(value) => {
+				let _ = result_sender.send(Ok(value));
+			},
+			Err(payload) => {
+				let _ = result_sender.send(Err(JobError::Panicked(panic_message(&*payload))));
+				// On to the worker, so it knows to replace itself.
+				panic::resume_unwind(payload);
+			},
+		});
+		// Counted before sending so a fast worker never takes it below zero.
+		self.shared.counters.queued.fetch_add(1, Ordering::SeqCst);
+		match sender.try_send(job) {
+			Ok(()) => Ok(JobHandle { receiver }),
+			Err(error) => {
+				self.shared.counters.queued.fetch_sub(1, Ordering::SeqCst);
+				match error {
+					TrySendError::Full(_) => Err(ExecuteError::QueueFull),
+					TrySendError::Disconnected(_) => Err(ExecuteError::ShutDown),
+				}
+			},
+		}
+	}
+
+	pub fn metrics(&self) -> PoolMetrics {
+		self.observer().metrics()
+	}
+
+	/// Something the jobs themselves can hold to see how busy the pool is.
+	pub fn observer(&self) -> PoolObserver {
+		PoolObserver { counters: Arc::clone(&self.shared.counters) }
+	}
+
+	/// Stops taking jobs and waits up to `timeout` for the workers to finish
+	/// what's queued. Returns false if some were still busy at the deadline,
+	/// those are left to finish on their own.
+	pub fn shutdown_with_timeout(mut self, timeout: Duration) -> bool {
+		self.stop(Some(Instant::now() + timeout))
+	}
+
+	fn stop(&mut self, deadline: Option<Instant>) -> bool {
+		// Sender must be explicitly dropped in order to
+		// ensure that the worker threads actually stop looping.
+		drop(self.sender.take());
+		let slots = lock(&self.shared.threads).len();
+		for id in 0..slots {
+			// Loops because a worker that panics on the way out leaves a new one in its slot.
+			loop {
+				let Some(thread) = lock(&self.shared.threads)[id].take() else {
+					break;
+				};
+				if let Some(deadline) = deadline {
+					while !thread.is_finished() && Instant::now() < deadline {
+						thread::sleep(SHUTDOWN_POLL_INTERVAL);
+					}
+					if !thread.is_finished() {
+						eprintln!("Worker {id} is still busy; leaving it behind");
+						return false;
+					}
+				}
+				println!("Shutting down worker {id}");
+				match thread.join() {
+					Ok(_) => println!("Successfully shut down worker {id}"),
+					Err(error) => eprintln!("Could not shut down worker properly {id} {:?}", error),
+				}
+			}
+		}
+		true
+	}
+}
+
+impl Drop for FixedThreadPool {
+	fn drop(&mut self) {
+		if self.sender.is_some() {
+			self.stop(None);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Barrier;
+
+	fn wait_for(pool: &FixedThreadPool, done: impl Fn(&PoolMetrics) -> bool) -> PoolMetrics {
+		let deadline = Instant::now() + Duration::from_secs(5);
+		loop {
+			let metrics = pool.metrics();
+			if done(&metrics) || Instant::now() > deadline {
+				return metrics;
+			}
+			thread::sleep(Duration::from_millis(5));
+		}
+	}
+
+	#[test]
+	fn jobs_hand_back_their_results() {
+		let pool = FixedThreadPool::new(2, 4);
+		let handles: Vec<JobHandle<usize>> = (0..4).map(|n| pool.execute(move || n * 10).expect("room in queue")).collect();
+		let results: Vec<usize> = handles.into_iter().map(|handle| handle.join().expect("job failed")).collect();
+		assert_eq!(results, vec![0, 10, 20, 30]);
+		assert_eq!(wait_for(&pool, |metrics| metrics.completed == 4).completed, 4);
+	}
+
+	#[test]
+	fn full_queues_turn_jobs_away() {
+		let pool = FixedThreadPool::new(1, 1);
+		let release = Arc::new(Barrier::new(2));
+		let blocker = Arc::clone(&release);
+		let running = pool.execute(move || { blocker.wait(); }).expect("worker is free");
+		wait_for(&pool, |metrics| metrics.active == 1);
+
+		let queued = pool.execute(|| "queued").expect("one place in the queue");
+		assert_eq!(pool.metrics().queued, 1);
+		assert_eq!(pool.execute(|| "too many").err(), Some(ExecuteError::QueueFull));
+
+		release.wait();
+		assert_eq!(running.join(), Ok(()));
+		assert_eq!(queued.join(), Ok("queued"));
+		assert!(pool.execute(|| ()).is_ok());
+	}
+
+	#[test]
+	fn panicking_jobs_are_reported_and_their_worker_replaced() {
+		let pool = FixedThreadPool::new(1, 4);
+		let handle = pool.execute(|| -> u32 { panic!("oh no") }).expect("room in queue");
+		assert_eq!(handle.join(), Err(JobError::Panicked("oh no".to_string())));
+
+		let metrics = wait_for(&pool, |metrics| metrics.workers == 1 && metrics.active == 0);
+		assert_eq!(metrics.panicked, 1);
+		assert_eq!(metrics.workers, 1);
+		// The only worker died, so this only runs if it was replaced.
+		assert_eq!(pool.execute(|| 5).expect("room in queue").join(), Ok(5));
+		assert_eq!(wait_for(&pool, |metrics| metrics.completed == 1).completed, 1);
+	}
+
+	#[test]
+	fn shutdown_finishes_queued_jobs() {
+		let pool = FixedThreadPool::new(2, 8);
+		let counter = Arc::new(AtomicUsize::new(0));
+		for _ in 0..6 {
+			let counter = Arc::clone(&counter);
+			pool.execute(move || {
+				thread::sleep(Duration::from_millis(5));
+				counter.fetch_add(1, Ordering::SeqCst);
+			}).expect("room in queue");
+		}
+		assert!(pool.shutdown_with_timeout(Duration::from_secs(5)));
+		assert_eq!(counter.load(Ordering::SeqCst), 6);
+	}
+
+	#[test]
+	fn shutdown_gives_up_on_stuck_workers() {
+		let pool = FixedThreadPool::new(1, 1);
+		let release = Arc::new(Barrier::new(2));
+		let blocker = Arc::clone(&release);
+		pool.execute(move || { blocker.wait(); }).expect("worker is free");
+		wait_for(&pool, |metrics| metrics.active == 1);
+
+		assert!(!pool.shutdown_with_timeout(Duration::from_millis(20)));
+		release.wait();
+	}
+}